@@ -138,7 +138,7 @@ pub(crate) async fn basic_auth(
 mod tests {
     use super::*;
 
-    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::pingora::{ClientCertificate, RequestHeader, TestSession};
     use pandora_module_utils::standard_response::response_text;
     use pandora_module_utils::{FromYaml, RequestFilter};
     use test_log::test;
@@ -225,6 +225,28 @@ auth_rate_limits:
         Ok(())
     }
 
+    #[test]
+    fn invalid_hash() {
+        <AuthHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+auth_mode: http
+auth_credentials:
+    me: test
+            "#,
+        )
+        .expect_err("invalid password hash should be rejected");
+
+        let mut conf = <AuthHandler as RequestFilter>::Conf::from_yaml(default_conf()).unwrap();
+        conf.merge_with_opt(crate::AuthOpt {
+            auth_display_hash: false,
+            auth_credentials: Some(vec!["other:test".to_owned()]),
+            auth_mode: None,
+            auth_realm: None,
+        })
+        .expect_err("invalid password hash on command line should be rejected");
+        assert!(!conf.auth_credentials.contains_key("other"));
+    }
+
     #[test(tokio::test)]
     async fn no_auth_header() -> Result<(), Box<Error>> {
         let handler = make_handler(default_conf());
@@ -334,6 +356,73 @@ auth_rate_limits:
         Ok(())
     }
 
+    #[test(tokio::test)]
+    async fn client_certificate() -> Result<(), Box<Error>> {
+        let certificate = ClientCertificate {
+            subject: "CN=client,O=Example".to_owned(),
+            common_name: Some("client".to_owned()),
+            subject_alt_names: Vec::new(),
+            fingerprint: "00".repeat(32),
+            verify_error: None,
+        };
+
+        // Client certificates ignored by default
+        let handler = make_handler(default_conf());
+        let mut session = make_session().await;
+        session.set_client_certificate(certificate.clone());
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.remote_user(), None);
+        check_unauthorized_response(&session);
+
+        let conf = format!("{}\nauth_client_certificates: true", default_conf());
+        let handler = make_handler(&conf);
+        let mut session = make_session().await;
+        session.set_client_certificate(certificate.clone());
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.remote_user(), Some("client"));
+
+        // Without a certificate regular authentication still applies
+        let mut session = make_session().await;
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.remote_user(), None);
+        check_unauthorized_response(&session);
+
+        // Certificates that failed verification are ignored
+        let mut session = make_session().await;
+        session.set_client_certificate(ClientCertificate {
+            verify_error: Some("unable to get local issuer certificate".to_owned()),
+            ..certificate.clone()
+        });
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.remote_user(), None);
+
+        // Subject is used if there is no common name
+        let mut session = make_session().await;
+        session.set_client_certificate(ClientCertificate {
+            common_name: None,
+            ..certificate
+        });
+        assert_eq!(
+            handler.request_filter(&mut session, &mut ()).await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.remote_user(), Some("CN=client,O=Example"));
+
+        Ok(())
+    }
+
     #[test(tokio::test)]
     async fn display_hash() -> Result<(), Box<Error>> {
         let mut conf = default_conf().to_owned();
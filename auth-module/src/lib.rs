@@ -178,6 +178,17 @@ where
     ))
 }
 
+fn validate_credentials(credentials: &HashMap<String, String>) -> Result<(), String> {
+    for (user, hash) in credentials {
+        if bcrypt::HashParts::from_str(hash).is_err() {
+            return Err(format!(
+                "password hash for user `{user}` isn't a valid bcrypt hash"
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn deserialize_interval<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -213,6 +224,7 @@ pub struct AuthPageSession {
     pub token_secret: Option<Vec<u8>>,
 
     /// Name of the cookie to store the JWT token
+    #[pandora(non_empty)]
     pub cookie_name: String,
 
     /// Determines whether the `Secure` attribute should be set for the cookie, allowing it to be
@@ -249,8 +261,15 @@ pub struct AuthConf {
     pub auth_display_hash: bool,
 
     /// Accepted credentials by user name
+    #[pandora(validate = "validate_credentials")]
     pub auth_credentials: HashMap<String, String>,
 
+    /// If `true`, requests with a verified TLS client certificate will be considered authorized.
+    ///
+    /// The certificate’s common name (or its subject if the common name is missing) becomes the
+    /// user name then.
+    pub auth_client_certificates: bool,
+
     /// Login rate limits
     ///
     /// Note that in Basic HTTP mode each request is a “login”
@@ -272,8 +291,8 @@ pub struct AuthConf {
 impl AuthConf {
     /// Merges the command line options into the current configuration. Command line options
     /// present overwrite existing settings, with the exception of `--auth-credentials` that adds
-    /// to the existing ones.
-    pub fn merge_with_opt(&mut self, opt: AuthOpt) {
+    /// to the existing ones. Invalid password hashes are rejected.
+    pub fn merge_with_opt(&mut self, opt: AuthOpt) -> Result<(), Box<Error>> {
         if opt.auth_display_hash {
             self.auth_display_hash = true;
         }
@@ -281,6 +300,12 @@ impl AuthConf {
         if let Some(auth_credentials) = opt.auth_credentials {
             for entry in auth_credentials {
                 if let Some((user, hash)) = entry.split_once(':') {
+                    if bcrypt::HashParts::from_str(hash).is_err() {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("Invalid credentials, password hash for user {user} isn't a valid bcrypt hash"),
+                        ));
+                    }
                     self.auth_credentials
                         .insert(user.to_owned(), hash.to_owned());
                 } else {
@@ -296,6 +321,8 @@ impl AuthConf {
         if let Some(auth_realm) = opt.auth_realm {
             self.auth_realm = auth_realm;
         }
+
+        Ok(())
     }
 }
 
@@ -304,6 +331,7 @@ impl Default for AuthConf {
         Self {
             auth_display_hash: false,
             auth_credentials: HashMap::new(),
+            auth_client_certificates: false,
             auth_rate_limits: Default::default(),
             auth_mode: AuthMode::Page,
             auth_realm: "Server authentication".to_owned(),
@@ -359,6 +387,18 @@ impl RequestFilter for AuthHandler {
             return Ok(RequestFilterResult::Unhandled);
         }
 
+        if self.conf.auth_client_certificates {
+            if let Some(certificate) = session.client_certificate() {
+                let user = certificate
+                    .common_name
+                    .as_ref()
+                    .unwrap_or(&certificate.subject)
+                    .clone();
+                session.set_remote_user(user);
+                return Ok(RequestFilterResult::Unhandled);
+            }
+        }
+
         match self.conf.auth_mode {
             AuthMode::HTTP => basic_auth(&self.conf, session).await,
             AuthMode::Page => page_auth(&self.conf, session).await,
@@ -156,6 +156,20 @@ impl RequestFilter for CommonLogHandler {
                         LogToken::None
                     }
                 }
+                LogField::ClientCertSubject => {
+                    if let Some(certificate) = session.client_certificate() {
+                        LogToken::Text(certificate.subject.clone())
+                    } else {
+                        LogToken::None
+                    }
+                }
+                LogField::ClientCertFingerprint => {
+                    if let Some(certificate) = session.client_certificate() {
+                        LogToken::Text(certificate.fingerprint.clone())
+                    } else {
+                        LogToken::None
+                    }
+                }
                 LogField::RemoteName
                 | LogField::Status
                 | LogField::BytesSent
@@ -189,7 +203,9 @@ impl RequestFilter for CommonLogHandler {
                 | LogField::TimeLocal
                 | LogField::TimeISO
                 | LogField::Request
-                | LogField::RequestHeader(_) => {
+                | LogField::RequestHeader(_)
+                | LogField::ClientCertSubject
+                | LogField::ClientCertFingerprint => {
                     // This is a token we’ve added previously. Panic if we don’t have one, it’s
                     // a bug that needs investigating.
                     existing_tokens.next().unwrap()
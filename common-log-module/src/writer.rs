@@ -17,6 +17,7 @@
 use chrono::{DateTime, Local};
 use http::HeaderValue;
 use log::error;
+use pandora_module_utils::chroot::chroot_path;
 use pandora_module_utils::pingora::SocketAddr;
 use std::collections::HashMap;
 use std::fs::File;
@@ -38,6 +39,7 @@ pub(crate) enum LogToken {
     BytesSent(usize),
     ProcessingTime(Duration),
     Header(HeaderValue),
+    Text(String),
 }
 
 #[derive(Debug)]
@@ -63,9 +65,13 @@ impl WriterMessage {
     }
 }
 
-fn open_file(path: &PathBuf) -> Box<dyn Write + Send> {
+fn open_file(path: &Path) -> Box<dyn Write + Send> {
     if path.as_os_str() != "-" {
-        match File::options().append(true).create(true).open(path) {
+        match File::options()
+            .append(true)
+            .create(true)
+            .open(chroot_path(path))
+        {
             Ok(file) => return Box::new(file),
             Err(err) => {
                 error!(
@@ -135,6 +141,7 @@ fn stringify_data(buf: &mut Vec<u8>, time: SystemTime, tokens: Vec<LogToken>) {
                 write!(buf, "{:.3}", time.as_secs_f32() * 1000.0)
             }
             LogToken::Header(value) => write_escaped(buf, value),
+            LogToken::Text(value) => write_escaped(buf, value),
         };
     }
     let _ = writeln!(buf);
@@ -152,7 +159,9 @@ pub(crate) async fn log_writer(mut receiver: Receiver<WriterMessage>) {
             }
             WriterMessage::LogData(data) => {
                 stringify_data(&mut buf, data.time, data.tokens);
-                let writer = files.entry(data.log_file).or_insert_with_key(open_file);
+                let writer = files
+                    .entry(data.log_file)
+                    .or_insert_with_key(|path| open_file(path));
                 let _ = writer.write_all(&buf);
             }
         }
@@ -201,13 +210,14 @@ mod tests {
             LogToken::ProcessingTime(Duration::from_nanos(1234567)),
             LogToken::RemotePort(SocketAddr::Inet("127.0.0.1:8080".parse().unwrap())),
             LogToken::TimeISO,
+            LogToken::Text("CN=client,O=Example".to_owned()),
         ];
 
         let mut buf = Vec::new();
         stringify_data(&mut buf, time, tokens);
         assert_eq!(
             String::from_utf8(buf).unwrap(),
-            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00]\n"
+            "127.0.0.1 - \"me\" [29/May/2024:09:53:19 -0100] \"GET /test\\x0a/\\x22 HTTP/1.1\" 200 876 \"https://example.com/\" \"Mozilla/1.0 \\x5c\\x22invalid data\\x80\" 1.235 8080 [2024-05-29T09:53:19-01:00] \"CN=client,O=Example\"\n"
         );
     }
 }
@@ -23,7 +23,7 @@ use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
 #[derive(Debug, Default, Parser)]
 pub struct CompressionOpt {
     /// Compression level to be used for dynamic compression (omit to disable compression)
-    #[clap(long)]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(0..=9))]
     pub compression_level: Option<u32>,
 
     /// Decompress upstream responses before passing them on
@@ -35,6 +35,7 @@ pub struct CompressionOpt {
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct CompressionConf {
     /// Compression level to be used for dynamic compression (omit to disable compression).
+    #[pandora(range = "0..=9")]
     pub compression_level: Option<u32>,
 
     /// If `true`, upstream responses will be decompressed
@@ -165,6 +166,12 @@ mod tests {
         TestSession::from(header).await
     }
 
+    #[test]
+    fn invalid_level() {
+        <Handler as RequestFilter>::Conf::from_yaml("compression_level: 10")
+            .expect_err("compression level outside of the permitted range should be rejected");
+    }
+
     #[test(tokio::test)]
     async fn unconfigured() -> Result<(), Box<Error>> {
         let handler = make_handler(false);
@@ -24,7 +24,7 @@ use ip_anonymization_module::{IPAnonymizationHandler, IPAnonymizationOpt};
 use log::error;
 use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
 use rewrite_module::RewriteHandler;
-use startup_module::{DefaultApp, StartupConf, StartupOpt};
+use startup_module::{inherit_systemd_sockets, DefaultApp, StartupConf, StartupOpt};
 
 use web_app::{WebAppHandler, WebAppOpt};
 
@@ -58,11 +58,11 @@ struct Conf {
 
 fn main() {
     env_logger::init();
+    inherit_systemd_sockets();
 
     let opt = Opt::parse();
 
-    #[allow(unused_mut)]
-    let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
+    let conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
         Ok(conf) => conf,
         Err(err) => {
             error!("{err}");
@@ -70,10 +70,29 @@ fn main() {
         }
     };
 
+    #[allow(unused_mut)]
+    let mut conf = match opt
+        .startup
+        .set
+        .iter()
+        .flatten()
+        .try_fold(conf, |conf, assignment| {
+            conf.merge_from_override(assignment)
+        }) {
+        Ok(conf) => conf,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
     conf.handler.anonymization.merge_with_opt(opt.anonymization);
     conf.handler.compression.merge_with_opt(opt.compression);
     conf.handler.log.merge_with_opt(opt.log);
-    conf.handler.auth.merge_with_opt(opt.auth);
+    if let Err(err) = conf.handler.auth.merge_with_opt(opt.auth) {
+        error!("{err}");
+        return;
+    }
     conf.handler.web_app.merge_with_opt(opt.web_app);
 
     let server = match DefaultApp::<Handler>::from_conf(conf.handler)
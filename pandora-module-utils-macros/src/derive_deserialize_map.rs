@@ -16,7 +16,9 @@ use proc_macro::TokenStream;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::quote;
 use serde_derive_internals::attr::RenameRule;
-use syn::{spanned::Spanned, DeriveInput, Error, Field, FieldsNamed, Ident, LitStr, Path, Type};
+use syn::{
+    spanned::Spanned, DeriveInput, Error, Expr, Field, FieldsNamed, Ident, LitStr, Path, Type,
+};
 
 use crate::utils::{generics_with_de, get_fields, type_name_short, where_clause};
 
@@ -91,7 +93,9 @@ struct FieldAttributes {
     name: Ident,
     ty: Type,
     deserialize_name: Vec<LitStr>,
+    deprecated: Vec<(LitStr, LitStr)>,
     deserialize: TokenStream2,
+    checks: Vec<TokenStream2>,
     flatten: bool,
 }
 
@@ -99,9 +103,13 @@ impl FieldAttributes {
     fn parse(field: &Field, container_attrs: &ContainerAttributes) -> Result<Self, Error> {
         let mut rename = None;
         let mut deserialize_name = Vec::new();
+        let mut deprecated = Vec::new();
         let mut skip = false;
         let mut deserialize_with = None;
         let mut flatten = false;
+        let mut validate_with = None;
+        let mut range = None;
+        let mut non_empty = None;
 
         let name = if let Some(name) = &field.ident {
             name.clone()
@@ -115,6 +123,8 @@ impl FieldAttributes {
                 continue;
             }
 
+            let mut attr_aliases: Vec<LitStr> = Vec::new();
+            let mut deprecated_message = None;
             attr.parse_nested_meta(|meta| {
                 if meta.path.is_ident("rename") {
                     if rename.is_some() {
@@ -134,7 +144,14 @@ impl FieldAttributes {
                     })?;
                     Ok(())
                 } else if meta.path.is_ident("alias") {
-                    deserialize_name.push(meta.value()?.parse()?);
+                    attr_aliases.push(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("deprecated") {
+                    if deprecated_message.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate deprecated"));
+                    }
+                    let message: LitStr = meta.value()?.parse()?;
+                    deprecated_message = Some(message);
                     Ok(())
                 } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing") {
                     if skip {
@@ -166,10 +183,69 @@ impl FieldAttributes {
                         quote! {#path(self.#name, deserializer)}
                     });
                     Ok(())
+                } else if meta.path.is_ident("validate") {
+                    if validate_with.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate validate"));
+                    }
+                    let s: LitStr = meta.value()?.parse()?;
+                    validate_with = Some(s.parse_with(Path::parse_mod_style)?);
+                    Ok(())
+                } else if meta.path.is_ident("range") {
+                    if range.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate range"));
+                    }
+                    let s: LitStr = meta.value()?.parse()?;
+                    let expr: Expr = s.parse()?;
+                    if !matches!(expr, Expr::Range(_)) {
+                        return Err(Error::new_spanned(s, "range expected"));
+                    }
+                    range = Some((s, expr));
+                    Ok(())
+                } else if meta.path.is_ident("non_empty") {
+                    if non_empty.is_some() {
+                        return Err(Error::new_spanned(meta.path, "duplicate non_empty"));
+                    }
+                    non_empty = Some(meta.path);
+                    Ok(())
                 } else {
                     Err(Error::new_spanned(meta.path, "unexpected parameter"))
                 }
             })?;
+
+            if let Some(message) = deprecated_message {
+                if attr_aliases.is_empty() {
+                    return Err(Error::new_spanned(
+                        message,
+                        "deprecated has to be combined with alias",
+                    ));
+                }
+                for alias in &attr_aliases {
+                    deprecated.push((alias.clone(), message.clone()));
+                }
+            }
+            deserialize_name.extend(attr_aliases);
+        }
+
+        if flatten || skip {
+            let attr = if flatten { "flatten" } else { "skip" };
+            if let Some(path) = &validate_with {
+                return Err(Error::new_spanned(
+                    path,
+                    format!("validate is incompatible with {attr}"),
+                ));
+            }
+            if let Some((range, _)) = &range {
+                return Err(Error::new_spanned(
+                    range,
+                    format!("range is incompatible with {attr}"),
+                ));
+            }
+            if let Some(path) = &non_empty {
+                return Err(Error::new_spanned(
+                    path,
+                    format!("non_empty is incompatible with {attr}"),
+                ));
+            }
         }
 
         if flatten {
@@ -212,12 +288,69 @@ impl FieldAttributes {
             }
         });
 
+        let mut checks = Vec::new();
+        for (alias, message) in &deprecated {
+            checks.push(quote! {
+                if field == #alias {
+                    #crate_path::_private::deprecated_field(field, #message);
+                }
+            });
+        }
+        if non_empty.is_some() {
+            checks.push(quote! {
+                if let ::std::option::Option::Some(value) =
+                    (&&::std::marker::PhantomData::<#ty>).validated_value(&self.#name)
+                {
+                    if #crate_path::_private::IsEmpty::is_empty(value) {
+                        return ::std::result::Result::Err(
+                            <D::Error as #crate_path::serde::de::Error>::custom(
+                                ::std::format_args!(
+                                    "invalid value for field `{field}`: value should not be empty"
+                                )
+                            )
+                        );
+                    }
+                }
+            });
+        }
+        if let Some((range_str, range)) = range {
+            checks.push(quote! {
+                if let ::std::option::Option::Some(value) =
+                    (&&::std::marker::PhantomData::<#ty>).validated_value(&self.#name)
+                {
+                    if !(#range).contains(value) {
+                        return ::std::result::Result::Err(
+                            <D::Error as #crate_path::serde::de::Error>::custom(
+                                ::std::format_args!(
+                                    "invalid value for field `{field}`: value has to be within the range {}",
+                                    #range_str,
+                                )
+                            )
+                        );
+                    }
+                }
+            });
+        }
+        if let Some(path) = validate_with {
+            checks.push(quote! {
+                if let ::std::result::Result::Err(err) = #path(&self.#name) {
+                    return ::std::result::Result::Err(
+                        <D::Error as #crate_path::serde::de::Error>::custom(
+                            ::std::format_args!("invalid value for field `{field}`: {err}")
+                        )
+                    );
+                }
+            });
+        }
+
         Ok(Self {
             skip,
             name,
             ty,
             deserialize_name,
+            deprecated,
             deserialize,
+            checks,
             flatten,
         })
     }
@@ -313,7 +446,14 @@ fn generate_deserialize_map_impl(
         .collect::<Vec<_>>();
     let regular_deserialize_name = regular_fields.iter().map(|attr| &attr.deserialize_name);
     let regular_deserialize = regular_fields.iter().map(|attr| &attr.deserialize);
+    let regular_checks = regular_fields.iter().map(|attr| &attr.checks);
     let deserialize_name = collect_deserialize_names(&regular_fields)?;
+    let (deprecated_name, deserialize_name): (Vec<_>, Vec<_>) =
+        deserialize_name.into_iter().partition(|name| {
+            regular_fields
+                .iter()
+                .any(|attr| attr.deprecated.iter().any(|(alias, _)| alias == *name))
+        });
 
     Ok(quote! {
         const _: () = {
@@ -322,6 +462,11 @@ fn generate_deserialize_map_impl(
                     #deserialize_name,
                 )*
             ];
+            const __DEPRECATED_FIELDS: &[&::std::primitive::str] = &[
+                #(
+                    #deprecated_name,
+                )*
+            ];
 
             #vis struct __Visitor<#generics> #where_clause {
                 #(
@@ -336,7 +481,7 @@ fn generate_deserialize_map_impl(
                 type Value = #struct_name;
 
                 fn accepts_field(field: &::std::primitive::str) -> ::std::primitive::bool {
-                    if __FIELDS.contains(&field) {
+                    if __FIELDS.contains(&field) || __DEPRECATED_FIELDS.contains(&field) {
                         return true;
                     }
                     #(
@@ -359,10 +504,16 @@ fn generate_deserialize_map_impl(
                 where
                     D: #crate_path::serde::de::Deserializer<#de>
                 {
+                    #[allow(unused_imports)]
+                    use #crate_path::_private::ValidatedValue;
+
                     match field {
                         #(
                             #(#regular_deserialize_name)|* => {
                                 self.#regular_name = #regular_deserialize?;
+                                #(
+                                    #regular_checks
+                                )*
                                 ::std::result::Result::Ok(self)
                             }
                         )*
@@ -137,6 +137,39 @@ fn generate_request_filter_impl(
                     Ok(None)
                 }
 
+                fn fail_to_connect(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _peer: &::pandora_module_utils::pingora::HttpPeer,
+                    _ctx: &mut Self::CTX,
+                    e: ::std::boxed::Box<::pandora_module_utils::pingora::Error>,
+                ) -> ::std::boxed::Box<::pandora_module_utils::pingora::Error> {
+                    #(
+                        let e = self.#field_name.fail_to_connect(_session, _peer, &mut _ctx.#field_name, e);
+                    )*
+                    e
+                }
+
+                fn error_while_proxy(
+                    &self,
+                    _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
+                    _peer: &::pandora_module_utils::pingora::HttpPeer,
+                    _ctx: &mut Self::CTX,
+                    e: ::std::boxed::Box<::pandora_module_utils::pingora::Error>,
+                    _client_reused: bool,
+                ) -> ::std::boxed::Box<::pandora_module_utils::pingora::Error> {
+                    #(
+                        let e = self.#field_name.error_while_proxy(
+                            _session,
+                            _peer,
+                            &mut _ctx.#field_name,
+                            e,
+                            _client_reused,
+                        );
+                    )*
+                    e
+                }
+
                 fn response_filter(
                     &self,
                     _session: &mut impl ::pandora_module_utils::pingora::SessionWrapper,
@@ -158,6 +191,19 @@ fn generate_request_filter_impl(
                         self.#field_name.logging(_session, _e, &mut _ctx.#field_name).await;
                     )*
                 }
+
+                fn background_services(
+                    &self,
+                    _server_conf: &::pandora_module_utils::pingora::ServerConf,
+                ) -> ::std::vec::Vec<
+                    ::std::boxed::Box<dyn ::pandora_module_utils::pingora::Service>
+                > {
+                    let mut services = ::std::vec::Vec::new();
+                    #(
+                        services.extend(self.#field_name.background_services(_server_conf));
+                    )*
+                    services
+                }
             }
         };
     }
@@ -197,6 +197,12 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///
 ///   Deserialize this field from the given name or from its Rust name. May be repeated to specify
 ///   multiple possible names for the same field.
+/// * `#[pandora(alias = "name", deprecated = "message")]`
+///
+///   Same as `alias` but a warning will be logged whenever this name is used, mentioning the
+///   configuration file if known. This allows renaming fields without breaking existing
+///   configuration files. The message should explain what to use instead. Deprecated names are
+///   not listed in the “unknown field” error messages.
 /// * `#[pandora(flatten)]`
 ///
 ///   Flatten the contents of this field into the container it is defined in. This removes one
@@ -223,6 +229,24 @@ pub fn derive_request_filter(input: TokenStream) -> TokenStream {
 ///   Same as `deserialize_with` but `$module::deserialize` will be used as the `deserialize_with`
 ///   function.
 ///
+/// The following field attributes validate values when they are deserialized. Invalid values will
+/// cause a deserialization error naming the field. For fields of type `Option<T>`, `range` and
+/// `non_empty` only apply if a value is present.
+///
+/// * `#[pandora(range = "range")]`
+///
+///   Require the value to be contained in the given range, e.g. `range = "1..=9"` or
+///   `range = "..100"`.
+/// * `#[pandora(non_empty)]`
+///
+///   Reject empty values. This is supported for `String`, `PathBuf`, `Vec`, `OneOrMany`, `HashMap`
+///   and `BTreeMap` types.
+/// * `#[pandora(validate = "path")]`
+///
+///   Validate the value using the given function. The function must be callable as
+///   `fn(&T) -> Result<(), E>` where `E` implements `Display`. The error will become part of the
+///   deserialization error.
+///
 /// In addition, the following analogs of [Serde’s container
 /// attributes](https://serde.rs/container-attrs.html) are currently supported:
 ///
@@ -16,7 +16,7 @@ use async_trait::async_trait;
 use pandora_module_utils::pingora::{Error, RequestHeader, SessionWrapper, TestSession};
 use pandora_module_utils::serde::{Deserialize, Deserializer};
 use pandora_module_utils::{
-    merge_conf, DeserializeMap, FromYaml, RequestFilter, RequestFilterResult,
+    merge_conf, DeserializeMap, FromYaml, OneOrMany, RequestFilter, RequestFilterResult,
 };
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
@@ -327,6 +327,117 @@ fn field_attributes() {
     assert_eq!(conf.value6.value, String::new());
 }
 
+#[test]
+fn deprecated_aliases() {
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf {
+        #[pandora(alias = "old_value", deprecated = "use `value` instead")]
+        #[pandora(alias = "other_value")]
+        value: u32,
+        #[pandora(
+            alias = "older1",
+            alias = "older2",
+            deprecated = "use `value2` instead"
+        )]
+        value2: u32,
+    }
+
+    let conf = Conf::from_yaml("old_value: 12").unwrap();
+    assert_eq!(conf.value, 12);
+
+    let conf = conf.merge_from_yaml("other_value: 34").unwrap();
+    assert_eq!(conf.value, 34);
+
+    let conf = conf.merge_from_yaml("older2: 56").unwrap();
+    assert_eq!(conf.value2, 56);
+
+    let err = Conf::from_yaml("unknown: 1").unwrap_err().to_string();
+    assert!(err.contains("`other_value`"), "{err}");
+    assert!(!err.contains("`old_value`"), "{err}");
+    assert!(!err.contains("`older1`"), "{err}");
+}
+
+#[test]
+fn validation_attributes() {
+    fn validate_name(name: &String) -> Result<(), String> {
+        if name.contains('/') {
+            Err(format!("`{name}` should not contain slashes"))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct InnerConf {
+        #[pandora(range = "1..=9")]
+        level: Option<u32>,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf {
+        #[pandora(range = "..100")]
+        value1: u32,
+        #[pandora(non_empty)]
+        value2: Option<String>,
+        #[pandora(non_empty, validate = "validate_name", alias = "v3")]
+        value3: String,
+        #[pandora(non_empty)]
+        value4: Vec<u32>,
+        map: HashMap<String, InnerConf>,
+    }
+
+    let conf = Conf::from_yaml(
+        r#"
+            value1: 99
+            value2: hi
+            v3: name
+            value4: [1]
+            map:
+                hi:
+                    level: 9
+        "#,
+    )
+    .unwrap();
+    assert_eq!(conf.value1, 99);
+    assert_eq!(conf.value2, Some("hi".to_owned()));
+    assert_eq!(conf.value3, "name".to_owned());
+    assert_eq!(conf.value4, vec![1]);
+    assert_eq!(conf.map["hi"].level, Some(9));
+
+    // Values not present in the configuration aren’t validated
+    let conf = Conf::from_yaml("{}").unwrap();
+    assert_eq!(conf, Conf::default());
+
+    let err = Conf::from_yaml("value1: 100").unwrap_err().to_string();
+    assert!(err.contains("invalid value for field `value1`"), "{err}");
+    assert!(err.contains("..100"), "{err}");
+
+    let err = Conf::from_yaml("value2: ''").unwrap_err().to_string();
+    assert!(err.contains("invalid value for field `value2`"), "{err}");
+
+    let err = Conf::from_yaml("v3: ''").unwrap_err().to_string();
+    assert!(err.contains("invalid value for field `v3`"), "{err}");
+
+    let err = Conf::from_yaml("value3: a/b").unwrap_err().to_string();
+    assert!(err.contains("invalid value for field `value3`"), "{err}");
+    assert!(err.contains("`a/b` should not contain slashes"), "{err}");
+
+    let err = Conf::from_yaml("value4: []").unwrap_err().to_string();
+    assert!(err.contains("invalid value for field `value4`"), "{err}");
+
+    let err = Conf::from_yaml(
+        r#"
+            map:
+                hi:
+                    level: 0
+        "#,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("map.hi"), "{err}");
+    assert!(err.contains("invalid value for field `level`"), "{err}");
+}
+
 #[test]
 fn from_yaml_seed() {
     fn assert_hash_eq<V: Debug + Eq>(left: &HashMap<String, V>, right: Vec<(&str, V)>) {
@@ -491,6 +602,80 @@ fn from_yaml_seed() {
     }
 }
 
+#[test]
+fn merge_from_override() {
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct HostConf {
+        root: String,
+        #[pandora(range = "0..=9")]
+        level: Option<u32>,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+    struct Conf {
+        enabled: bool,
+        list: Vec<String>,
+        addrs: OneOrMany<String>,
+        vhosts: HashMap<String, HostConf>,
+    }
+
+    let conf = Conf::from_yaml(
+        r#"
+            list: [a]
+            addrs: [x, y]
+            vhosts:
+                localhost:
+                    root: /var/www
+                    level: 3
+        "#,
+    )
+    .unwrap();
+
+    let conf = conf
+        .merge_from_override("vhosts.localhost.root=/srv")
+        .unwrap()
+        .merge_from_override("vhosts.\"example.com\".level=5")
+        .unwrap()
+        .merge_from_override("enabled=true")
+        .unwrap()
+        .merge_from_override("list=[b, c]")
+        .unwrap()
+        .merge_from_override("addrs=z")
+        .unwrap();
+    assert!(conf.enabled);
+    assert_eq!(conf.list, vec!["b".to_owned(), "c".to_owned()]);
+    assert_eq!(*conf.addrs, vec!["z".to_owned()]);
+    assert_eq!(
+        conf.vhosts["localhost"],
+        HostConf {
+            root: "/srv".to_owned(),
+            level: Some(3),
+        }
+    );
+    assert_eq!(
+        conf.vhosts["example.com"],
+        HostConf {
+            root: String::new(),
+            level: Some(5),
+        }
+    );
+
+    conf.clone()
+        .merge_from_override("vhosts.localhost.level=10")
+        .expect_err("invalid value should be rejected");
+    conf.clone()
+        .merge_from_override("unknown=1")
+        .expect_err("unknown field should be rejected");
+    conf.clone()
+        .merge_from_override("enabled")
+        .expect_err("missing value should be rejected");
+    conf.clone()
+        .merge_from_override("list=[b, c")
+        .expect_err("unparsable value should be rejected");
+    conf.merge_from_override("vhosts..root=/srv")
+        .expect_err("empty path segment should be rejected");
+}
+
 #[test]
 fn merge_across_maps() {
     fn assert_hashmap_eq<V: Debug + Eq>(left: &HashMap<String, V>, right: Vec<(&str, V)>) {
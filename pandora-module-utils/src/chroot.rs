@@ -0,0 +1,71 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Path translation after changing the root directory
+//!
+//! Modules resolve configured paths when the configuration is loaded. If the Startup module
+//! changes the root directory afterwards, these paths have to be translated before accessing the
+//! file system.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Root directory the process changed into if any
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Records the directory that the process changed its root directory into.
+///
+/// Only the first call has an effect, the root directory cannot be changed twice.
+pub fn set_chroot(root: impl Into<PathBuf>) {
+    let _ = ROOT.set(root.into());
+}
+
+/// Translates an absolute path resolved before changing the root directory.
+///
+/// Paths outside the new root directory and relative paths are returned unchanged.
+pub fn chroot_path(path: &Path) -> Cow<'_, Path> {
+    match ROOT.get().and_then(|root| path.strip_prefix(root).ok()) {
+        Some(relative) => Cow::Owned(Path::new("/").join(relative)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translation() {
+        assert_eq!(
+            chroot_path(Path::new("/srv/www/index.html")),
+            Path::new("/srv/www/index.html")
+        );
+
+        set_chroot("/srv");
+        assert_eq!(chroot_path(Path::new("/srv")), Path::new("/"));
+        assert_eq!(
+            chroot_path(Path::new("/srv/www/index.html")),
+            Path::new("/www/index.html")
+        );
+        assert_eq!(
+            chroot_path(Path::new("/srvx/file")),
+            Path::new("/srvx/file")
+        );
+        assert_eq!(
+            chroot_path(Path::new("www/index.html")),
+            Path::new("www/index.html")
+        );
+    }
+}
@@ -279,7 +279,12 @@ impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for OneOrMany<T> {
             }
         }
 
-        deserializer.deserialize_any(ListVisitor { seed: self })
+        let seed = if _private::replacing_lists() {
+            OneOrMany::default()
+        } else {
+            self
+        };
+        deserializer.deserialize_any(ListVisitor { seed })
     }
 }
 
@@ -306,17 +311,22 @@ pub mod _private {
     //! instead:
     //! <https://lukaskalbertodt.github.io/2019/12/05/generalized-autoref-based-specialization.html>
 
+    use log::warn;
     use serde::{
         de::{DeserializeSeed, MapAccess, Visitor},
         Deserialize, Deserializer,
     };
     use std::{
+        cell::{Cell, RefCell},
         collections::{BTreeMap, HashMap},
         fmt::Formatter,
         hash::Hash,
         marker::PhantomData,
+        path::{Path, PathBuf},
     };
 
+    use super::OneOrMany;
+
     pub trait DeserializeMerge<'de, T> {
         fn deserialize_merge<D>(&self, initial: T, deserializer: D) -> Result<T, D::Error>
         where
@@ -352,6 +362,29 @@ pub mod _private {
         }
     }
 
+    // `Vec`: append entries from new value, unless lists are being replaced.
+    impl<'de, T> DeserializeMerge<'de, Vec<T>> for &&PhantomData<Vec<T>>
+    where
+        T: Deserialize<'de>,
+    {
+        fn deserialize_merge<D>(
+            &self,
+            mut initial: Vec<T>,
+            deserializer: D,
+        ) -> Result<Vec<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let value = Vec::deserialize(deserializer)?;
+            if replacing_lists() {
+                Ok(value)
+            } else {
+                initial.extend(value);
+                Ok(initial)
+            }
+        }
+    }
+
     // `Option` with type supporting `DeserializeSeed`: merge values when both present.
     impl<'de, T> DeserializeMerge<'de, Option<T>> for &&PhantomData<Option<T>>
     where
@@ -472,6 +505,138 @@ pub mod _private {
             initial.deserialize(deserializer)
         }
     }
+
+    thread_local! {
+        static CONF_FILE: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    }
+
+    /// Remembers the configuration file being loaded on the current thread while the guard is
+    /// alive, so that warnings can refer to it.
+    #[derive(Debug)]
+    pub struct ConfFileGuard {
+        previous: Option<PathBuf>,
+    }
+
+    impl ConfFileGuard {
+        pub fn new(path: &Path) -> Self {
+            let previous = CONF_FILE.with(|file| file.replace(Some(path.to_owned())));
+            Self { previous }
+        }
+    }
+
+    impl Drop for ConfFileGuard {
+        fn drop(&mut self) {
+            CONF_FILE.with(|file| *file.borrow_mut() = self.previous.take());
+        }
+    }
+
+    thread_local! {
+        static REPLACE_LISTS: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Makes lists replace existing values rather than add to them on the current thread while
+    /// the guard is alive, as required for configuration overrides.
+    #[derive(Debug)]
+    pub struct ReplaceListsGuard {
+        previous: bool,
+    }
+
+    impl ReplaceListsGuard {
+        pub fn enable() -> Self {
+            let previous = REPLACE_LISTS.with(|replace| replace.replace(true));
+            Self { previous }
+        }
+    }
+
+    impl Drop for ReplaceListsGuard {
+        fn drop(&mut self) {
+            REPLACE_LISTS.with(|replace| replace.set(self.previous));
+        }
+    }
+
+    /// Checks whether lists should replace existing values, see [`ReplaceListsGuard`].
+    pub fn replacing_lists() -> bool {
+        REPLACE_LISTS.with(Cell::get)
+    }
+
+    /// Called when a deprecated field name is encountered in the configuration.
+    pub fn deprecated_field(field: &str, message: &str) {
+        CONF_FILE.with(|file| {
+            if let Some(path) = &*file.borrow() {
+                warn!(
+                    "Deprecated configuration field `{field}` in file `{}`: {message}",
+                    path.display()
+                );
+            } else {
+                warn!("Deprecated configuration field `{field}`: {message}");
+            }
+        });
+    }
+
+    // Same approach is used for validation: `Option` values are only validated if present,
+    // other values are always validated.
+    pub trait ValidatedValue<T> {
+        type Inner;
+
+        fn validated_value<'a>(&self, value: &'a T) -> Option<&'a Self::Inner>;
+    }
+
+    impl<T> ValidatedValue<T> for PhantomData<T> {
+        type Inner = T;
+
+        fn validated_value<'a>(&self, value: &'a T) -> Option<&'a Self::Inner> {
+            Some(value)
+        }
+    }
+
+    impl<T> ValidatedValue<Option<T>> for &PhantomData<Option<T>> {
+        type Inner = T;
+
+        fn validated_value<'a>(&self, value: &'a Option<T>) -> Option<&'a Self::Inner> {
+            value.as_ref()
+        }
+    }
+
+    /// Implemented by types supporting the `non_empty` validation attribute.
+    pub trait IsEmpty {
+        fn is_empty(&self) -> bool;
+    }
+
+    impl IsEmpty for String {
+        fn is_empty(&self) -> bool {
+            self.is_empty()
+        }
+    }
+
+    impl IsEmpty for PathBuf {
+        fn is_empty(&self) -> bool {
+            self.as_os_str().is_empty()
+        }
+    }
+
+    impl<T> IsEmpty for Vec<T> {
+        fn is_empty(&self) -> bool {
+            self.is_empty()
+        }
+    }
+
+    impl<T> IsEmpty for OneOrMany<T> {
+        fn is_empty(&self) -> bool {
+            self.inner.is_empty()
+        }
+    }
+
+    impl<K, V> IsEmpty for HashMap<K, V> {
+        fn is_empty(&self) -> bool {
+            self.is_empty()
+        }
+    }
+
+    impl<K, V> IsEmpty for BTreeMap<K, V> {
+        fn is_empty(&self) -> bool {
+            self.is_empty()
+        }
+    }
 }
 
 #[cfg(test)]
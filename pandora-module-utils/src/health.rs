@@ -0,0 +1,78 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health state shared between modules
+//!
+//! Modules report problems with the components they are responsible for, e.g. an upstream server
+//! that cannot be reached. The Startup module uses this state to answer readiness probes.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Unhealthy components mapped to the description of the respective problem
+static PROBLEMS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Marks a component as unhealthy, the server will no longer be considered ready.
+///
+/// The component name should be unique to the module, e.g. `upstream http://127.0.0.1:8081`.
+/// Reporting a problem for a component that is already unhealthy replaces the description.
+pub fn set_unhealthy(component: impl Into<String>, problem: impl Into<String>) {
+    PROBLEMS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(component.into(), problem.into());
+}
+
+/// Marks a component as healthy again.
+pub fn set_healthy(component: &str) {
+    PROBLEMS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(component);
+}
+
+/// Returns all unhealthy components along with the description of their problem.
+pub fn unhealthy_components() -> Vec<(String, String)> {
+    PROBLEMS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .map(|(component, problem)| (component.clone(), problem.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_state() {
+        set_unhealthy("health_state test component", "broken");
+        assert!(unhealthy_components().contains(&(
+            "health_state test component".to_owned(),
+            "broken".to_owned()
+        )));
+
+        set_unhealthy("health_state test component", "still broken");
+        assert!(unhealthy_components().contains(&(
+            "health_state test component".to_owned(),
+            "still broken".to_owned()
+        )));
+
+        set_healthy("health_state test component");
+        assert!(!unhealthy_components()
+            .iter()
+            .any(|(component, _)| component == "health_state test component"));
+    }
+}
@@ -15,7 +15,9 @@
 #![doc = include_str!("../README.md")]
 #![allow(non_ascii_idents)]
 
+pub mod chroot;
 mod deserialize;
+pub mod health;
 #[doc(hidden)]
 pub mod jar;
 pub mod merger;
@@ -25,7 +27,7 @@ pub mod standard_response;
 mod trie;
 
 use log::{error, info, trace};
-use pingora::{Error, ErrorType, HttpPeer, ResponseHeader, SessionWrapper};
+use pingora::{Error, ErrorType, HttpPeer, ResponseHeader, ServerConf, Service, SessionWrapper};
 use serde::{de::DeserializeSeed, Deserialize};
 use std::fmt::Debug;
 use std::fs::File;
@@ -109,6 +111,33 @@ pub trait RequestFilter: Sized {
         Ok(None)
     }
 
+    /// Called when connecting to the upstream server failed, see
+    /// [`pingora::ProxyHttp::fail_to_connect`]. If the handler marks the error as retryable,
+    /// `upstream_peer` will be called again.
+    fn fail_to_connect(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        e
+    }
+
+    /// Called when an error occurred while proxying the request to the upstream server, see
+    /// [`pingora::ProxyHttp::error_while_proxy`]. If the handler marks the error as retryable,
+    /// `upstream_peer` will be called again.
+    fn error_while_proxy(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _peer: &HttpPeer,
+        _ctx: &mut Self::CTX,
+        e: Box<Error>,
+        _client_reused: bool,
+    ) -> Box<Error> {
+        e
+    }
+
     /// Called when a response header is about to be sent, either from a request filter or an
     /// upstream response.
     ///
@@ -129,13 +158,25 @@ pub trait RequestFilter: Sized {
         _ctx: &mut Self::CTX,
     ) {
     }
+
+    /// Creates the services this handler needs to run in the background, e.g. health checks.
+    /// These are added to the server along with the handler.
+    fn background_services(&self, _server_conf: &ServerConf) -> Vec<Box<dyn Service>> {
+        Vec::new()
+    }
 }
 
+/// Component name that [`FromYaml::load_from_files`] reports configuration problems under
+pub const CONFIGURATION_COMPONENT: &str = "configuration";
+
 /// Trait for configuration structures that can be loaded from YAML files. This trait has a blanket
 /// implementation for any structure implementing [`serde::Deserialize`].
 pub trait FromYaml {
     /// Loads and merges configuration from a number of YAML files. Glob patterns in file names
     /// will be resolved and file names will be sorted before further processing.
+    ///
+    /// The outcome is reported as the health of the [`CONFIGURATION_COMPONENT`]: the server is
+    /// not ready if loading failed or some patterns had to be skipped.
     fn load_from_files<I>(files: I) -> Result<Self, Box<Error>>
     where
         Self: Sized,
@@ -161,6 +202,38 @@ pub trait FromYaml {
     fn merge_from_yaml(self, yaml_conf: impl AsRef<str>) -> Result<Self, Box<Error>>
     where
         Self: Sized;
+
+    /// Applies a configuration override like `vhosts.localhost.root=/srv`, using existing data
+    /// for all other fields.
+    ///
+    /// The value is interpreted as YAML and merged into the configuration as if it were part of
+    /// a configuration file, except that lists replace existing lists. Path segments containing dots can be put in double quotes, e.g.
+    /// `vhosts."example.com".root=/srv`.
+    fn merge_from_override(self, assignment: impl AsRef<str>) -> Result<Self, Box<Error>>
+    where
+        Self: Sized;
+}
+
+fn parse_override(assignment: &str) -> Option<(Vec<String>, &str)> {
+    let mut path = Vec::new();
+    let mut segment = String::new();
+    let mut quoted = false;
+    for (i, c) in assignment.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' | '=' if !quoted => {
+                if segment.is_empty() {
+                    return None;
+                }
+                path.push(std::mem::take(&mut segment));
+                if c == '=' {
+                    return Some((path, &assignment[i + 1..]));
+                }
+            }
+            c => segment.push(c),
+        }
+    }
+    None
 }
 
 impl<D> FromYaml for D
@@ -173,29 +246,44 @@ where
         I: IntoIterator,
         I::Item: AsRef<str>,
     {
-        let mut files = files
-            .into_iter()
-            .filter_map(|path| match glob::glob(path.as_ref()) {
-                Ok(iter) => Some(iter),
+        let mut problems = Vec::new();
+        let mut paths = Vec::new();
+        for pattern in files {
+            let pattern = pattern.as_ref();
+            let matches = match glob::glob(pattern) {
+                Ok(matches) => matches,
                 Err(err) => {
-                    error!("Ignoring invalid glob pattern `{}`: {err}", path.as_ref());
-                    None
+                    error!("Ignoring invalid glob pattern `{pattern}`: {err}");
+                    problems.push(format!("invalid glob pattern `{pattern}`"));
+                    continue;
                 }
-            })
-            .flatten()
-            .filter_map(|path| match path {
-                Ok(path) => Some(path),
-                Err(err) => {
-                    error!("Failed resolving glob pattern: {err}");
-                    None
+            };
+            for path in matches {
+                match path {
+                    Ok(path) => paths.push(path),
+                    Err(err) => {
+                        error!("Failed resolving glob pattern: {err}");
+                        problems.push(format!("failed resolving glob pattern: {err}"));
+                    }
                 }
-            })
-            .collect::<Vec<_>>();
-        files.sort();
-        files.into_iter().try_fold(Self::default(), |conf, path| {
+            }
+        }
+        paths.sort();
+        let result = paths.into_iter().try_fold(Self::default(), |conf, path| {
             info!("Loading configuration file `{}`", path.display());
             conf.merge_load_from_yaml(path)
-        })
+        });
+
+        if let Err(err) = &result {
+            problems.push(err.to_string());
+        }
+        if problems.is_empty() {
+            health::set_healthy(CONFIGURATION_COMPONENT);
+        } else {
+            health::set_unhealthy(CONFIGURATION_COMPONENT, problems.join("; "));
+        }
+
+        result
     }
 
     fn load_from_yaml(path: impl AsRef<Path>) -> Result<Self, Box<Error>> {
@@ -213,6 +301,7 @@ where
         })?;
         let reader = BufReader::new(file);
 
+        let _guard = _private::ConfFileGuard::new(path);
         let conf = self
             .deserialize(serde_yaml::Deserializer::from_reader(reader))
             .map_err(|err| {
@@ -241,4 +330,39 @@ where
 
         Ok(conf)
     }
+
+    fn merge_from_override(self, assignment: impl AsRef<str>) -> Result<Self, Box<Error>> {
+        let assignment = assignment.as_ref();
+        let (path, value) = parse_override(assignment).ok_or_else(|| {
+            Error::explain(
+                ErrorType::ReadError,
+                format!("invalid configuration override `{assignment}`, expected key.path=value"),
+            )
+        })?;
+
+        let mut value = serde_yaml::from_str(value).map_err(|err| {
+            Error::because(
+                ErrorType::ReadError,
+                format!("invalid value in configuration override `{assignment}`"),
+                err,
+            )
+        })?;
+        for key in path.into_iter().rev() {
+            let mut mapping = serde_yaml::Mapping::new();
+            mapping.insert(key.into(), value);
+            value = mapping.into();
+        }
+
+        let _guard = _private::ReplaceListsGuard::enable();
+        let conf = self.deserialize(value).map_err(|err| {
+            Error::because(
+                ErrorType::ReadError,
+                format!("failed applying configuration override `{assignment}`"),
+                err,
+            )
+        })?;
+        trace!("Applied configuration override: {conf:#?}");
+
+        Ok(conf)
+    }
 }
@@ -24,6 +24,7 @@ pub use pingora::protocols::l4::socket::SocketAddr;
 pub use pingora::proxy::{http_proxy_service, ProxyHttp, Session};
 pub use pingora::server::configuration::{Opt as ServerOpt, ServerConf};
 pub use pingora::server::Server;
+pub use pingora::services::Service;
 pub use pingora::upstreams::peer::HttpPeer;
 pub use pingora::{Error, ErrorType};
 use std::borrow::Cow;
@@ -78,6 +79,35 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
         self.extensions_mut().insert(addr);
     }
 
+    /// Returns the verified TLS client certificate of the connection if any.
+    ///
+    /// A certificate that failed verification (accepted with optional client authentication) isn’t
+    /// returned here. It can still be retrieved from the extensions, with `verify_error` set.
+    fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.extensions()
+            .get::<ClientCertificate>()
+            .filter(|certificate| certificate.verify_error.is_none())
+    }
+
+    /// Sets the TLS client certificate for this connection.
+    fn set_client_certificate(&mut self, certificate: ClientCertificate) {
+        self.extensions_mut().insert(certificate);
+    }
+
+    /// Returns the name of the listener that accepted the connection, if it has one.
+    fn listener_name(&self) -> Option<&str> {
+        if let Some(ListenerName(name)) = self.extensions().get() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Sets the name of the listener that accepted the connection.
+    fn set_listener_name(&mut self, name: String) {
+        self.extensions_mut().insert(ListenerName(name));
+    }
+
     /// Returns a reference to the associated extensions.
     fn extensions(&self) -> &Extensions;
 
@@ -145,10 +175,33 @@ pub trait SessionWrapper: Send + Deref<Target = Session> + DerefMut {
     }
 }
 
+/// Details of a TLS client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Subject of the certificate, e.g. `CN=client,O=Example,C=DE`
+    pub subject: String,
+
+    /// Common name from the certificate subject if present
+    pub common_name: Option<String>,
+
+    /// Subject alternative names: DNS names, email addresses, URIs and IP addresses
+    pub subject_alt_names: Vec<String>,
+
+    /// SHA-256 fingerprint of the certificate as lowercase hex string
+    pub fingerprint: String,
+
+    /// Reason the certificate failed verification, `None` for verified certificates
+    pub verify_error: Option<String>,
+}
+
 /// Type used to store remote user’s name in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct RemoteUser(String);
 
+/// Type used to store the listener name in `SessionWrapper::extensions`
+#[derive(Debug, Clone)]
+struct ListenerName(String);
+
 /// Type used to store original request URI in `SessionWrapper::extensions`
 #[derive(Debug, Clone)]
 struct OriginalUri(Uri);
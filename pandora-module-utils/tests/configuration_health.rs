@@ -0,0 +1,56 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that configuration loading is reflected in the health state. This needs its own test
+//! binary, the configuration component is shared by all configuration loads in the process.
+
+use pandora_module_utils::health::unhealthy_components;
+use pandora_module_utils::{DeserializeMap, FromYaml, CONFIGURATION_COMPONENT};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+struct Conf {
+    value: u32,
+}
+
+fn problem() -> Option<String> {
+    unhealthy_components()
+        .into_iter()
+        .find(|(component, _)| component == CONFIGURATION_COMPONENT)
+        .map(|(_, problem)| problem)
+}
+
+#[test]
+fn configuration_health() {
+    let dir = std::env::temp_dir().join(format!("configuration-health-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let valid = dir.join("valid.yaml");
+    std::fs::write(&valid, "value: 12\n").unwrap();
+    let invalid = dir.join("invalid.yaml");
+    std::fs::write(&invalid, "value: twelve\n").unwrap();
+
+    assert!(Conf::load_from_files([invalid.to_str().unwrap()]).is_err());
+    assert!(problem().is_some_and(|problem| problem.contains("invalid.yaml")));
+
+    // A successful reload makes the server ready again
+    let conf = Conf::load_from_files([valid.to_str().unwrap()]).unwrap();
+    assert_eq!(conf.value, 12);
+    assert_eq!(problem(), None);
+
+    // Skipped patterns are problems as well
+    let conf = Conf::load_from_files(["[", valid.to_str().unwrap()]).unwrap();
+    assert_eq!(conf.value, 12);
+    assert!(problem().is_some_and(|problem| problem.contains("invalid glob pattern `[`")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
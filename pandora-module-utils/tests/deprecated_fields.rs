@@ -0,0 +1,73 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks the warnings produced for deprecated configuration fields. This needs its own test
+//! binary, the logger can only be installed once per process.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pandora_module_utils::{DeserializeMap, FromYaml};
+use std::sync::Mutex;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+struct Conf {
+    #[pandora(alias = "old_value", deprecated = "use `value` instead")]
+    value: u32,
+}
+
+#[test]
+fn deprecated_field_warning() {
+    log::set_logger(&CaptureLogger).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+
+    let path = std::env::temp_dir().join(format!("deprecated-fields-{}.yaml", std::process::id()));
+    std::fs::write(&path, "old_value: 12\n").unwrap();
+    let conf = Conf::load_from_files([path.to_str().unwrap()]).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(conf.value, 12);
+
+    let conf = Conf::from_yaml("value: 34").unwrap();
+    assert_eq!(conf.value, 34);
+
+    let conf = Conf::from_yaml("old_value: 56").unwrap();
+    assert_eq!(conf.value, 56);
+
+    assert_eq!(
+        *WARNINGS.lock().unwrap(),
+        [
+            format!(
+                "Deprecated configuration field `old_value` in file `{}`: use `value` instead",
+                path.display()
+            ),
+            "Deprecated configuration field `old_value`: use `value` instead".to_owned(),
+        ]
+    );
+}
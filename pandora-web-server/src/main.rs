@@ -17,7 +17,7 @@
 use clap::Parser;
 use log::error;
 use pandora_module_utils::{merge_conf, merge_opt, FromYaml, RequestFilter};
-use startup_module::{DefaultApp, StartupConf, StartupOpt};
+use startup_module::{inherit_systemd_sockets, DefaultApp, StartupConf, StartupOpt};
 
 #[derive(Debug, Clone, PartialEq, Eq, RequestFilter)]
 struct Handler {
@@ -78,11 +78,11 @@ struct Opt {
     anonymization: ip_anonymization_module::IPAnonymizationOpt,
     #[cfg(feature = "common-log-top-level")]
     log: common_log_module::CommonLogOpt,
-    #[cfg(feature = "auth-top-level")]
+    #[cfg(feature = "compression-top-level")]
     compression: compression_module::CompressionOpt,
-    #[cfg(feature = "static-files-top-level")]
+    #[cfg(feature = "auth-top-level")]
     auth: auth_module::AuthOpt,
-    #[cfg(feature = "compression-top-level")]
+    #[cfg(feature = "static-files-top-level")]
     static_files: static_files_module::StaticFilesOpt,
 }
 
@@ -95,11 +95,11 @@ struct Conf {
 
 fn main() {
     env_logger::init();
+    inherit_systemd_sockets();
 
     let opt = Opt::parse();
 
-    #[allow(unused_mut)]
-    let mut conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
+    let conf = match Conf::load_from_files(opt.startup.conf.as_deref().unwrap_or(&[])) {
         Ok(conf) => conf,
         Err(err) => {
             error!("{err}");
@@ -107,6 +107,22 @@ fn main() {
         }
     };
 
+    #[allow(unused_mut)]
+    let mut conf = match opt
+        .startup
+        .set
+        .iter()
+        .flatten()
+        .try_fold(conf, |conf, assignment| {
+            conf.merge_from_override(assignment)
+        }) {
+        Ok(conf) => conf,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
     #[cfg(feature = "ip-anonymization-top-level")]
     conf.handler.anonymization.merge_with_opt(opt.anonymization);
     #[cfg(feature = "common-log-top-level")]
@@ -114,7 +130,10 @@ fn main() {
     #[cfg(feature = "compression-top-level")]
     conf.handler.compression.merge_with_opt(opt.compression);
     #[cfg(feature = "auth-top-level")]
-    conf.handler.auth.merge_with_opt(opt.auth);
+    if let Err(err) = conf.handler.auth.merge_with_opt(opt.auth) {
+        error!("{err}");
+        return;
+    }
     #[cfg(feature = "static-files-top-level")]
     conf.handler.static_files.merge_with_opt(opt.static_files);
 
@@ -0,0 +1,1194 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ACME (RFC 8555) client obtaining and renewing certificates automatically
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http::{header, Method, StatusCode};
+use log::{error, info};
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcKeyRef};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{select_next_proto, AlpnError, NameType, SslRef};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509ReqBuilder, X509};
+use pandora_module_utils::chroot::chroot_path;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::connectors::http::Connector;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::tls::ext::{ssl_use_certificate, ssl_use_private_key};
+use pingora::utils::CertKey;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{rename, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::certificates::Certificates;
+use crate::configuration::CertKeyConf;
+use crate::http_client::http_request;
+
+pub(crate) const ACME_ERR: ErrorType = ErrorType::Custom("ACMEError");
+
+/// ALPN protocol identifier used for TLS-ALPN-01 challenges (RFC 8737)
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Path prefix used for HTTP-01 challenges
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Interval between checks whether certificates need renewing
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Delay before retrying after a failed renewal
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Delay between polling requests while the ACME server is processing
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximal number of polling requests
+const POLL_ATTEMPTS: usize = 30;
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn crypto_error(err: ErrorStack) -> Box<Error> {
+    Error::because(ACME_ERR, "cryptographic operation failed", err)
+}
+
+pub(crate) fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Reads the ACME account key, generates and stores a new one if there is none yet.
+fn load_account_key(path: &Path) -> Result<PKey<Private>, Box<Error>> {
+    if chroot_path(path).exists() {
+        let data = CertKeyConf::read_file(path)?;
+        PKey::private_key_from_pem(&data)
+            .map_err(|err| Error::because(ACME_ERR, "failed parsing ACME account key", err))
+    } else {
+        let key = generate_key().map_err(crypto_error)?;
+        write_private(path, &key.private_key_to_pem_pkcs8().map_err(crypto_error)?)?;
+        Ok(key)
+    }
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<(), Box<Error>> {
+    let path = &chroot_path(path);
+
+    // Write to a temporary file first, so that a reload never sees a partially written file
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|_| rename(&tmp_path, path))
+        .map_err(|err| {
+            Error::because(
+                ACME_ERR,
+                format!("failed writing file {}", path.display()),
+                err,
+            )
+        })
+}
+
+pub(crate) fn self_signed_certificate(
+    name: &str,
+    key: &PKey<Private>,
+    valid_days: u32,
+    extension: Option<X509Extension>,
+) -> Result<X509, ErrorStack> {
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_nid(Nid::COMMONNAME, name)?;
+    let subject = subject.build();
+
+    let mut cert = X509Builder::new()?;
+    cert.set_version(2)?;
+    cert.set_subject_name(&subject)?;
+    cert.set_issuer_name(&subject)?;
+    cert.set_pubkey(key)?;
+    cert.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    cert.set_not_after(Asn1Time::days_from_now(valid_days)?.as_ref())?;
+    let san = SubjectAlternativeName::new()
+        .dns(name)
+        .build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(san)?;
+    if let Some(extension) = extension {
+        cert.append_extension(extension)?;
+    }
+    cert.sign(key, MessageDigest::sha256())?;
+    Ok(cert.build())
+}
+
+/// Creates the certificate presented for a TLS-ALPN-01 challenge
+fn tls_alpn_certificate(name: &str, key_authorization: &str) -> Result<CertKey, ErrorStack> {
+    let key = generate_key()?;
+
+    // The acmeIdentifier extension contains the DER-encoded key authorization digest
+    let mut value = vec![0x04, 0x20];
+    value.extend_from_slice(&hash(
+        MessageDigest::sha256(),
+        key_authorization.as_bytes(),
+    )?);
+    let extension = X509Extension::new_from_der(
+        Asn1Object::from_str("1.3.6.1.5.5.7.1.31")?.as_ref(),
+        true,
+        Asn1OctetString::new_from_bytes(&value)?.as_ref(),
+    )?;
+
+    let cert = self_signed_certificate(name, &key, 1, Some(extension))?;
+    Ok(CertKey::new(vec![cert], key))
+}
+
+fn create_csr(name: &str, key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_nid(Nid::COMMONNAME, name)?;
+
+    let mut request = X509ReqBuilder::new()?;
+    request.set_pubkey(key)?;
+    request.set_subject_name(&subject.build())?;
+    let mut extensions = Stack::new()?;
+    extensions.push(
+        SubjectAlternativeName::new()
+            .dns(name)
+            .build(&request.x509v3_context(None))?,
+    )?;
+    request.add_extensions(&extensions)?;
+    request.sign(key, MessageDigest::sha256())?;
+    request.build().to_der()
+}
+
+/// Makes sure that certificate and key files exist for a name managed via ACME
+///
+/// If no certificate was obtained yet, an expired self-signed certificate is stored as a
+/// placeholder. It allows the server to start up and will be replaced immediately.
+pub(crate) fn ensure_certificate(name: &str, conf: &CertKeyConf) -> Result<(), Box<Error>> {
+    let (Some(cert_path), Some(key_path)) = (&conf.cert_path, &conf.key_path) else {
+        return Ok(());
+    };
+    if chroot_path(cert_path).exists() && chroot_path(key_path).exists() {
+        return Ok(());
+    }
+
+    let key = generate_key().map_err(crypto_error)?;
+    let cert = self_signed_certificate(name, &key, 0, None).map_err(crypto_error)?;
+    write_private(
+        key_path,
+        &key.private_key_to_pem_pkcs8().map_err(crypto_error)?,
+    )?;
+    write_private(cert_path, &cert.to_pem().map_err(crypto_error)?)
+}
+
+/// Challenge type to respond to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChallengeType {
+    /// HTTP-01 challenge, answered by the TLS redirector
+    Http01,
+    /// TLS-ALPN-01 challenge, answered by the TLS listener
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+/// Responses to ACME challenges currently being validated
+#[derive(Debug, Default)]
+pub(crate) struct AcmeChallenges {
+    http: Mutex<HashMap<String, String>>,
+    tls_alpn: Mutex<HashMap<String, CertKey>>,
+}
+
+impl AcmeChallenges {
+    /// Returns the key authorization if the path belongs to a pending HTTP-01 challenge
+    pub(crate) fn http_response(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PREFIX)?;
+        self.http.lock().unwrap().get(token).cloned()
+    }
+
+    /// Returns the certificate for a pending TLS-ALPN-01 challenge
+    pub(crate) fn tls_alpn_certificate(&self, name: &str) -> Option<CertKey> {
+        self.tls_alpn.lock().unwrap().get(name).cloned()
+    }
+
+    pub(crate) fn add(
+        &self,
+        challenge_type: ChallengeType,
+        name: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<ChallengeGuard<'_>, Box<Error>> {
+        let key = match challenge_type {
+            ChallengeType::Http01 => {
+                self.http
+                    .lock()
+                    .unwrap()
+                    .insert(token.to_owned(), key_authorization.to_owned());
+                token.to_owned()
+            }
+            ChallengeType::TlsAlpn01 => {
+                let cert = tls_alpn_certificate(name, key_authorization).map_err(crypto_error)?;
+                self.tls_alpn.lock().unwrap().insert(name.to_owned(), cert);
+                name.to_owned()
+            }
+        };
+        Ok(ChallengeGuard {
+            challenges: self,
+            challenge_type,
+            key,
+        })
+    }
+}
+
+/// Removes a challenge response once validation is done
+#[derive(Debug)]
+pub(crate) struct ChallengeGuard<'a> {
+    challenges: &'a AcmeChallenges,
+    challenge_type: ChallengeType,
+    key: String,
+}
+
+impl Drop for ChallengeGuard<'_> {
+    fn drop(&mut self) {
+        match self.challenge_type {
+            ChallengeType::Http01 => {
+                self.challenges.http.lock().unwrap().remove(&self.key);
+            }
+            ChallengeType::TlsAlpn01 => {
+                self.challenges.tls_alpn.lock().unwrap().remove(&self.key);
+            }
+        }
+    }
+}
+
+/// ALPN selection callback answering TLS-ALPN-01 challenges
+///
+/// If the client requests the `acme-tls/1` protocol, the challenge certificate for the requested
+/// server name is used. Otherwise HTTP/1.1 is selected, or HTTP/2 if enabled for the listener.
+pub(crate) fn select_alpn<'a>(
+    challenges: &AcmeChallenges,
+    http2: bool,
+    ssl: &mut SslRef,
+    client: &'a [u8],
+) -> Result<&'a [u8], AlpnError> {
+    if let Some(protocol) = select_next_proto(b"\x0aacme-tls/1", client) {
+        let cert = ssl
+            .servername(NameType::HOST_NAME)
+            .and_then(|name| challenges.tls_alpn_certificate(name))
+            .ok_or(AlpnError::ALERT_FATAL)?;
+        ssl_use_certificate(ssl, cert.leaf()).map_err(|_| AlpnError::ALERT_FATAL)?;
+        ssl_use_private_key(ssl, cert.key()).map_err(|_| AlpnError::ALERT_FATAL)?;
+        return Ok(protocol);
+    }
+
+    let server: &[u8] = if http2 {
+        b"\x02h2\x08http/1.1"
+    } else {
+        b"\x08http/1.1"
+    };
+    select_next_proto(server, client).ok_or(AlpnError::NOACK)
+}
+
+/// Settings of the ACME client
+#[derive(Debug, Clone)]
+pub(crate) struct AcmeSettings {
+    pub(crate) directory: String,
+    pub(crate) contact: Option<String>,
+    pub(crate) ca_certs: Option<Arc<Box<[X509]>>>,
+    pub(crate) account_key_path: PathBuf,
+    pub(crate) names: Vec<(String, CertKeyConf)>,
+    pub(crate) renew_before_days: u32,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    problem_type: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    challenge_type: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+/// ACME objects that have to be polled until processing is done
+trait Pollable: DeserializeOwned {
+    fn status(&self) -> &str;
+}
+
+impl Pollable for Order {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+impl Pollable for Authorization {
+    fn status(&self) -> &str {
+        &self.status
+    }
+}
+
+#[derive(Debug)]
+struct Response {
+    status: StatusCode,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn parse<T: DeserializeOwned>(&self) -> Result<T, Box<Error>> {
+        serde_json::from_slice(&self.body)
+            .map_err(|err| Error::because(ACME_ERR, "failed parsing ACME server response", err))
+    }
+}
+
+/// Client session with an ACME server using a particular account
+struct AcmeClient<'a> {
+    settings: &'a AcmeSettings,
+    connector: Connector,
+    directory: Directory,
+    key: PKey<Private>,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl std::fmt::Debug for AcmeClient<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcmeClient")
+            .field("directory", &self.directory)
+            .field("kid", &self.kid)
+            .finish()
+    }
+}
+
+impl<'a> AcmeClient<'a> {
+    /// Connects to the ACME server and registers the account if necessary
+    async fn new(settings: &'a AcmeSettings) -> Result<Self, Box<Error>> {
+        let key = load_account_key(&settings.account_key_path)?;
+        let ec_key = key.ec_key().map_err(crypto_error)?;
+        let (jwk, thumbprint) = Self::jwk(&ec_key).map_err(crypto_error)?;
+
+        let mut client = Self {
+            settings,
+            connector: Connector::new(None),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+
+        client.directory = client
+            .request(Method::GET, &settings.directory, None)
+            .await?
+            .parse()?;
+
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(contact) = &settings.contact {
+            account["contact"] = json!([format!("mailto:{contact}")]);
+        }
+        let new_account = client.directory.new_account.clone();
+        let response = client.post(&new_account, Some(&account)).await?;
+        client.kid = Some(response.location.ok_or_else(|| {
+            Error::explain(ACME_ERR, "ACME server didn't return the account URL")
+        })?);
+
+        Ok(client)
+    }
+
+    /// Determines JSON Web Key and its RFC 7638 thumbprint for an account key
+    fn jwk<T: HasPublic>(ec_key: &EcKeyRef<T>) -> Result<(Value, String), ErrorStack> {
+        let mut context = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec_key
+            .public_key()
+            .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut context)?;
+        let x = b64(x.to_vec_padded(32)?);
+        let y = b64(y.to_vec_padded(32)?);
+
+        // Thumbprint input has the required members in lexicographic order without whitespace
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint = b64(hash(MessageDigest::sha256(), canonical.as_bytes())?);
+        let jwk = json!({"crv": "P-256", "kty": "EC", "x": x, "y": y});
+        Ok((jwk, thumbprint))
+    }
+
+    /// Produces a JWS-signed request body in flattened JSON serialization
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Vec<u8>, ErrorStack> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        if let Some(kid) = &self.kid {
+            protected["kid"] = json!(kid);
+        } else {
+            protected["jwk"] = self.jwk.clone();
+        }
+        let protected = b64(protected.to_string());
+        // An empty payload signifies POST-as-GET requests
+        let payload = payload
+            .map(|payload| b64(payload.to_string()))
+            .unwrap_or_default();
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(format!("{protected}.{payload}").as_bytes())?;
+        let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend_from_slice(&signature.s().to_vec_padded(32)?);
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(raw),
+        })
+        .to_string()
+        .into_bytes())
+    }
+
+    async fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response, Box<Error>> {
+        let response = http_request(
+            &self.connector,
+            ACME_ERR,
+            method,
+            url,
+            body.map(|body| ("application/jose+json", body)),
+            self.settings.ca_certs.clone(),
+        )
+        .await?;
+
+        let header = |name| {
+            response
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+        if let Some(nonce) = header("Replay-Nonce") {
+            self.nonce = Some(nonce);
+        }
+
+        Ok(Response {
+            status: response.status,
+            location: header(header::LOCATION.as_str()),
+            body: response.body,
+        })
+    }
+
+    /// Sends a signed request, `None` payload meaning a POST-as-GET request
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, Box<Error>> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let new_nonce = self.directory.new_nonce.clone();
+                    self.request(Method::GET, &new_nonce, None).await?;
+                    self.nonce.take().ok_or_else(|| {
+                        Error::explain(ACME_ERR, "ACME server didn't provide a nonce")
+                    })?
+                }
+            };
+
+            let body = self.sign(url, &nonce, payload).map_err(crypto_error)?;
+            let response = self.request(Method::POST, url, Some(body)).await?;
+            if response.status.is_success() {
+                return Ok(response);
+            }
+
+            let problem: Problem = response.parse().unwrap_or_default();
+            if problem.problem_type == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::explain(
+                ACME_ERR,
+                format!(
+                    "ACME request to {url} failed with status {}: {} {}",
+                    response.status, problem.problem_type, problem.detail
+                ),
+            ));
+        }
+    }
+
+    /// Polls an ACME object until the server is done processing it
+    async fn poll<T: Pollable>(&mut self, url: &str) -> Result<T, Box<Error>> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object: T = self.post(url, None).await?.parse()?;
+            if !matches!(object.status(), "pending" | "processing") {
+                return Ok(object);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::explain(
+            ACME_ERR,
+            format!("timed out waiting for ACME server to process {url}"),
+        ))
+    }
+
+    async fn authorize(
+        &mut self,
+        url: &str,
+        challenges: &AcmeChallenges,
+        challenge_type: ChallengeType,
+    ) -> Result<(), Box<Error>> {
+        let authorization: Authorization = self.post(url, None).await?.parse()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let name = &authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.challenge_type == challenge_type.as_str())
+            .ok_or_else(|| {
+                Error::explain(
+                    ACME_ERR,
+                    format!(
+                        "ACME server didn't offer {} challenge for {name}",
+                        challenge_type.as_str()
+                    ),
+                )
+            })?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+
+        let _guard = challenges.add(challenge_type, name, &challenge.token, &key_authorization)?;
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        let authorization: Authorization = self.poll(url).await?;
+        if authorization.status != "valid" {
+            let detail = authorization
+                .challenges
+                .iter()
+                .filter_map(|challenge| challenge.error.as_ref())
+                .map(|problem| problem.detail.as_str())
+                .next()
+                .unwrap_or_default();
+            return Err(Error::explain(
+                ACME_ERR,
+                format!("authorization for {name} failed: {detail}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Obtains a new certificate, returns the PEM-encoded certificate chain and the private key
+    async fn issue(
+        &mut self,
+        name: &str,
+        challenges: &AcmeChallenges,
+        challenge_type: ChallengeType,
+    ) -> Result<(Vec<u8>, PKey<Private>), Box<Error>> {
+        let new_order = self.directory.new_order.clone();
+        let response = self
+            .post(
+                &new_order,
+                Some(&json!({"identifiers": [{"type": "dns", "value": name}]})),
+            )
+            .await?;
+        let order_url = response
+            .location
+            .clone()
+            .ok_or_else(|| Error::explain(ACME_ERR, "ACME server didn't return the order URL"))?;
+        let order: Order = response.parse()?;
+
+        for url in &order.authorizations {
+            self.authorize(url, challenges, challenge_type).await?;
+        }
+
+        let key = generate_key().map_err(crypto_error)?;
+        let csr = create_csr(name, &key).map_err(crypto_error)?;
+        self.post(&order.finalize, Some(&json!({"csr": b64(csr)})))
+            .await?;
+
+        let order: Order = self.poll(&order_url).await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            _ => {
+                return Err(Error::explain(
+                    ACME_ERR,
+                    format!(
+                        "ACME order for {name} failed: {}",
+                        order
+                            .error
+                            .map(|problem| problem.detail)
+                            .unwrap_or_default()
+                    ),
+                ))
+            }
+        };
+
+        let chain = self.post(&certificate, None).await?.body;
+        Ok((chain, key))
+    }
+}
+
+/// Background service obtaining certificates and renewing them before they expire
+#[derive(Debug)]
+pub(crate) struct AcmeService {
+    pub(crate) settings: Arc<AcmeSettings>,
+    pub(crate) certificates: Arc<Certificates>,
+    pub(crate) challenges: Arc<AcmeChallenges>,
+    pub(crate) challenge_type: ChallengeType,
+}
+
+impl AcmeService {
+    fn needs_renewal(&self, name: &str) -> bool {
+        let Ok(threshold) = Asn1Time::days_from_now(self.settings.renew_before_days) else {
+            return false;
+        };
+        self.certificates
+            .get()
+            .get(name)
+            .map_or(true, |cert| cert.leaf().not_after() < threshold.as_ref())
+    }
+
+    async fn renew(&self) -> Result<(), Box<Error>> {
+        let due = self
+            .settings
+            .names
+            .iter()
+            .filter(|(name, _)| self.needs_renewal(name))
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut client = AcmeClient::new(&self.settings).await?;
+        let mut result = Ok(());
+        let mut renewed = false;
+        for (name, conf) in due {
+            let issued = client
+                .issue(name, &self.challenges, self.challenge_type)
+                .await
+                .and_then(|(chain, key)| {
+                    let (Some(cert_path), Some(key_path)) = (&conf.cert_path, &conf.key_path)
+                    else {
+                        return Ok(());
+                    };
+                    let key = key.private_key_to_pem_pkcs8().map_err(crypto_error)?;
+                    write_private(key_path, &key)?;
+                    write_private(cert_path, &chain)
+                });
+            match issued {
+                Ok(()) => {
+                    info!("Obtained new certificate for {name} via ACME");
+                    renewed = true;
+                }
+                Err(err) => {
+                    error!("Failed obtaining certificate for {name} via ACME: {err}");
+                    result = Err(err);
+                }
+            }
+        }
+
+        if renewed {
+            self.certificates.reload()?;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AcmeService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            let delay = match self.renew().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(err) => {
+                    error!("ACME certificate renewal failed, will retry later: {err}");
+                    RETRY_INTERVAL
+                }
+            };
+
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::pkey::Public;
+    use openssl::x509::X509Req;
+    use pandora_module_utils::pingora::{HttpPeer, ProxyHttp, ResponseHeader, Session};
+    use pingora::proxy::http_proxy_service;
+    use pingora::server::configuration::ServerConf;
+    use pingora::services::Service;
+    use test_log::test;
+    use tokio::sync::watch;
+
+    const NAME: &str = "example.com";
+    const TOKEN: &str = "challenge-token";
+
+    #[derive(Debug, Default)]
+    struct MockState {
+        account_key: Option<EcKey<Public>>,
+        thumbprint: String,
+        nonce: u32,
+        rejected_nonce: bool,
+        validated: bool,
+        certificate: Option<Vec<u8>>,
+    }
+
+    /// Minimal ACME server verifying request signatures and challenge responses
+    struct MockAcme {
+        base: String,
+        state: Mutex<MockState>,
+        challenges: Arc<AcmeChallenges>,
+        ca: (X509, PKey<Private>),
+    }
+
+    fn decode(data: &Value) -> Option<Vec<u8>> {
+        URL_SAFE_NO_PAD.decode(data.as_str()?).ok()
+    }
+
+    impl MockAcme {
+        fn new(base: String, challenges: Arc<AcmeChallenges>) -> Self {
+            let key = generate_key().unwrap();
+            let mut subject = X509NameBuilder::new().unwrap();
+            subject
+                .append_entry_by_nid(Nid::COMMONNAME, "Test ACME CA")
+                .unwrap();
+            let subject = subject.build();
+            let mut cert = X509Builder::new().unwrap();
+            cert.set_version(2).unwrap();
+            cert.set_subject_name(&subject).unwrap();
+            cert.set_issuer_name(&subject).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+                .unwrap();
+            cert.set_not_after(Asn1Time::days_from_now(90).unwrap().as_ref())
+                .unwrap();
+            cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+            Self {
+                base,
+                state: Default::default(),
+                challenges,
+                ca: (cert.build(), key),
+            }
+        }
+
+        fn order(&self, state: &MockState) -> Value {
+            let mut order = json!({
+                "status": if state.certificate.is_some() {
+                    "valid"
+                } else if state.validated {
+                    "ready"
+                } else {
+                    "pending"
+                },
+                "authorizations": [format!("{}/authz/1", self.base)],
+                "finalize": format!("{}/finalize/1", self.base),
+            });
+            if state.certificate.is_some() {
+                order["certificate"] = json!(format!("{}/cert/1", self.base));
+            }
+            order
+        }
+
+        /// Verifies request signature, returns the payload or `null` for POST-as-GET requests
+        fn verify(&self, state: &mut MockState, body: &[u8]) -> Option<Value> {
+            let jws: Value = serde_json::from_slice(body).ok()?;
+            let protected: Value = serde_json::from_slice(&decode(&jws["protected"])?).ok()?;
+            assert_eq!(protected["alg"], "ES256");
+            assert_eq!(protected["nonce"], format!("nonce{}", state.nonce));
+
+            let key = if let Some(jwk) = protected.get("jwk") {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+                let x = BigNum::from_slice(&decode(&jwk["x"])?).ok()?;
+                let y = BigNum::from_slice(&decode(&jwk["y"])?).ok()?;
+                let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y).ok()?;
+                state.account_key = Some(key.clone());
+                let (_, thumbprint) = AcmeClient::jwk(&key).ok()?;
+                state.thumbprint = thumbprint;
+                key
+            } else {
+                assert_eq!(protected["kid"], format!("{}/account/1", self.base));
+                state.account_key.clone()?
+            };
+
+            let signature = decode(&jws["signature"])?;
+            let signature = EcdsaSig::from_private_components(
+                BigNum::from_slice(&signature[..32]).ok()?,
+                BigNum::from_slice(&signature[32..]).ok()?,
+            )
+            .ok()?;
+            let data = format!(
+                "{}.{}",
+                jws["protected"].as_str()?,
+                jws["payload"].as_str()?
+            );
+            let digest = hash(MessageDigest::sha256(), data.as_bytes()).ok()?;
+            if !signature.verify(&digest, &key).ok()? {
+                return None;
+            }
+
+            let payload = decode(&jws["payload"])?;
+            if payload.is_empty() {
+                Some(Value::Null)
+            } else {
+                serde_json::from_slice(&payload).ok()
+            }
+        }
+
+        fn handle(&self, path: &str, body: &[u8]) -> (u16, Option<String>, Vec<u8>) {
+            let mut state = self.state.lock().unwrap();
+            if path == "/dir" {
+                let directory = json!({
+                    "newNonce": format!("{}/nonce", self.base),
+                    "newAccount": format!("{}/account", self.base),
+                    "newOrder": format!("{}/order", self.base),
+                });
+                return (200, None, directory.to_string().into_bytes());
+            }
+            if path == "/nonce" {
+                return (204, None, Vec::new());
+            }
+
+            let Some(payload) = self.verify(&mut state, body) else {
+                let problem = json!({"type": "urn:ietf:params:acme:error:malformed"});
+                return (400, None, problem.to_string().into_bytes());
+            };
+
+            // Reject the first nonce to test recovery
+            if !state.rejected_nonce {
+                state.rejected_nonce = true;
+                let problem = json!({"type": "urn:ietf:params:acme:error:badNonce"});
+                return (400, None, problem.to_string().into_bytes());
+            }
+
+            let key_authorization = format!("{TOKEN}.{}", state.thumbprint);
+            let (status, location, response) = match path {
+                "/account" => {
+                    assert_eq!(payload["termsOfServiceAgreed"], true);
+                    assert_eq!(payload["contact"], json!(["mailto:admin@example.com"]));
+                    (
+                        201,
+                        Some(format!("{}/account/1", self.base)),
+                        json!({"status": "valid"}),
+                    )
+                }
+                "/order" => {
+                    assert_eq!(
+                        payload["identifiers"],
+                        json!([{"type": "dns", "value": NAME}])
+                    );
+                    (
+                        201,
+                        Some(format!("{}/order/1", self.base)),
+                        self.order(&state),
+                    )
+                }
+                "/order/1" => (200, None, self.order(&state)),
+                "/authz/1" => {
+                    let authorization = json!({
+                        "status": if state.validated { "valid" } else { "pending" },
+                        "identifier": {"type": "dns", "value": NAME},
+                        "challenges": [
+                            {"type": "http-01", "url": format!("{}/chall/http", self.base), "token": TOKEN},
+                            {"type": "tls-alpn-01", "url": format!("{}/chall/alpn", self.base), "token": TOKEN},
+                        ],
+                    });
+                    (200, None, authorization)
+                }
+                "/chall/http" => {
+                    state.validated = self
+                        .challenges
+                        .http_response(&format!("/.well-known/acme-challenge/{TOKEN}"))
+                        == Some(key_authorization);
+                    (200, None, json!({}))
+                }
+                "/chall/alpn" => {
+                    let cert = self.challenges.tls_alpn_certificate(NAME).unwrap();
+                    let expected =
+                        hash(MessageDigest::sha256(), key_authorization.as_bytes()).unwrap();
+                    let der = cert.leaf().to_der().unwrap();
+                    state.validated = der.windows(expected.len()).any(|w| w == &*expected);
+                    (200, None, json!({}))
+                }
+                "/finalize/1" => {
+                    let csr = decode(&payload["csr"]).unwrap();
+                    let csr = X509Req::from_der(&csr).unwrap();
+                    let public_key = csr.public_key().unwrap();
+                    assert!(csr.verify(&public_key).unwrap());
+
+                    let mut cert = X509Builder::new().unwrap();
+                    cert.set_version(2).unwrap();
+                    cert.set_subject_name(csr.subject_name()).unwrap();
+                    cert.set_issuer_name(self.ca.0.subject_name()).unwrap();
+                    cert.set_pubkey(&public_key).unwrap();
+                    cert.set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+                        .unwrap();
+                    cert.set_not_after(Asn1Time::days_from_now(90).unwrap().as_ref())
+                        .unwrap();
+                    cert.sign(&self.ca.1, MessageDigest::sha256()).unwrap();
+
+                    let mut chain = cert.build().to_pem().unwrap();
+                    chain.extend_from_slice(&self.ca.0.to_pem().unwrap());
+                    state.certificate = Some(chain);
+                    (200, None, self.order(&state))
+                }
+                "/cert/1" => {
+                    state.nonce += 1;
+                    return (200, None, state.certificate.clone().unwrap());
+                }
+                _ => (404, None, json!({})),
+            };
+            (status, location, response.to_string().into_bytes())
+        }
+    }
+
+    #[async_trait]
+    impl ProxyHttp for MockAcme {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<bool, Box<Error>> {
+            let path = session.req_header().uri.path().to_owned();
+            let mut body = Vec::new();
+            while let Some(chunk) = session.read_request_body().await? {
+                body.extend_from_slice(&chunk);
+            }
+
+            let (status, location, response) = self.handle(&path, &body);
+            let mut header = ResponseHeader::build(status, Some(3))?;
+            header.append_header("Content-Length", response.len().to_string())?;
+            let nonce = {
+                let mut state = self.state.lock().unwrap();
+                state.nonce += 1;
+                state.nonce
+            };
+            header.append_header("Replay-Nonce", format!("nonce{nonce}"))?;
+            if let Some(location) = location {
+                header.append_header("Location", location)?;
+            }
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(response.into()).await?;
+            Ok(true)
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+    }
+
+    async fn issue_certificate(addr: &str, challenge_type: ChallengeType) {
+        let challenges = Arc::new(AcmeChallenges::default());
+        let mut service = http_proxy_service(
+            &Arc::new(ServerConf::default()),
+            MockAcme::new(format!("http://{addr}"), challenges.clone()),
+        );
+        service.add_tcp(addr);
+        let (_shutdown, watch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        let dir = std::env::temp_dir().join(format!(
+            "startup-module-acme-{}-{}",
+            challenge_type.as_str(),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf = CertKeyConf {
+            cert_path: Some(dir.join("cert.pem")),
+            key_path: Some(dir.join("key.pem")),
+            ..Default::default()
+        };
+        ensure_certificate(NAME, &conf).unwrap();
+        let certificates = Arc::new(
+            Certificates::new(HashMap::from([(NAME.to_owned(), conf.clone())]), None).unwrap(),
+        );
+
+        let service = AcmeService {
+            settings: Arc::new(AcmeSettings {
+                directory: format!("http://{addr}/dir"),
+                contact: Some("admin@example.com".to_owned()),
+                ca_certs: None,
+                account_key_path: dir.join("account.pem"),
+                names: vec![(NAME.to_owned(), conf)],
+                renew_before_days: 30,
+            }),
+            certificates: certificates.clone(),
+            challenges: challenges.clone(),
+            challenge_type,
+        };
+
+        // Placeholder certificate expires immediately
+        assert!(service.needs_renewal(NAME));
+
+        // Give the mock server a moment to start up
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        service.renew().await.unwrap();
+
+        assert!(!service.needs_renewal(NAME));
+        let certificates = certificates.get();
+        let cert = certificates.get(NAME).unwrap();
+        let issuer = cert
+            .leaf()
+            .issuer_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string();
+        assert_eq!(issuer, "Test ACME CA");
+        assert_eq!(cert.intermediates().len(), 1);
+        assert!(dir.join("account.pem").exists());
+
+        // Challenge responses should be gone after validation
+        assert!(challenges
+            .http_response(&format!("/.well-known/acme-challenge/{TOKEN}"))
+            .is_none());
+        assert!(challenges.tls_alpn_certificate(NAME).is_none());
+    }
+
+    #[test(tokio::test)]
+    async fn issue_http01() {
+        issue_certificate("127.0.0.1:18452", ChallengeType::Http01).await;
+    }
+
+    #[test(tokio::test)]
+    async fn issue_tls_alpn01() {
+        issue_certificate("127.0.0.1:18453", ChallengeType::TlsAlpn01).await;
+    }
+
+    #[test]
+    fn account_key_chroot() {
+        let dir =
+            std::env::temp_dir().join(format!("startup-module-acme-chroot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = generate_key().unwrap();
+        std::fs::write(
+            dir.join("account.pem"),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+
+        // Configured paths are resolved outside the chroot, the key has to be found nevertheless
+        let root = PathBuf::from(format!("/startup-module-root-{}", std::process::id()));
+        pandora_module_utils::chroot::set_chroot(&root);
+        let configured = root
+            .join(dir.strip_prefix("/").unwrap())
+            .join("account.pem");
+        let loaded = load_account_key(&configured).unwrap();
+        assert!(loaded.public_eq(&key));
+        assert!(!configured.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn challenge_guard() {
+        let challenges = AcmeChallenges::default();
+        let path = "/.well-known/acme-challenge/token";
+        {
+            let _guard = challenges
+                .add(ChallengeType::Http01, NAME, "token", "token.thumbprint")
+                .unwrap();
+            assert_eq!(
+                challenges.http_response(path).as_deref(),
+                Some("token.thumbprint")
+            );
+            assert_eq!(challenges.http_response("/token"), None);
+        }
+        assert_eq!(challenges.http_response(path), None);
+    }
+}
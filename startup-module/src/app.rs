@@ -0,0 +1,477 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wrapper around the app provided by the caller
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::HeaderMap;
+use pandora_module_utils::pingora::{
+    Error, ErrorType, HttpPeer, ProxyHttp, RequestHeader, ResponseHeader, Session, SocketAddr,
+};
+use pingora::cache::key::HashBinary;
+use pingora::cache::{CacheKey, CacheMeta, RespCacheable};
+use pingora::protocols::Digest;
+use pingora::proxy::PurgeStatus;
+use std::borrow::Cow;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::health::HealthEndpoints;
+use crate::listener::add_strict_transport_security;
+use crate::redirector::Redirect;
+use crate::relay::is_unrelayed;
+
+/// Wrapper around the app, answering some requests before the app gets to see them
+///
+/// Connections to internal relay listeners that weren’t established by the relay are rejected.
+/// Health endpoints are answered first then. If the wrapper belongs to the TLS redirector, requests
+/// are redirected next unless they are exempt from redirecting. Everything else is delegated to
+/// the app, which is shared between the main service and the redirector.
+///
+/// Responses from upstream servers get the `Strict-Transport-Security` header if configured for
+/// the listener.
+pub(crate) struct StartupApp<SV> {
+    app: Arc<SV>,
+    health: Option<Arc<HealthEndpoints>>,
+    redirect: Option<Redirect>,
+}
+
+impl<SV> StartupApp<SV> {
+    /// Creates a wrapper around the app, with health endpoints if configured.
+    pub(crate) fn new(app: Arc<SV>, health: Option<Arc<HealthEndpoints>>) -> Self {
+        Self {
+            app,
+            health,
+            redirect: None,
+        }
+    }
+
+    /// Makes the wrapper redirect requests to HTTPS.
+    pub(crate) fn with_redirect(self, redirect: Redirect) -> Self {
+        Self {
+            redirect: Some(redirect),
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl<SV> ProxyHttp for StartupApp<SV>
+where
+    SV: ProxyHttp + Send + Sync,
+    SV::CTX: Send + Sync,
+{
+    type CTX = SV::CTX;
+
+    fn new_ctx(&self) -> Self::CTX {
+        self.app.new_ctx()
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        if let (Some(SocketAddr::Inet(local)), Some(SocketAddr::Inet(peer))) =
+            (session.server_addr(), session.client_addr())
+        {
+            if is_unrelayed(local, peer) {
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(403),
+                    format!("rejecting connection from {peer}, it wasn't relayed"),
+                ));
+            }
+        }
+
+        if let Some(health) = &self.health {
+            if health.respond(session).await? {
+                return Ok(true);
+            }
+        }
+
+        if let Some(redirect) = &self.redirect {
+            if redirect.respond(session).await? {
+                return Ok(true);
+            }
+        }
+
+        self.app.request_filter(session, ctx).await
+    }
+
+    fn request_cache_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app.request_cache_filter(session, ctx)
+    }
+
+    fn cache_key_callback(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<CacheKey, Box<Error>> {
+        self.app.cache_key_callback(session, ctx)
+    }
+
+    fn cache_miss(&self, session: &mut Session, ctx: &mut Self::CTX) {
+        self.app.cache_miss(session, ctx)
+    }
+
+    async fn cache_hit_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Result<bool, Box<Error>> {
+        self.app.cache_hit_filter(meta, ctx, req).await
+    }
+
+    async fn proxy_upstream_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        self.app.proxy_upstream_filter(session, ctx).await
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable, Box<Error>> {
+        self.app.response_cache_filter(session, resp, ctx)
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        self.app.cache_vary_filter(meta, ctx, req)
+    }
+
+    fn cache_not_modified_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<Error>> {
+        self.app.cache_not_modified_filter(session, resp, ctx)
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>, Box<Error>> {
+        self.app.upstream_peer(session, ctx).await
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .upstream_request_filter(session, upstream_request, ctx)
+            .await
+    }
+
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        self.app
+            .upstream_response_filter(session, upstream_response, ctx)
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .response_filter(session, upstream_response, ctx)
+            .await?;
+        add_strict_transport_security(session, upstream_response)
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        self.app
+            .upstream_response_body_filter(session, body, end_of_stream, ctx)
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .upstream_response_trailer_filter(session, upstream_trailers, ctx)
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>, Box<Error>> {
+        self.app
+            .response_body_filter(session, body, end_of_stream, ctx)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Bytes>, Box<Error>> {
+        self.app
+            .response_trailer_filter(session, upstream_trailers, ctx)
+            .await
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        self.app.logging(session, e, ctx).await
+    }
+
+    fn suppress_error_log(&self, session: &Session, ctx: &Self::CTX, error: &Error) -> bool {
+        self.app.suppress_error_log(session, ctx, error)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        self.app
+            .error_while_proxy(peer, session, e, ctx, client_reused)
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        self.app.fail_to_connect(session, peer, ctx, e)
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16 {
+        self.app.fail_to_proxy(session, e, ctx).await
+    }
+
+    fn should_serve_stale(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+        error: Option<&Error>,
+    ) -> bool {
+        self.app.should_serve_stale(session, ctx, error)
+    }
+
+    async fn connected_to_upstream(
+        &self,
+        session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .connected_to_upstream(session, reused, peer, fd, digest, ctx)
+            .await
+    }
+
+    fn request_summary(&self, session: &Session, ctx: &Self::CTX) -> String {
+        self.app.request_summary(session, ctx)
+    }
+
+    fn is_purge(&self, session: &Session, ctx: &Self::CTX) -> bool {
+        self.app.is_purge(session, ctx)
+    }
+
+    fn purge_response_filter(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+        purge_status: PurgeStatus,
+        purge_response: &mut Cow<'static, ResponseHeader>,
+    ) -> Result<(), Box<Error>> {
+        self.app
+            .purge_response_filter(session, ctx, purge_status, purge_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::StatusCode;
+    use pandora_module_utils::pingora::ErrorType;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::configuration::{HealthConf, TlsRedirectorConf};
+
+    /// App answering all requests itself, counting the requests it gets to see
+    #[derive(Debug, Default)]
+    struct TestApp {
+        requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ProxyHttp for TestApp {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<bool, Box<Error>> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            let text = "handled";
+            let mut header = ResponseHeader::build(StatusCode::OK, Some(1))?;
+            header.append_header("Content-Length", text.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(text.into()).await?;
+            Ok(true)
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+    }
+
+    fn health() -> Option<Arc<HealthEndpoints>> {
+        HealthEndpoints::from_conf(HealthConf {
+            liveness_path: Some("/healthz".to_owned()),
+            ..Default::default()
+        })
+        .unwrap()
+        .map(Arc::new)
+    }
+
+    fn redirect() -> Redirect {
+        Redirect::from_conf(
+            &TlsRedirectorConf {
+                redirect_to: "example.com".to_owned(),
+                exempt: vec!["/exempt".to_owned()].into(),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap()
+    }
+
+    /// Passes a request to the wrapper, returns status code and body of the response.
+    async fn request(wrapper: &StartupApp<TestApp>, path: &str) -> (u16, String) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+
+        let mut session = Session::new_h1(Box::new(server));
+        assert!(session.read_request().await.unwrap());
+        assert!(wrapper.request_filter(&mut session, &mut ()).await.unwrap());
+        drop(session);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_owned())
+    }
+
+    #[test(tokio::test)]
+    async fn delegation() {
+        let app = Arc::new(TestApp::default());
+        let wrapper = StartupApp::new(app.clone(), None);
+
+        assert_eq!(
+            request(&wrapper, "/healthz").await,
+            (200, "handled".to_owned())
+        );
+        assert_eq!(app.requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn health_first() {
+        let app = Arc::new(TestApp::default());
+        let wrapper = StartupApp::new(app.clone(), health());
+
+        assert_eq!(
+            request(&wrapper, "/healthz").await,
+            (200, "OK\n".to_owned())
+        );
+        assert_eq!(app.requests.load(Ordering::Relaxed), 0);
+
+        assert_eq!(
+            request(&wrapper, "/other").await,
+            (200, "handled".to_owned())
+        );
+        assert_eq!(app.requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_after_health() {
+        let app = Arc::new(TestApp::default());
+        let wrapper = StartupApp::new(app.clone(), health()).with_redirect(redirect());
+
+        // Health endpoints aren’t redirected
+        assert_eq!(
+            request(&wrapper, "/healthz").await,
+            (200, "OK\n".to_owned())
+        );
+
+        let (status, _) = request(&wrapper, "/other").await;
+        assert_eq!(status, 308);
+        assert_eq!(app.requests.load(Ordering::Relaxed), 0);
+
+        assert_eq!(
+            request(&wrapper, "/exempt").await,
+            (200, "handled".to_owned())
+        );
+        assert_eq!(app.requests.load(Ordering::Relaxed), 1);
+    }
+}
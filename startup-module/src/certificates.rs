@@ -0,0 +1,315 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Certificate storage supporting reloads while the server is running
+
+use arc_swap::{ArcSwap, Guard};
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pandora_module_utils::chroot::chroot_path;
+use pandora_module_utils::pingora::Error;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::utils::CertKey;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::configuration::{CertKeyConf, TLS_CONF_ERR};
+use crate::ocsp::check_response;
+use crate::systemd::{notify, notify_reloading};
+
+/// OCSP responses to staple, by server name
+type OcspResponses = HashMap<String, Arc<Vec<u8>>>;
+
+/// Modification times of the certificate, key and OCSP response files
+type FileTimes = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// Subject alternative names of the loaded certificates mapped to their server names
+type SanNames = Vec<(String, String)>;
+
+/// Checks whether a server name matches a wildcard pattern like `*.example.com`
+///
+/// The wildcard only covers a single label, so `example.com` and `a.b.example.com` don’t match.
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    pattern.strip_prefix("*.").is_some_and(|parent| {
+        name.split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == parent)
+    })
+}
+
+/// Certificates for all server names, with the default certificate stored under an empty name
+#[derive(Debug)]
+pub(crate) struct Certificates {
+    sources: HashMap<String, CertKeyConf>,
+    current: ArcSwap<HashMap<String, CertKey>>,
+    san_names: ArcSwap<SanNames>,
+    ocsp: ArcSwap<OcspResponses>,
+    reloaded: Notify,
+    file_times: Mutex<FileTimes>,
+    reload_interval: Option<Duration>,
+}
+
+impl Certificates {
+    /// Loads the certificates, failing if any of them cannot be loaded
+    pub(crate) fn new(
+        sources: HashMap<String, CertKeyConf>,
+        reload_interval: Option<Duration>,
+    ) -> Result<Self, Box<Error>> {
+        let file_times = Mutex::new(Self::file_times(&sources));
+        let current = Self::load(&sources)?;
+        let san_names = ArcSwap::from_pointee(Self::san_names(&current));
+        let ocsp = ArcSwap::from_pointee(Self::load_ocsp(&sources)?);
+        Ok(Self {
+            sources,
+            current: ArcSwap::from_pointee(current),
+            san_names,
+            ocsp,
+            reloaded: Notify::new(),
+            file_times,
+            reload_interval,
+        })
+    }
+
+    fn load(
+        sources: &HashMap<String, CertKeyConf>,
+    ) -> Result<HashMap<String, CertKey>, Box<Error>> {
+        let mut certificates = HashMap::with_capacity(sources.len());
+        for (name, conf) in sources {
+            let cert = conf.to_certificate().map_err(|err| {
+                let context = if name.is_empty() {
+                    "failed setting up default certificate/key".to_owned()
+                } else {
+                    format!("failed setting up certificate/key for server name {name}")
+                };
+                Error::because(TLS_CONF_ERR, context, err)
+            })?;
+            certificates.insert(name.clone(), cert);
+        }
+        Ok(certificates)
+    }
+
+    fn load_ocsp(sources: &HashMap<String, CertKeyConf>) -> Result<OcspResponses, Box<Error>> {
+        let mut responses = HashMap::new();
+        for (name, conf) in sources {
+            if let Some(path) = &conf.ocsp_path {
+                let response = CertKeyConf::read_file(path)?;
+                check_response(&response).map_err(|err| {
+                    Error::because(
+                        TLS_CONF_ERR,
+                        format!("invalid OCSP response in {}", path.display()),
+                        err,
+                    )
+                })?;
+                responses.insert(name.clone(), Arc::new(response));
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Collects DNS names from the certificates, explicit names take precedence over wildcards
+    fn san_names(certificates: &HashMap<String, CertKey>) -> SanNames {
+        let mut names: SanNames = certificates
+            .iter()
+            .filter(|(name, _)| !name.is_empty())
+            .flat_map(|(name, cert)| {
+                cert.leaf()
+                    .subject_alt_names()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|san| san.dnsname().map(str::to_ascii_lowercase))
+                    .map(|san| (san, name.clone()))
+            })
+            .collect();
+        names.sort_by(|(san1, name1), (san2, name2)| {
+            (san1.starts_with("*."), name1).cmp(&(san2.starts_with("*."), name2))
+        });
+        names
+    }
+
+    fn file_times(sources: &HashMap<String, CertKeyConf>) -> FileTimes {
+        sources
+            .values()
+            .flat_map(|conf| [&conf.cert_path, &conf.key_path, &conf.ocsp_path])
+            .flatten()
+            .map(|path| {
+                let modified = chroot_path(path)
+                    .metadata()
+                    .and_then(|meta| meta.modified())
+                    .ok();
+                (path.clone(), modified)
+            })
+            .collect()
+    }
+
+    /// Determines the configured server name to use for the name requested by the client
+    ///
+    /// Explicitly configured server names are checked first, then wildcard names like
+    /// `*.example.com`. Finally, the subject alternative names of the loaded certificates are
+    /// considered. `None` means that the default certificate should be used.
+    pub(crate) fn resolve(&self, server_name: &str) -> Option<&str> {
+        let server_name = server_name.to_ascii_lowercase();
+        let configured = |name: &str| {
+            self.sources
+                .get_key_value(name)
+                .map(|(name, _)| name.as_str())
+        };
+
+        if let Some(name) = configured(&server_name) {
+            return Some(name);
+        }
+
+        if let Some((_, parent)) = server_name.split_once('.') {
+            if let Some(name) = configured(&format!("*.{parent}")) {
+                return Some(name);
+            }
+        }
+
+        self.san_names
+            .load()
+            .iter()
+            .find(|(san, _)| *san == server_name || matches_wildcard(san, &server_name))
+            .and_then(|(_, name)| configured(name))
+    }
+
+    /// Retrieves the currently active certificates
+    pub(crate) fn get(&self) -> Guard<Arc<HashMap<String, CertKey>>> {
+        self.current.load()
+    }
+
+    /// Reloads all certificates from disk
+    ///
+    /// The active certificates are only replaced if all certificates could be loaded
+    /// successfully. Otherwise the files are considered modified still, so that the next check
+    /// retries loading them.
+    pub(crate) fn reload(&self) -> Result<(), Box<Error>> {
+        let file_times = Self::file_times(&self.sources);
+        let current = Self::load(&self.sources)?;
+        let mut ocsp = Self::load_ocsp(&self.sources)?;
+
+        // Fetched OCSP responses remain valid as long as the certificate didn’t change
+        let previous = self.current.load();
+        for (name, response) in self.ocsp.load().iter() {
+            let unchanged = previous
+                .get(name)
+                .zip(current.get(name))
+                .is_some_and(|(old, new)| old.leaf() == new.leaf());
+            if unchanged && !ocsp.contains_key(name) {
+                ocsp.insert(name.clone(), response.clone());
+            }
+        }
+
+        self.san_names.store(Arc::new(Self::san_names(&current)));
+        self.current.store(Arc::new(current));
+        self.ocsp.store(Arc::new(ocsp));
+        *self.file_times.lock().unwrap() = file_times;
+        self.reloaded.notify_one();
+        Ok(())
+    }
+
+    /// Waits until certificates are reloaded
+    pub(crate) async fn reloaded(&self) {
+        self.reloaded.notified().await
+    }
+
+    /// Retrieves the OCSP response to staple for a server name
+    pub(crate) fn ocsp_response(&self, name: &str) -> Option<Arc<Vec<u8>>> {
+        self.ocsp.load().get(name).cloned()
+    }
+
+    /// Replaces the OCSP response fetched for a server name
+    pub(crate) fn set_ocsp_response(&self, name: &str, response: Option<Vec<u8>>) {
+        let response = response.map(Arc::new);
+        self.ocsp.rcu(|responses| {
+            let mut responses = HashMap::clone(responses);
+            if let Some(response) = &response {
+                responses.insert(name.to_owned(), response.clone());
+            } else {
+                responses.remove(name);
+            }
+            responses
+        });
+    }
+
+    /// Server names with OCSP responses that need to be fetched rather than read from a file
+    pub(crate) fn names_without_ocsp_file(&self) -> impl Iterator<Item = &str> {
+        self.sources
+            .iter()
+            .filter(|(_, conf)| conf.ocsp_path.is_none())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Reloads certificates if any of the files changed since the last check
+    pub(crate) fn reload_if_modified(&self) -> Result<bool, Box<Error>> {
+        if *self.file_times.lock().unwrap() == Self::file_times(&self.sources) {
+            return Ok(false);
+        }
+
+        self.reload()?;
+        Ok(true)
+    }
+}
+
+/// Background service reloading certificates on `SIGHUP` or when files change
+#[derive(Debug)]
+pub(crate) struct CertificateReloader {
+    pub(crate) certificates: Arc<Certificates>,
+}
+
+#[async_trait]
+impl BackgroundService for CertificateReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(err) => {
+                error!("Failed listening to SIGHUP, certificates won’t reload on signal: {err}");
+                None
+            }
+        };
+        let mut timer = self.certificates.reload_interval.map(|period| {
+            let mut timer = interval(period);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        });
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                Some(_) = async { hangup.as_mut()?.recv().await } => {
+                    notify_reloading();
+                    match self.certificates.reload() {
+                        Ok(()) => info!("Reloaded TLS certificates"),
+                        Err(err) => {
+                            warn!("Failed reloading TLS certificates, keeping the previous ones: {err}");
+                        }
+                    }
+                    notify("READY=1");
+                }
+                Some(_) = async { Some(timer.as_mut()?.tick().await) } => {
+                    match self.certificates.reload_if_modified() {
+                        Ok(true) => info!("Reloaded modified TLS certificates"),
+                        Ok(false) => {}
+                        Err(err) => {
+                            warn!("Failed reloading modified TLS certificates, keeping the previous ones: {err}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS client certificate verification
+
+use openssl::ex_data::Index;
+use pandora_module_utils::pingora::{ClientCertificate, Session};
+use pingora::tls::error::ErrorStack;
+use pingora::tls::hash::MessageDigest;
+use pingora::tls::nid::Nid;
+use pingora::tls::ssl::{Ssl, SslRef, SslVerifyMode};
+use pingora::tls::x509::store::X509StoreBuilder;
+use pingora::tls::x509::{X509Ref, X509StoreContext, X509StoreContextRef, X509};
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Mutex, OnceLock};
+
+/// Details of client certificates used by open connections, by their SHA-256 digest
+///
+/// Pingora only keeps the certificate digest around after the TLS handshake, so the details have
+/// to be extracted while the certificate is still accessible. Each entry counts the connections
+/// referring to it and is removed once the last of these connections is closed.
+static CERTIFICATES: Mutex<BTreeMap<Vec<u8>, (ClientCertificate, usize)>> =
+    Mutex::new(BTreeMap::new());
+
+/// Client certificate of a connection, stored in the connection’s ex-data
+#[derive(Debug)]
+struct ConnectionCertificate {
+    required: bool,
+    current: Mutex<Option<Vec<u8>>>,
+    verify_error: Mutex<Option<String>>,
+}
+
+impl ConnectionCertificate {
+    /// Returns the ex-data index used to store the connection’s certificate
+    fn index() -> Result<Index<Ssl, Self>, ErrorStack> {
+        static INDEX: OnceLock<Index<Ssl, ConnectionCertificate>> = OnceLock::new();
+        if let Some(index) = INDEX.get() {
+            return Ok(*index);
+        }
+        let index = Ssl::new_ex_index()?;
+        Ok(*INDEX.get_or_init(|| index))
+    }
+
+    /// Associates the connection with a certificate, replacing any previous one.
+    ///
+    /// If the same certificate failed verification for another connection, e.g. because of
+    /// different CA certificates configured for the server name, the failure is kept. The
+    /// certificate digest alone doesn’t allow telling these connections apart.
+    fn set(&self, digest: Vec<u8>, certificate: ClientCertificate) {
+        let mut current = self.current.lock().unwrap_or_else(|err| err.into_inner());
+        let mut certificates = CERTIFICATES.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(previous) = current.take() {
+            release(&mut certificates, &previous);
+        }
+        let (existing, connections) = certificates
+            .entry(digest.clone())
+            .or_insert((certificate.clone(), 0));
+        if existing.verify_error.is_none() {
+            existing.verify_error = certificate.verify_error;
+        }
+        *connections += 1;
+        *current = Some(digest);
+    }
+}
+
+impl Drop for ConnectionCertificate {
+    fn drop(&mut self) {
+        let current = self
+            .current
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(digest) = current.take() {
+            release(
+                &mut CERTIFICATES.lock().unwrap_or_else(|err| err.into_inner()),
+                &digest,
+            );
+        }
+    }
+}
+
+/// Drops a connection’s reference to a certificate, removing the certificate if unused.
+fn release(certificates: &mut BTreeMap<Vec<u8>, (ClientCertificate, usize)>, digest: &[u8]) {
+    if let Some((_, connections)) = certificates.get_mut(digest) {
+        *connections -= 1;
+        if *connections == 0 {
+            certificates.remove(digest);
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn certificate_details(cert: &X509Ref) -> Result<(Vec<u8>, ClientCertificate), ErrorStack> {
+    let digest = cert.digest(MessageDigest::sha256())?.to_vec();
+
+    let mut subject = Vec::new();
+    let mut common_name = None;
+    for entry in cert.subject_name().entries() {
+        let name = entry.object().nid().short_name().unwrap_or("?");
+        let value = entry.data().as_utf8()?.to_string();
+        if entry.object().nid() == Nid::COMMONNAME && common_name.is_none() {
+            common_name = Some(value.clone());
+        }
+        subject.push(format!("{name}={value}"));
+    }
+    // RFC 4514 lists the most specific component first
+    subject.reverse();
+
+    let subject_alt_names = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    if let Some(dns) = name.dnsname() {
+                        Some(dns.to_owned())
+                    } else if let Some(email) = name.email() {
+                        Some(email.to_owned())
+                    } else if let Some(uri) = name.uri() {
+                        Some(uri.to_owned())
+                    } else {
+                        match name.ipaddress()? {
+                            [a, b, c, d] => Some(Ipv4Addr::new(*a, *b, *c, *d).to_string()),
+                            addr => <[u8; 16]>::try_from(addr)
+                                .ok()
+                                .map(|addr| Ipv6Addr::from(addr).to_string()),
+                        }
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let certificate = ClientCertificate {
+        subject: subject.join(","),
+        common_name,
+        subject_alt_names,
+        fingerprint: hex(&digest),
+        verify_error: None,
+    };
+    Ok((digest, certificate))
+}
+
+fn verify_callback(preverified: bool, ctx: &mut X509StoreContextRef) -> bool {
+    let connection = X509StoreContext::ssl_idx()
+        .ok()
+        .and_then(|index| ctx.ex_data(index))
+        .zip(ConnectionCertificate::index().ok())
+        .and_then(|(ssl, index)| ssl.ex_data(index));
+    let Some(connection) = connection else {
+        return preverified;
+    };
+
+    // Verification continues after an error is ignored, keep the first one
+    let mut verify_error = connection
+        .verify_error
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if !preverified && verify_error.is_none() {
+        *verify_error = Some(ctx.error().error_string().to_owned());
+    }
+
+    if ctx.error_depth() == 0 && (preverified || !connection.required) {
+        if let Some(Ok((digest, mut certificate))) = ctx.current_cert().map(certificate_details) {
+            certificate.verify_error = verify_error.clone();
+            connection.set(digest, certificate);
+        }
+    }
+
+    // Without a required certificate, failures are recorded rather than rejecting the client
+    preverified || !connection.required
+}
+
+/// Client certificate verification settings for a server name
+#[derive(Debug, Clone)]
+pub(crate) struct ClientAuth {
+    pub(crate) required: bool,
+    pub(crate) ca_certs: Vec<X509>,
+}
+
+impl ClientAuth {
+    /// Makes the connection request a client certificate and verify it against the CA
+    /// certificates.
+    pub(crate) fn apply(&self, ssl: &mut SslRef) -> Result<(), ErrorStack> {
+        let mut store = X509StoreBuilder::new()?;
+        for cert in &self.ca_certs {
+            store.add_cert(cert.clone())?;
+        }
+        ssl.set_verify_cert_store(store.build())?;
+        ssl.set_ex_data(
+            ConnectionCertificate::index()?,
+            ConnectionCertificate {
+                required: self.required,
+                current: Mutex::new(None),
+                verify_error: Mutex::new(None),
+            },
+        );
+
+        let mut mode = SslVerifyMode::PEER;
+        if self.required {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        ssl.set_verify_callback(mode, verify_callback);
+        Ok(())
+    }
+}
+
+/// Looks up the details of the client certificate used for this session’s connection.
+pub(crate) fn client_certificate(session: &Session) -> Option<ClientCertificate> {
+    let digest = session.digest()?.ssl_digest.as_ref()?;
+    if digest.cert_digest.is_empty() {
+        return None;
+    }
+
+    lookup(&digest.cert_digest)
+}
+
+/// Looks up the details of a certificate used by an open connection.
+fn lookup(digest: &[u8]) -> Option<ClientCertificate> {
+    CERTIFICATES
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(digest)
+        .map(|(certificate, _)| certificate.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslContext, SslMethod};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::net::IpAddr;
+
+    fn generate_cert(name: &str) -> X509 {
+        let key = PKey::generate_ed25519().unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("C", "DE").unwrap();
+        subject.append_entry_by_text("O", "Example").unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(&format!("{name}.example.com"))
+            .email("client@example.com")
+            .ip("192.0.2.1")
+            .ip("2001:db8::1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::null()).unwrap();
+        cert.build()
+    }
+
+    fn new_connection() -> Ssl {
+        let context = SslContext::builder(SslMethod::tls_server())
+            .unwrap()
+            .build();
+        let mut ssl = Ssl::new(&context).unwrap();
+        ClientAuth {
+            required: true,
+            ca_certs: Vec::new(),
+        }
+        .apply(&mut ssl)
+        .unwrap();
+        ssl
+    }
+
+    fn connection_certificate(ssl: &Ssl) -> &ConnectionCertificate {
+        ssl.ex_data(ConnectionCertificate::index().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn details() {
+        let cert = generate_cert("details");
+        let (digest, certificate) = certificate_details(&cert).unwrap();
+        assert_eq!(
+            digest,
+            cert.digest(MessageDigest::sha256()).unwrap().to_vec()
+        );
+        assert_eq!(certificate.subject, "CN=details,O=Example,C=DE");
+        assert_eq!(certificate.common_name.as_deref(), Some("details"));
+        assert_eq!(
+            certificate.subject_alt_names,
+            vec![
+                "details.example.com".to_owned(),
+                "client@example.com".to_owned(),
+                IpAddr::from([192, 0, 2, 1]).to_string(),
+                "2001:db8::1".to_owned(),
+            ]
+        );
+        assert_eq!(certificate.fingerprint, hex(&digest));
+        assert_eq!(certificate.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn connection_lifetime() {
+        let (digest, certificate) = certificate_details(&generate_cert("lifetime")).unwrap();
+        assert_eq!(lookup(&digest), None);
+
+        let first = new_connection();
+        let second = new_connection();
+        connection_certificate(&first).set(digest.clone(), certificate.clone());
+        connection_certificate(&second).set(digest.clone(), certificate.clone());
+        assert_eq!(lookup(&digest), Some(certificate.clone()));
+
+        // Details are kept as long as any connection uses the certificate
+        drop(first);
+        assert_eq!(lookup(&digest), Some(certificate));
+        drop(second);
+        assert_eq!(lookup(&digest), None);
+    }
+
+    #[test]
+    fn connection_certificate_replaced() {
+        let (old_digest, old_certificate) = certificate_details(&generate_cert("old")).unwrap();
+        let (new_digest, new_certificate) = certificate_details(&generate_cert("new")).unwrap();
+
+        let ssl = new_connection();
+        connection_certificate(&ssl).set(old_digest.clone(), old_certificate);
+        connection_certificate(&ssl).set(new_digest.clone(), new_certificate.clone());
+        assert_eq!(lookup(&old_digest), None);
+        assert_eq!(lookup(&new_digest), Some(new_certificate));
+
+        drop(ssl);
+        assert_eq!(lookup(&new_digest), None);
+    }
+}
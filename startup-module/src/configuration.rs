@@ -14,11 +14,15 @@
 
 use async_trait::async_trait;
 use clap::Parser;
+use http::HeaderValue;
+use log::{debug, error};
+use pandora_module_utils::chroot::chroot_path;
 use pandora_module_utils::pingora::{
     http_proxy_service, Error, ErrorType, ProxyHttp, Server, ServerConf, ServerOpt,
 };
 use pandora_module_utils::{DeserializeMap, OneOrMany};
-use pingora::listeners::{TcpSocketOptions, TlsAccept, TlsSettings};
+use pingora::listeners::{ServerAddress, TcpSocketOptions, TlsAccept, TlsSettings};
+use pingora::services::background::background_service;
 use pingora::services::Service;
 use pingora::tls::ext::ssl_add_chain_cert;
 use pingora::tls::{
@@ -28,26 +32,49 @@ use pingora::tls::{
     x509::X509,
 };
 use pingora::utils::CertKey;
-use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::de::{Deserialize, Deserializer, MapAccess, Unexpected, Visitor};
 use std::collections::HashMap;
-use std::fs::read;
+use std::fs::{create_dir_all, read, Permissions};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::acme::{
+    ensure_certificate, select_alpn, AcmeChallenges, AcmeService, AcmeSettings, ChallengeType,
+    ACME_ERR, ACME_TLS_ALPN,
+};
+use crate::app::StartupApp;
+use crate::certificates::{CertificateReloader, Certificates};
+use crate::client_auth::ClientAuth;
+use crate::health::HealthEndpoints;
+use crate::listener::{add_endpoints, bind_early, ListenerService};
+use crate::ocsp::OcspFetcher;
+use crate::privileges::Privileges;
+use crate::proxy_protocol::IpNetwork;
 use crate::redirector::create_redirector;
+use crate::systemd::{
+    expand_listen, inherited_listen, notify_enabled, notify_ready, SystemdNotifier,
+};
+use crate::tls_policy::{AcceptorPolicy, TlsPolicy};
+use crate::ServerApp;
 
 pub(crate) const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 
 /// Run a web server
 #[derive(Debug, Default, Parser)]
 pub struct StartupOpt {
-    /// Address and port to listen on, e.g. "127.0.0.1:8080". This command line flag can be
-    /// specified multiple times.
+    /// Address and port to listen on, e.g. "127.0.0.1:8080", or Unix socket path, e.g.
+    /// "unix:/run/pandora.sock". This command line flag can be specified multiple times.
     #[clap(short, long, value_parser = clap::value_parser!(String))]
     pub listen: Option<Vec<ListenAddr>>,
     /// Use this flag to make the server run in the background.
     #[clap(short, long)]
     pub daemon: bool,
+    /// Take over listening sockets from a running server instead of binding them. The running
+    /// server should be sent the SIGQUIT signal once this process is started.
+    #[clap(short, long)]
+    pub upgrade: bool,
     /// Test the configuration and exit. This is useful to validate the configuration before
     /// restarting the process.
     #[clap(short, long)]
@@ -55,14 +82,51 @@ pub struct StartupOpt {
     /// The path to the configuration file. This command line flag can be specified multiple times.
     #[clap(short, long)]
     pub conf: Option<Vec<String>>,
+    /// Overrides a configuration setting after the configuration files are loaded, e.g.
+    /// "vhosts.localhost.root=/srv". Put path segments containing dots in double quotes. This
+    /// command line flag can be specified multiple times.
+    #[clap(long)]
+    pub set: Option<Vec<String>>,
+}
+
+/// Settings of the built-in health endpoints
+///
+/// These are answered before the handler gets to see the request.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct HealthConf {
+    /// Path answering liveness probes, e.g. `/healthz`
+    ///
+    /// Requests to this path receive a `200 OK` response as long as the server is running.
+    pub liveness_path: Option<String>,
+
+    /// Path answering readiness probes, e.g. `/readyz`
+    ///
+    /// Requests to this path receive a `200 OK` response if no component is reported unhealthy,
+    /// `503 Service Unavailable` otherwise. The problems are logged.
+    pub readiness_path: Option<String>,
+
+    /// Names of the listeners to answer health probes on, all listeners if empty
+    pub listeners: OneOrMany<String>,
+
+    /// If `true`, readiness responses list the problems reported instead of a generic message
+    pub expose_problems: bool,
 }
 
 /// Address for the server to listen on
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ListenAddr {
-    /// IP address and port combination, e.g. `127.0.0.1:8080` or `[::1]:8080`
+    /// IP address and port combination, e.g. `127.0.0.1:8080` or `[::1]:8080`, alternatively
+    /// a Unix socket path with `unix:` prefix, e.g. `unix:/run/pandora.sock`, or sockets passed
+    /// on by systemd: `systemd` for all of them or `systemd:<name>` for those with the given name
     pub addr: String,
 
+    /// Name of the listener, e.g. `admin`
+    ///
+    /// Handlers can use the name to process requests differently depending on the listener they
+    /// arrived on, e.g. virtual hosts can be restricted to particular listeners. Multiple
+    /// addresses can share the same name.
+    pub name: Option<String>,
+
     /// If `true`, TLS will be enabled for this address.
     ///
     /// This required TLS configuration to be present.
@@ -73,21 +137,103 @@ pub struct ListenAddr {
     /// If set, the IPV6_V6ONLY flag will be set accordingly for the socket. Otherwise the system
     /// default will be used.
     pub ipv6_only: Option<bool>,
+
+    /// If `true`, HTTP/2 will be offered to clients via ALPN.
+    ///
+    /// Without TLS, cleartext HTTP/2 connections with prior knowledge will be accepted.
+    pub http2: bool,
+
+    /// File permissions of a Unix socket, e.g. `0o660`
+    ///
+    /// If not set, the socket will be accessible to all users (`0o666`).
+    pub mode: Option<u32>,
+
+    /// User name or numerical user ID that a Unix socket should be owned by
+    pub owner: Option<String>,
+
+    /// Group name or numerical group ID that a Unix socket should be owned by
+    pub group: Option<String>,
+
+    /// If `true`, connections are expected to start with a PROXY protocol header (version 1 or
+    /// 2) and the client address from this header will be used.
+    pub proxy_protocol: bool,
+
+    /// Networks allowed to send PROXY protocol headers, e.g. `10.0.0.0/8`
+    ///
+    /// Connections from other addresses are closed immediately. This setting is required if
+    /// `proxy_protocol` is enabled.
+    pub trusted_proxies: Vec<IpNetwork>,
+
+    /// If `true`, the SO_REUSEPORT flag will be set for the socket, allowing other processes to
+    /// listen on the same address. The kernel will distribute incoming connections among them.
+    pub reuse_port: bool,
+
+    /// If `true`, TCP keepalive probes will be sent on idle connections.
+    pub tcp_keepalive: bool,
+
+    /// Time in seconds a connection has to be idle before keepalive probes are sent
+    ///
+    /// This enables TCP keepalive. If not set, the system default will be used.
+    pub tcp_keepalive_idle: Option<u64>,
+
+    /// Time in seconds between keepalive probes
+    ///
+    /// This enables TCP keepalive. If not set, the system default will be used.
+    pub tcp_keepalive_interval: Option<u64>,
+
+    /// Number of unanswered keepalive probes before the connection is closed
+    ///
+    /// This enables TCP keepalive. If not set, the system default will be used.
+    pub tcp_keepalive_count: Option<u32>,
+
+    /// If `true`, the TCP_NODELAY flag will be set for accepted connections, disabling Nagle’s
+    /// algorithm.
+    pub tcp_nodelay: bool,
+
+    /// Maximal length of the queue of connections waiting to be accepted, 65535 by default
+    ///
+    /// The system might limit this further, e.g. via the `net.core.somaxconn` setting on Linux.
+    pub backlog: Option<u32>,
 }
 
 impl ListenAddr {
-    pub(crate) fn to_socket_options(&self) -> Option<TcpSocketOptions> {
+    /// Returns the socket path if this is a Unix socket address
+    pub(crate) fn unix_path(&self) -> Option<&str> {
+        self.addr.strip_prefix("unix:")
+    }
+
+    /// Returns the file permissions to be applied to a Unix socket
+    pub(crate) fn socket_mode(&self) -> u32 {
+        self.mode.unwrap_or(0o666)
+    }
+
+    fn to_socket_options(&self) -> Option<TcpSocketOptions> {
         self.ipv6_only
             .map(|ipv6_only| TcpSocketOptions { ipv6_only })
     }
+
+    /// Checks whether connections have to be relayed to Pingora’s internal listeners
+    pub(crate) fn is_relayed(&self) -> bool {
+        self.proxy_protocol || (self.http2 && !self.tls)
+    }
+
+    pub(crate) fn to_server_address(&self) -> ServerAddress {
+        if let Some(path) = self.unix_path() {
+            ServerAddress::Uds(
+                path.to_owned(),
+                Some(Permissions::from_mode(self.socket_mode())),
+            )
+        } else {
+            ServerAddress::Tcp(self.addr.clone(), self.to_socket_options())
+        }
+    }
 }
 
 impl From<String> for ListenAddr {
     fn from(value: String) -> Self {
         Self {
             addr: value,
-            tls: false,
-            ipv6_only: None,
+            ..Default::default()
         }
     }
 }
@@ -140,12 +286,40 @@ impl<'de> Deserialize<'de> for ListenAddr {
                 use serde::de::Error as _;
 
                 const ADDR_FIELD: &str = "addr";
+                const NAME_FIELD: &str = "name";
                 const IPV6_ONLY_FIELD: &str = "ipv6_only";
                 const TLS_FIELD: &str = "tls";
+                const HTTP2_FIELD: &str = "http2";
+                const MODE_FIELD: &str = "mode";
+                const OWNER_FIELD: &str = "owner";
+                const GROUP_FIELD: &str = "group";
+                const PROXY_PROTOCOL_FIELD: &str = "proxy_protocol";
+                const TRUSTED_PROXIES_FIELD: &str = "trusted_proxies";
+                const REUSE_PORT_FIELD: &str = "reuse_port";
+                const TCP_KEEPALIVE_FIELD: &str = "tcp_keepalive";
+                const TCP_KEEPALIVE_IDLE_FIELD: &str = "tcp_keepalive_idle";
+                const TCP_KEEPALIVE_INTERVAL_FIELD: &str = "tcp_keepalive_interval";
+                const TCP_KEEPALIVE_COUNT_FIELD: &str = "tcp_keepalive_count";
+                const TCP_NODELAY_FIELD: &str = "tcp_nodelay";
+                const BACKLOG_FIELD: &str = "backlog";
 
                 let mut addr = None;
+                let mut name = None;
                 let mut tls = None;
                 let mut ipv6_only = None;
+                let mut http2 = None;
+                let mut mode = None;
+                let mut owner = None;
+                let mut group = None;
+                let mut proxy_protocol = None;
+                let mut trusted_proxies = None;
+                let mut reuse_port = None;
+                let mut tcp_keepalive = None;
+                let mut tcp_keepalive_idle = None;
+                let mut tcp_keepalive_interval = None;
+                let mut tcp_keepalive_count = None;
+                let mut tcp_nodelay = None;
+                let mut backlog = None;
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
                         ADDR_FIELD => {
@@ -154,6 +328,12 @@ impl<'de> Deserialize<'de> for ListenAddr {
                             }
                             addr = Some(map.next_value()?);
                         }
+                        NAME_FIELD => {
+                            if name.is_some() {
+                                return Err(A::Error::duplicate_field(NAME_FIELD));
+                            }
+                            name = Some(map.next_value()?);
+                        }
                         IPV6_ONLY_FIELD => {
                             if ipv6_only.is_some() {
                                 return Err(A::Error::duplicate_field(IPV6_ONLY_FIELD));
@@ -166,10 +346,116 @@ impl<'de> Deserialize<'de> for ListenAddr {
                             }
                             tls = Some(map.next_value()?);
                         }
+                        HTTP2_FIELD => {
+                            if http2.is_some() {
+                                return Err(A::Error::duplicate_field(HTTP2_FIELD));
+                            }
+                            http2 = Some(map.next_value()?);
+                        }
+                        MODE_FIELD => {
+                            if mode.is_some() {
+                                return Err(A::Error::duplicate_field(MODE_FIELD));
+                            }
+                            let value: String = map.next_value()?;
+                            let value = value.strip_prefix("0o").unwrap_or(&value);
+                            mode = Some(u32::from_str_radix(value, 8).map_err(|_| {
+                                A::Error::invalid_value(
+                                    Unexpected::Str(value),
+                                    &"octal permissions like 0o660",
+                                )
+                            })?);
+                        }
+                        OWNER_FIELD => {
+                            if owner.is_some() {
+                                return Err(A::Error::duplicate_field(OWNER_FIELD));
+                            }
+                            owner = Some(map.next_value()?);
+                        }
+                        GROUP_FIELD => {
+                            if group.is_some() {
+                                return Err(A::Error::duplicate_field(GROUP_FIELD));
+                            }
+                            group = Some(map.next_value()?);
+                        }
+                        PROXY_PROTOCOL_FIELD => {
+                            if proxy_protocol.is_some() {
+                                return Err(A::Error::duplicate_field(PROXY_PROTOCOL_FIELD));
+                            }
+                            proxy_protocol = Some(map.next_value()?);
+                        }
+                        TRUSTED_PROXIES_FIELD => {
+                            if trusted_proxies.is_some() {
+                                return Err(A::Error::duplicate_field(TRUSTED_PROXIES_FIELD));
+                            }
+                            let value: OneOrMany<IpNetwork> = map.next_value()?;
+                            trusted_proxies = Some(value.into());
+                        }
+                        REUSE_PORT_FIELD => {
+                            if reuse_port.is_some() {
+                                return Err(A::Error::duplicate_field(REUSE_PORT_FIELD));
+                            }
+                            reuse_port = Some(map.next_value()?);
+                        }
+                        TCP_KEEPALIVE_FIELD => {
+                            if tcp_keepalive.is_some() {
+                                return Err(A::Error::duplicate_field(TCP_KEEPALIVE_FIELD));
+                            }
+                            tcp_keepalive = Some(map.next_value()?);
+                        }
+                        TCP_KEEPALIVE_IDLE_FIELD => {
+                            if tcp_keepalive_idle.is_some() {
+                                return Err(A::Error::duplicate_field(TCP_KEEPALIVE_IDLE_FIELD));
+                            }
+                            tcp_keepalive_idle = Some(map.next_value()?);
+                        }
+                        TCP_KEEPALIVE_INTERVAL_FIELD => {
+                            if tcp_keepalive_interval.is_some() {
+                                return Err(A::Error::duplicate_field(
+                                    TCP_KEEPALIVE_INTERVAL_FIELD,
+                                ));
+                            }
+                            tcp_keepalive_interval = Some(map.next_value()?);
+                        }
+                        TCP_KEEPALIVE_COUNT_FIELD => {
+                            if tcp_keepalive_count.is_some() {
+                                return Err(A::Error::duplicate_field(TCP_KEEPALIVE_COUNT_FIELD));
+                            }
+                            tcp_keepalive_count = Some(map.next_value()?);
+                        }
+                        TCP_NODELAY_FIELD => {
+                            if tcp_nodelay.is_some() {
+                                return Err(A::Error::duplicate_field(TCP_NODELAY_FIELD));
+                            }
+                            tcp_nodelay = Some(map.next_value()?);
+                        }
+                        BACKLOG_FIELD => {
+                            if backlog.is_some() {
+                                return Err(A::Error::duplicate_field(BACKLOG_FIELD));
+                            }
+                            backlog = Some(map.next_value()?);
+                        }
                         other => {
                             return Err(A::Error::unknown_field(
                                 other,
-                                &[ADDR_FIELD, IPV6_ONLY_FIELD, TLS_FIELD],
+                                &[
+                                    ADDR_FIELD,
+                                    NAME_FIELD,
+                                    IPV6_ONLY_FIELD,
+                                    TLS_FIELD,
+                                    HTTP2_FIELD,
+                                    MODE_FIELD,
+                                    OWNER_FIELD,
+                                    GROUP_FIELD,
+                                    PROXY_PROTOCOL_FIELD,
+                                    TRUSTED_PROXIES_FIELD,
+                                    REUSE_PORT_FIELD,
+                                    TCP_KEEPALIVE_FIELD,
+                                    TCP_KEEPALIVE_IDLE_FIELD,
+                                    TCP_KEEPALIVE_INTERVAL_FIELD,
+                                    TCP_KEEPALIVE_COUNT_FIELD,
+                                    TCP_NODELAY_FIELD,
+                                    BACKLOG_FIELD,
+                                ],
                             ))
                         }
                     }
@@ -177,10 +463,25 @@ impl<'de> Deserialize<'de> for ListenAddr {
 
                 if let Some(addr) = addr {
                     let tls = tls.unwrap_or(false);
+                    let http2 = http2.unwrap_or(false);
                     Ok(Self::Value {
                         addr,
+                        name,
                         ipv6_only,
                         tls,
+                        http2,
+                        mode,
+                        owner,
+                        group,
+                        proxy_protocol: proxy_protocol.unwrap_or(false),
+                        trusted_proxies: trusted_proxies.unwrap_or_default(),
+                        reuse_port: reuse_port.unwrap_or(false),
+                        tcp_keepalive: tcp_keepalive.unwrap_or(false),
+                        tcp_keepalive_idle,
+                        tcp_keepalive_interval,
+                        tcp_keepalive_count,
+                        tcp_nodelay: tcp_nodelay.unwrap_or(false),
+                        backlog,
                     })
                 } else {
                     Err(A::Error::missing_field(ADDR_FIELD))
@@ -193,6 +494,128 @@ impl<'de> Deserialize<'de> for ListenAddr {
     }
 }
 
+/// Client certificate verification mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// Do not request client certificates
+    Disabled,
+    /// Request a client certificate but accept connections without one
+    Optional,
+    /// Reject connections without a valid client certificate
+    Required,
+}
+
+/// Client certificate verification settings
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct ClientAuthConf {
+    /// Path to the file containing the CA certificates that client certificates are verified
+    /// against
+    pub ca_path: Option<PathBuf>,
+
+    /// Client certificate verification mode
+    ///
+    /// If not set, client certificates will be required if `ca_path` is present.
+    pub mode: Option<ClientAuthMode>,
+}
+
+impl ClientAuthConf {
+    /// Fills in any missing settings from the defaults
+    fn inherit(self, defaults: &Self) -> Self {
+        Self {
+            ca_path: self.ca_path.or_else(|| defaults.ca_path.clone()),
+            mode: self.mode.or(defaults.mode),
+        }
+    }
+
+    fn into_client_auth(self) -> Result<Option<ClientAuth>, Box<Error>> {
+        let required = match self.mode {
+            Some(ClientAuthMode::Disabled) => return Ok(None),
+            Some(ClientAuthMode::Optional) => false,
+            Some(ClientAuthMode::Required) => true,
+            None if self.ca_path.is_none() => return Ok(None),
+            None => true,
+        };
+
+        let ca_path = self.ca_path.ok_or_else(|| {
+            Error::explain(
+                TLS_CONF_ERR,
+                "`client_auth.ca_path` setting is required for client certificate verification",
+            )
+        })?;
+        let ca_certs = X509::stack_from_pem(&CertKeyConf::read_file(&ca_path)?)
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing CA certificates", err))?;
+        if ca_certs.is_empty() {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                format!("no CA certificates found in {}", ca_path.display()),
+            ));
+        }
+
+        Ok(Some(ClientAuth { required, ca_certs }))
+    }
+}
+
+/// TLS protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    Tls1_0,
+    /// TLS 1.1
+    Tls1_1,
+    /// TLS 1.2
+    Tls1_2,
+    /// TLS 1.3
+    Tls1_3,
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VersionVisitor;
+
+        impl Visitor<'_> for VersionVisitor {
+            type Value = TlsVersion;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("TLS version like \"1.2\"")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                match v.strip_prefix("TLSv").unwrap_or(v) {
+                    "1" | "1.0" => Ok(TlsVersion::Tls1_0),
+                    "1.1" => Ok(TlsVersion::Tls1_1),
+                    "1.2" => Ok(TlsVersion::Tls1_2),
+                    "1.3" => Ok(TlsVersion::Tls1_3),
+                    _ => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                }
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                // YAML will parse unquoted versions like 1.2 as numbers
+                self.visit_str(&v.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(VersionVisitor)
+    }
+}
+
 /// Certificate/key combination for a single server name
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct CertKeyConf {
@@ -201,12 +624,35 @@ pub struct CertKeyConf {
 
     /// Path to the private key file
     pub key_path: Option<PathBuf>,
+
+    /// Path to a DER-encoded OCSP response to staple
+    pub ocsp_path: Option<PathBuf>,
+
+    /// Client certificate verification settings
+    ///
+    /// For server names, missing settings are inherited from the default configuration.
+    pub client_auth: ClientAuthConf,
+
+    /// Minimal TLS version to accept, TLS 1.2 by default
+    ///
+    /// For server names, the default setting is inherited if not set.
+    pub min_version: Option<TlsVersion>,
+
+    /// Maximal TLS version to accept, TLS 1.3 by default
+    ///
+    /// For server names, the default setting is inherited if not set.
+    pub max_version: Option<TlsVersion>,
+
+    /// Allowed ciphers for TLS 1.2 and below, in the OpenSSL cipher list format
+    ///
+    /// For server names, the default setting is inherited if not set.
+    pub cipher_list: Option<String>,
 }
 
 impl CertKeyConf {
-    fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Box<Error>> {
+    pub(crate) fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Box<Error>> {
         let path = path.as_ref();
-        read(path).map_err(|err| {
+        read(chroot_path(path)).map_err(|err| {
             Error::because(
                 TLS_CONF_ERR,
                 format!("failed reading file {}", path.display()),
@@ -215,8 +661,36 @@ impl CertKeyConf {
         })
     }
 
-    fn into_certificate(self) -> Result<CertKey, Box<Error>> {
-        if let (Some(cert_path), Some(key_path)) = (self.cert_path, self.key_path) {
+    fn to_policy(&self, defaults: &Self) -> Result<TlsPolicy, Box<Error>> {
+        let policy = TlsPolicy {
+            min_version: self
+                .min_version
+                .or(defaults.min_version)
+                .unwrap_or(TlsVersion::Tls1_2),
+            max_version: self
+                .max_version
+                .or(defaults.max_version)
+                .unwrap_or(TlsVersion::Tls1_3),
+            cipher_list: self
+                .cipher_list
+                .clone()
+                .or_else(|| defaults.cipher_list.clone()),
+        };
+
+        if policy.min_version > policy.max_version {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                "`min_version` setting cannot be larger than `max_version`",
+            ));
+        }
+        policy
+            .validate()
+            .map_err(|err| Error::because(TLS_CONF_ERR, "invalid cipher settings", err))?;
+        Ok(policy)
+    }
+
+    pub(crate) fn to_certificate(&self) -> Result<CertKey, Box<Error>> {
+        if let (Some(cert_path), Some(key_path)) = (&self.cert_path, &self.key_path) {
             const END_MARKER: &[u8] = b"-----END CERTIFICATE-----";
             let mut certs = Vec::new();
             let cert_data = Self::read_file(cert_path)?;
@@ -247,6 +721,18 @@ impl CertKeyConf {
             let key = PKey::private_key_from_pem(&Self::read_file(key_path)?)
                 .map_err(|err| Error::because(TLS_CONF_ERR, "failed parsing private key", err))?;
 
+            // A mismatch would only be noticed on first use, e.g. after a partial update of files
+            let matches = certs[0]
+                .public_key()
+                .map(|public_key| public_key.public_eq(&key))
+                .unwrap_or(false);
+            if !matches {
+                return Err(Error::explain(
+                    TLS_CONF_ERR,
+                    "private key doesn't match the certificate",
+                ));
+            }
+
             Ok(CertKey::new(certs, key))
         } else {
             Err(Error::explain(
@@ -276,18 +762,132 @@ pub struct TlsRedirectorConf {
     /// If the requested name is not found in the list or the request didn’t contain a server name,
     /// the default redirect target will be used.
     pub redirect_by_name: HashMap<String, String>,
+
+    /// If `true`, requests will be redirected to the host name they were sent to
+    ///
+    /// Server names listed in `redirect_by_name` still take precedence. The default redirect
+    /// target is only used for requests without a valid host name, `redirect_to` setting is
+    /// optional then.
+    pub keep_host: bool,
+
+    /// HTTPS port to redirect to if `keep_host` is enabled, 443 by default
+    pub https_port: Option<u16>,
+
+    /// HTTP status code of the redirect response, 308 (Permanent Redirect) by default
+    ///
+    /// Allowed values are 301, 302, 303, 307 and 308.
+    pub status: Option<u16>,
+
+    /// Paths that should not be redirected but processed by the handler as usual
+    ///
+    /// Paths are matched exactly unless ending with `*`, e.g. `/.well-known/acme-challenge/*`
+    /// matches all paths starting with `/.well-known/acme-challenge/`.
+    pub exempt: OneOrMany<String>,
 }
 
 impl TlsRedirectorConf {
-    fn to_redirector(
+    fn to_redirector<SV>(
         &self,
         server_conf: &Arc<ServerConf>,
-    ) -> Result<Option<impl Service + 'static>, Box<Error>> {
+        app: StartupApp<SV>,
+        challenges: Option<Arc<AcmeChallenges>>,
+    ) -> Result<Option<impl Service + 'static>, Box<Error>>
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        <SV as ProxyHttp>::CTX: Send + Sync,
+    {
         if self.listen.is_empty() {
             Ok(None)
         } else {
-            create_redirector(self, server_conf).map(Some)
+            create_redirector(self, server_conf, app, challenges).map(Some)
+        }
+    }
+}
+
+/// Settings for obtaining certificates automatically via ACME
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct AcmeConf {
+    /// URL of the ACME server directory, e.g.
+    /// `https://acme-v02.api.letsencrypt.org/directory`
+    ///
+    /// ACME is disabled if this isn’t set.
+    pub directory: Option<String>,
+
+    /// Contact email address for the ACME account
+    pub contact: Option<String>,
+
+    /// Server names to obtain certificates for
+    pub names: OneOrMany<String>,
+
+    /// Directory to store the account key and the certificates in
+    pub storage_path: Option<PathBuf>,
+
+    /// Path to the file containing additional CA certificates to trust when connecting to the ACME
+    /// server
+    pub ca_path: Option<PathBuf>,
+
+    /// Number of days before expiration to renew certificates, 30 by default
+    pub renew_before_days: Option<u32>,
+}
+
+impl AcmeConf {
+    fn into_settings(self) -> Result<Option<AcmeSettings>, Box<Error>> {
+        let Some(directory) = self.directory else {
+            return Ok(None);
+        };
+
+        let storage_path = self.storage_path.ok_or_else(|| {
+            Error::explain(ACME_ERR, "`acme.storage_path` setting is required for ACME")
+        })?;
+        if self.names.is_empty() {
+            return Err(Error::explain(
+                ACME_ERR,
+                "`acme.names` setting should list at least one server name",
+            ));
         }
+        if let Some(name) = self.names.iter().find(|name| name.contains('*')) {
+            return Err(Error::explain(
+                ACME_ERR,
+                format!("wildcard name {name} cannot be validated without DNS challenges"),
+            ));
+        }
+        create_dir_all(&storage_path).map_err(|err| {
+            Error::because(
+                ACME_ERR,
+                format!("failed creating directory {}", storage_path.display()),
+                err,
+            )
+        })?;
+
+        let ca_certs = if let Some(ca_path) = self.ca_path {
+            let certs = X509::stack_from_pem(&CertKeyConf::read_file(&ca_path)?)
+                .map_err(|err| Error::because(ACME_ERR, "failed parsing CA certificates", err))?;
+            Some(Arc::new(certs.into_boxed_slice()))
+        } else {
+            None
+        };
+
+        let names = self
+            .names
+            .into_iter()
+            .map(|name| {
+                let conf = CertKeyConf {
+                    cert_path: Some(storage_path.join(format!("{name}.cert.pem"))),
+                    key_path: Some(storage_path.join(format!("{name}.key.pem"))),
+                    ..Default::default()
+                };
+                (name, conf)
+            })
+            .collect();
+
+        Ok(Some(AcmeSettings {
+            directory,
+            contact: self.contact,
+            ca_certs,
+            account_key_path: storage_path.join("account.key.pem"),
+            names,
+            renew_before_days: self.renew_before_days.unwrap_or(30),
+        }))
     }
 }
 
@@ -299,50 +899,273 @@ pub struct TlsConf {
     pub default: CertKeyConf,
 
     /// Certificate/key combinations for particular server names
+    ///
+    /// Names like `*.example.com` match any subdomain one level below `example.com`. Server
+    /// names without an entry here are matched against the certificates’ subject alternative
+    /// names before falling back to the default certificate.
     pub server_names: HashMap<String, CertKeyConf>,
 
+    /// Allowed cipher suites for TLS 1.3, in the OpenSSL cipher suites format
+    pub cipher_suites: Option<String>,
+
+    /// Key exchange groups to offer, e.g. `X25519:P-256`
+    pub groups: Option<String>,
+
+    /// Interval in seconds to check certificate and key files for modifications
+    ///
+    /// If not set, certificates are only reloaded on `SIGHUP`.
+    pub reload_interval: Option<u64>,
+
+    /// If `true`, OCSP responses are fetched from the responders listed in the certificates and
+    /// stapled
+    ///
+    /// This only applies to certificates without the `ocsp_path` setting.
+    pub fetch_ocsp: bool,
+
+    /// Value of the `Strict-Transport-Security` header to send along with responses on TLS
+    /// listeners, e.g. `max-age=31536000; includeSubDomains`
+    ///
+    /// The header is not added if the response already has one.
+    pub strict_transport_security: Option<String>,
+
+    /// Settings for obtaining certificates automatically via ACME
+    pub acme: AcmeConf,
+
     /// HTTP to HTTPS redirector settings
     pub redirector: TlsRedirectorConf,
 }
 
 impl TlsConf {
-    fn into_callbacks(self) -> Result<TlsAcceptCallbacks, Box<Error>> {
-        let mut certificates = HashMap::with_capacity(self.server_names.len() + 1);
-        for (name, conf) in self.server_names.into_iter() {
-            let cert = conf.into_certificate().map_err(|err| {
+    fn into_callbacks(mut self) -> Result<TlsAcceptCallbacks, Box<Error>> {
+        let acme = self.acme.into_settings()?;
+        if let Some(acme) = &acme {
+            for (name, acme_conf) in &acme.names {
+                let conf = self.server_names.entry(name.clone()).or_default();
+                if conf.cert_path.is_some() || conf.key_path.is_some() {
+                    return Err(Error::explain(
+                        ACME_ERR,
+                        format!(
+                            "server name {name} cannot have both ACME and certificate settings"
+                        ),
+                    ));
+                }
+                conf.cert_path = acme_conf.cert_path.clone();
+                conf.key_path = acme_conf.key_path.clone();
+                ensure_certificate(name, conf)?;
+            }
+
+            // Fall back to the first ACME certificate if no default certificate is configured
+            if self.default.cert_path.is_none() && self.default.key_path.is_none() {
+                if let Some((_, acme_conf)) = acme.names.first() {
+                    self.default.cert_path = acme_conf.cert_path.clone();
+                    self.default.key_path = acme_conf.key_path.clone();
+                }
+            }
+        }
+
+        let mut sources = HashMap::with_capacity(self.server_names.len() + 1);
+        let mut client_auth = HashMap::new();
+        let mut policies = HashMap::with_capacity(self.server_names.len() + 1);
+        for (name, mut conf) in self.server_names.into_iter() {
+            // Server names requested by clients are matched case-insensitively
+            let name = name.to_ascii_lowercase();
+            let policy = conf.to_policy(&self.default).map_err(|err| {
                 Error::because(
                     TLS_CONF_ERR,
-                    format!("failed setting up certificate/key for server name {name}"),
+                    format!("failed setting up TLS protocol settings for server name {name}"),
                     err,
                 )
             })?;
-            certificates.insert(name, cert);
+            policies.insert(name.clone(), Arc::new(policy));
+
+            let auth = std::mem::take(&mut conf.client_auth)
+                .inherit(&self.default.client_auth)
+                .into_client_auth()
+                .map_err(|err| {
+                    Error::because(
+                        TLS_CONF_ERR,
+                        format!("failed setting up client authentication for server name {name}"),
+                        err,
+                    )
+                })?;
+            if let Some(auth) = auth {
+                client_auth.insert(name.clone(), Arc::new(auth));
+            }
+
+            sources.insert(name, conf);
         }
-        let cert = self.default.into_certificate().map_err(|err| {
+
+        let mut default = self.default;
+        let policy = default.to_policy(&CertKeyConf::default()).map_err(|err| {
             Error::because(
                 TLS_CONF_ERR,
-                "failed setting up default certificate/key",
+                "failed setting up default TLS protocol settings",
                 err,
             )
         })?;
-        certificates.insert(String::new(), cert);
-        Ok(TlsAcceptCallbacks { certificates })
+        policies.insert(String::new(), Arc::new(policy));
+
+        // The protocol version is negotiated before the server name is known, so the acceptor has
+        // to allow all versions accepted for any server name.
+        let acceptor = AcceptorPolicy {
+            min_version: policies
+                .values()
+                .map(|policy| policy.min_version)
+                .min()
+                .unwrap_or(TlsVersion::Tls1_2),
+            max_version: policies
+                .values()
+                .map(|policy| policy.max_version)
+                .max()
+                .unwrap_or(TlsVersion::Tls1_3),
+            cipher_suites: self.cipher_suites,
+            groups: self.groups,
+        };
+        acceptor.validate().map_err(|err| {
+            Error::because(
+                TLS_CONF_ERR,
+                "invalid TLS cipher suite or key exchange settings",
+                err,
+            )
+        })?;
+
+        let auth = std::mem::take(&mut default.client_auth)
+            .into_client_auth()
+            .map_err(|err| {
+                Error::because(
+                    TLS_CONF_ERR,
+                    "failed setting up default client authentication",
+                    err,
+                )
+            })?;
+        if let Some(auth) = auth {
+            client_auth.insert(String::new(), Arc::new(auth));
+        }
+
+        let strict_transport_security = self
+            .strict_transport_security
+            .as_deref()
+            .map(HeaderValue::from_str)
+            .transpose()
+            .map_err(|err| {
+                Error::because(
+                    TLS_CONF_ERR,
+                    "tls.strict_transport_security setting is not a valid header value",
+                    err,
+                )
+            })?;
+
+        sources.insert(String::new(), default);
+        let certificates =
+            Certificates::new(sources, self.reload_interval.map(Duration::from_secs))?;
+
+        Ok(TlsAcceptCallbacks {
+            certificates: Arc::new(certificates),
+            client_auth,
+            policies,
+            acceptor: Arc::new(acceptor),
+            acme: acme.map(Arc::new),
+            challenges: Default::default(),
+            fetch_ocsp: self.fetch_ocsp,
+            strict_transport_security,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 struct TlsAcceptCallbacks {
-    certificates: HashMap<String, CertKey>,
+    certificates: Arc<Certificates>,
+    client_auth: HashMap<String, Arc<ClientAuth>>,
+    policies: HashMap<String, Arc<TlsPolicy>>,
+    acceptor: Arc<AcceptorPolicy>,
+    acme: Option<Arc<AcmeSettings>>,
+    challenges: Arc<AcmeChallenges>,
+    fetch_ocsp: bool,
+    strict_transport_security: Option<HeaderValue>,
+}
+
+impl TlsAcceptCallbacks {
+    fn to_settings(&self, http2: bool) -> Result<TlsSettings, Box<Error>> {
+        let mut tls_settings = TlsSettings::with_callbacks(Box::new(self.clone()))?;
+        self.acceptor
+            .apply(&mut tls_settings)
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed applying TLS settings", err))?;
+
+        // OpenSSL only sends OCSP responses if there is a status callback. The response itself is
+        // set in the certificate callback already.
+        tls_settings
+            .set_status_callback(|_| Ok(true))
+            .map_err(|err| Error::because(TLS_CONF_ERR, "failed enabling OCSP stapling", err))?;
+
+        if self.acme.is_some() {
+            let challenges = self.challenges.clone();
+            tls_settings.set_alpn_select_callback(move |ssl, client| {
+                select_alpn(&challenges, http2, ssl, client)
+            });
+        } else if http2 {
+            tls_settings.enable_h2();
+        }
+        Ok(tls_settings)
+    }
+
+    fn to_acme_service(&self, challenge_type: ChallengeType) -> Option<impl Service + 'static> {
+        Some(background_service(
+            "ACME client",
+            AcmeService {
+                settings: self.acme.clone()?,
+                certificates: self.certificates.clone(),
+                challenges: self.challenges.clone(),
+                challenge_type,
+            },
+        ))
+    }
+
+    fn to_ocsp_fetcher(&self) -> Option<impl Service + 'static> {
+        self.fetch_ocsp.then(|| {
+            background_service(
+                "OCSP response fetcher",
+                OcspFetcher::new(self.certificates.clone()),
+            )
+        })
+    }
+
+    fn to_reloader(&self) -> impl Service + 'static {
+        background_service(
+            "TLS certificate reloader",
+            CertificateReloader {
+                certificates: self.certificates.clone(),
+            },
+        )
+    }
 }
 
 #[async_trait]
 impl TlsAccept for TlsAcceptCallbacks {
     async fn certificate_callback(&self, ssl: &mut SslRef) {
-        let cert = ssl
+        if ssl.selected_alpn_protocol() == Some(ACME_TLS_ALPN) {
+            // Challenge certificate has been set already
+            return;
+        }
+
+        let name = ssl
             .servername(NameType::HOST_NAME)
-            .and_then(|name| self.certificates.get(name))
-            .or_else(|| self.certificates.get(""));
-        if let Some(cert) = cert {
+            .and_then(|name| self.certificates.resolve(name))
+            .unwrap_or("")
+            .to_owned();
+
+        if let Some(policy) = self.policies.get(&name) {
+            if !policy.allows_version(ssl) {
+                // Without a certificate the handshake will fail
+                debug!("Rejecting TLS connection to {name:?}, protocol version not allowed");
+                return;
+            }
+
+            if let Err(err) = policy.apply(ssl) {
+                error!("Failed applying TLS cipher settings: {err}");
+            }
+        }
+
+        if let Some(cert) = self.certificates.get().get(&name) {
             // Errors are unexpected here, these should only occur if a certificate has been set
             // already or private key and certificate don’t match. Ok to panic then.
             ssl_use_certificate(ssl, cert.leaf()).unwrap();
@@ -350,6 +1173,18 @@ impl TlsAccept for TlsAcceptCallbacks {
                 ssl_add_chain_cert(ssl, intermediate).unwrap();
             }
             ssl_use_private_key(ssl, cert.key()).unwrap();
+
+            if let Some(response) = self.certificates.ocsp_response(&name) {
+                if let Err(err) = ssl.set_ocsp_status(&response) {
+                    error!("Failed stapling OCSP response: {err}");
+                }
+            }
+        }
+
+        if let Some(client_auth) = self.client_auth.get(&name) {
+            if let Err(err) = client_auth.apply(ssl) {
+                error!("Failed setting up client certificate verification: {err}");
+            }
         }
     }
 }
@@ -363,21 +1198,82 @@ pub struct StartupConf {
     /// TLS configuration for the server
     pub tls: TlsConf,
 
+    /// Built-in health endpoints
+    pub health: HealthConf,
+
+    /// User name or ID to switch to after binding the listening sockets
+    pub user: Option<String>,
+
+    /// Group name or ID to switch to after binding the listening sockets, the primary group of
+    /// `user` by default
+    pub group: Option<String>,
+
+    /// Directory to change the root directory into after binding the listening sockets
+    pub chroot: Option<PathBuf>,
+
     /// Pingora’s default server configuration options
     #[pandora(flatten)]
     pub server: ServerConf,
 }
 
+fn create_service<SV>(
+    listen: &OneOrMany<ListenAddr>,
+    tls_callbacks: Option<&TlsAcceptCallbacks>,
+    server_conf: &Arc<ServerConf>,
+    app: SV,
+) -> Result<impl Service + 'static, Box<Error>>
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    <SV as ProxyHttp>::CTX: Send + Sync,
+{
+    let mut service = http_proxy_service(server_conf, app);
+    let mut relay_listeners = Vec::new();
+    for addr in listen {
+        let tls_settings = if addr.tls {
+            // This should be unreachable, callbacks are always created if TLS is used somewhere
+            let tls_callbacks = tls_callbacks.ok_or_else(|| {
+                Error::explain(TLS_CONF_ERR, "TLS configuration missing for TLS address")
+            })?;
+
+            Some(tls_callbacks.to_settings(addr.http2)?)
+        } else {
+            None
+        };
+
+        // Browsers only heed the header on HTTPS responses
+        let strict_transport_security = tls_callbacks
+            .filter(|_| addr.tls)
+            .and_then(|callbacks| callbacks.strict_transport_security.as_ref());
+        add_endpoints(
+            &mut service,
+            addr,
+            tls_settings,
+            strict_transport_security,
+            &mut relay_listeners,
+        )?;
+    }
+
+    ListenerService::new(service, listen, relay_listeners)
+}
+
 impl StartupConf {
     /// Sets up a server with the given configuration and command line options
     pub fn into_server<SV>(self, app: SV, opt: Option<StartupOpt>) -> Result<Server, Box<Error>>
     where
-        SV: ProxyHttp + Send + Sync + 'static,
+        SV: ServerApp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
     {
         let opt = opt.unwrap_or_default();
+        let app = Arc::new(app);
+        let health = HealthEndpoints::from_conf(self.health)?.map(Arc::new);
+        let privileges = Privileges::new(self.user.as_deref(), self.group.as_deref(), self.chroot)?;
 
-        let mut listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
+        let listen = opt.listen.map(|l| l.into()).unwrap_or(self.listen);
+        let mut listen = expand_listen(&listen)?;
+        if listen.is_empty() {
+            // Use sockets passed on by systemd if any
+            listen = inherited_listen();
+        }
         if listen.is_empty() {
             // Make certain we have a listening address
             listen.push("127.0.0.1:8080".into());
@@ -388,47 +1284,1578 @@ impl StartupConf {
             ServerOpt {
                 daemon: opt.daemon,
                 test: opt.test,
-                upgrade: false,
+                upgrade: opt.upgrade,
                 nocapture: false,
                 conf: None,
             },
             self.server,
         );
-        server.bootstrap();
 
-        let mut service = http_proxy_service(&server.configuration, app);
-        for addr in &listen {
-            if addr.tls {
-                continue;
+        // Set up TLS before bootstrapping, so that `--test` validates TLS settings as well
+        let mut redirector_listen = OneOrMany::default();
+        let tls_callbacks = if listen.iter().any(|addr| addr.tls) {
+            let mut tls = self.tls;
+            let redirector = std::mem::take(&mut tls.redirector);
+            redirector_listen = expand_listen(&redirector.listen)?;
+            let tls_callbacks = tls.into_callbacks()?;
+            server.add_service(tls_callbacks.to_reloader());
+            if let Some(fetcher) = tls_callbacks.to_ocsp_fetcher() {
+                server.add_service(fetcher);
             }
 
-            if let Some(socket_options) = addr.to_socket_options() {
-                service.add_tcp_with_settings(&addr.addr, socket_options);
+            let challenges = tls_callbacks
+                .acme
+                .is_some()
+                .then(|| tls_callbacks.challenges.clone());
+            if let Some(redirector) = redirector.to_redirector(
+                &server.configuration,
+                StartupApp::new(app.clone(), health.clone()),
+                challenges,
+            )? {
+                server.add_service(redirector);
+            }
+
+            // HTTP-01 challenges can only be answered if there is a plain HTTP listener
+            let challenge_type = if redirector.listen.is_empty() {
+                ChallengeType::TlsAlpn01
             } else {
-                service.add_tcp(&addr.addr);
+                ChallengeType::Http01
+            };
+            if let Some(acme) = tls_callbacks.to_acme_service(challenge_type) {
+                server.add_service(acme);
             }
+
+            Some(tls_callbacks)
+        } else {
+            None
+        };
+
+        // Services needed by the handler, e.g. upstream health checks
+        server.add_services(app.background_services(&server.configuration));
+
+        if notify_enabled() {
+            server.add_service(background_service(
+                "systemd notifier",
+                SystemdNotifier {
+                    acknowledge_reload: tls_callbacks.is_none(),
+                },
+            ));
         }
 
-        if listen.iter().any(|addr| addr.tls) {
-            if let Some(redirector) = self.tls.redirector.to_redirector(&server.configuration)? {
-                server.add_service(redirector);
+        server.bootstrap();
+
+        if let Some(privileges) = &privileges {
+            let network = tls_callbacks
+                .as_ref()
+                .is_some_and(|callbacks| callbacks.acme.is_some() || callbacks.fetch_ocsp);
+            privileges.validate(&server.configuration, opt.daemon, network)?;
+        }
+
+        // Bind sockets right away so that failures prevent startup rather than merely being
+        // logged. Sockets taken over during an upgrade are already bound, others are bound when the
+        // service starts then.
+        if !opt.upgrade {
+            bind_early(&listen)?;
+            bind_early(&redirector_listen)?;
+        }
+
+        server.add_service(create_service(
+            &listen,
+            tls_callbacks.as_ref(),
+            &server.configuration,
+            StartupApp::new(app, health),
+        )?);
+
+        if let Some(privileges) = privileges {
+            privileges.apply()?;
+        }
+
+        // Listening sockets are set up, connections are queued until the services start
+        notify_ready();
+
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::{Request, Version};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::Private;
+    use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslVerifyMode, SslVersion};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use pandora_module_utils::health::{set_healthy, set_unhealthy};
+    use pandora_module_utils::pingora::{HttpPeer, ResponseHeader, Session, SessionWrapper};
+    use pandora_module_utils::{RequestFilter, RequestFilterResult};
+    use pingora::server::{Fds, ShutdownWatch};
+    use pingora::tls::tokio_ssl::SslStream;
+    use std::pin::Pin;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    use crate::client_auth::client_certificate;
+    use crate::DefaultApp;
+
+    struct TestApp;
+
+    #[async_trait]
+    impl ProxyHttp for TestApp {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<bool, Box<Error>> {
+            let certificate = client_certificate(session);
+            let text = format!(
+                "{:?} {}",
+                session.req_header().version,
+                certificate
+                    .map(|cert| match cert.verify_error {
+                        Some(err) => format!("{} unverified: {err}", cert.subject),
+                        None => format!("{} {}", cert.subject, cert.subject_alt_names.join(",")),
+                    })
+                    .unwrap_or_else(|| "none".to_owned())
+            );
+            let mut header = ResponseHeader::build(200, Some(1))?;
+            header.append_header("Content-Length", text.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(text.into()).await?;
+            Ok(true)
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+    }
+
+    impl ServerApp for TestApp {}
+
+    fn generate_cert(
+        name: &str,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        san: Option<&str>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::generate_ed25519().unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("O", "Example").unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        if let Some(san) = san {
+            let san = SubjectAlternativeName::new()
+                .dns(san)
+                .build(&cert.x509v3_context(issuer.map(|(cert, _)| cert.as_ref()), None))
+                .unwrap();
+            cert.append_extension(san).unwrap();
+        }
+
+        if let Some((issuer, issuer_key)) = issuer {
+            cert.set_issuer_name(issuer.subject_name()).unwrap();
+            cert.sign(issuer_key, MessageDigest::null()).unwrap();
+        } else {
+            cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+            cert.set_issuer_name(&subject).unwrap();
+            cert.sign(&key, MessageDigest::null()).unwrap();
+        }
+        (cert.build(), key)
+    }
+
+    fn write_file(test: &str, name: &str, data: &[u8]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("startup-module-{test}-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn tls_conf(test: &str) -> TlsConf {
+        let (cert, key) = generate_cert("localhost", None, None);
+        TlsConf {
+            default: CertKeyConf {
+                cert_path: Some(write_file(test, "cert.pem", &cert.to_pem().unwrap())),
+                key_path: Some(write_file(
+                    test,
+                    "key.pem",
+                    &key.private_key_to_pem_pkcs8().unwrap(),
+                )),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn start_server(listen: ListenAddr, tls: TlsConf) -> watch::Sender<bool> {
+        start_server_with(listen, &tls.into_callbacks().unwrap())
+    }
+
+    fn start_server_with(
+        listen: ListenAddr,
+        tls_callbacks: &TlsAcceptCallbacks,
+    ) -> watch::Sender<bool> {
+        let server_conf = Arc::new(ServerConf::default());
+        let mut service = create_service(
+            &vec![listen].into(),
+            Some(tls_callbacks),
+            &server_conf,
+            TestApp,
+        )
+        .unwrap();
+
+        let (shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+        shutdown
+    }
+
+    async fn connect(
+        addr: &str,
+        alpn: &[u8],
+        client_cert: Option<&(X509, PKey<Private>)>,
+    ) -> SslStream<TcpStream> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_alpn_protos(alpn).unwrap();
+        if let Some((cert, key)) = client_cert {
+            connector.set_certificate(cert).unwrap();
+            connector.set_private_key(key).unwrap();
+        }
+        connect_with(addr, connector, "localhost").await
+    }
+
+    async fn connect_with(
+        addr: &str,
+        mut connector: SslConnectorBuilder,
+        server_name: &str,
+    ) -> SslStream<TcpStream> {
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector
+            .build()
+            .configure()
+            .unwrap()
+            .into_ssl(server_name)
+            .unwrap();
+
+        let mut attempts = 0;
+        let tcp = loop {
+            match TcpStream::connect(addr).await {
+                Ok(tcp) => break tcp,
+                Err(err) => {
+                    attempts += 1;
+                    assert!(attempts < 50, "failed connecting to server: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
             }
+        };
+
+        let mut stream = SslStream::new(ssl, tcp).unwrap();
+        // With TLS 1.3 a rejected client certificate only becomes visible when reading data
+        let _ = Pin::new(&mut stream).connect().await;
+        stream
+    }
+
+    async fn http1_request(mut stream: SslStream<TcpStream>) -> Option<String> {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .ok()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.ok()?;
+        let response = String::from_utf8(response).ok()?;
+        let (_, body) = response.split_once("\r\n\r\n")?;
+        Some(body.to_owned())
+    }
+
+    #[test(tokio::test)]
+    async fn http2_tls() {
+        const ADDR: &str = "127.0.0.1:18443";
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                http2: true,
+                ..Default::default()
+            },
+            tls_conf("http2_tls"),
+        );
+
+        let stream = connect(ADDR, b"\x02h2\x08http/1.1", None).await;
+        assert_eq!(
+            stream.ssl().selected_alpn_protocol(),
+            Some(b"h2".as_slice())
+        );
+
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("https://localhost/").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), Version::HTTP_2);
+
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(chunk) = body.data().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(text, b"HTTP/2.0 none");
+    }
+
+    #[test(tokio::test)]
+    async fn http2_disabled() {
+        const ADDR: &str = "127.0.0.1:18444";
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls_conf("http2_disabled"),
+        );
+
+        let stream = connect(ADDR, b"\x02h2\x08http/1.1", None).await;
+        assert_ne!(
+            stream.ssl().selected_alpn_protocol(),
+            Some(b"h2".as_slice())
+        );
+
+        // HTTP/2 handshake should fail, the server expects HTTP/1.1
+        let result = async {
+            let (mut client, connection) = h2::client::handshake(stream).await?;
+            tokio::spawn(connection);
+            let request = Request::get("https://localhost/").body(()).unwrap();
+            let (response, _) = client.send_request(request, true)?;
+            response.await
+        }
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn http2_cleartext() {
+        const ADDR: &str = "127.0.0.1:18445";
+        let server_conf = Arc::new(ServerConf::default());
+        let listen = ListenAddr {
+            addr: ADDR.to_owned(),
+            http2: true,
+            ..Default::default()
+        };
+        let mut service =
+            create_service(&vec![listen].into(), None, &server_conf, TestApp).unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
 
-            let tls_callbacks = self.tls.into_callbacks()?;
-            for addr in &listen {
-                if !addr.tls {
-                    continue;
+        let mut attempts = 0;
+        let stream = loop {
+            match TcpStream::connect(ADDR).await {
+                Ok(stream) => break stream,
+                Err(err) => {
+                    attempts += 1;
+                    assert!(attempts < 50, "failed connecting to server: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
                 }
+            }
+        };
+
+        // HTTP/2 with prior knowledge
+        let (mut client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = Request::get("http://localhost/").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), Version::HTTP_2);
+
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(chunk) = body.data().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(text, b"HTTP/2.0 none");
+
+        // HTTP/1.1 is still accepted on the same listener
+        let mut stream = TcpStream::connect(ADDR).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("\r\n\r\nHTTP/1.1 none"), "{response}");
+    }
+
+    #[test]
+    fn http2_cleartext_requires_tcp() {
+        let listen = ListenAddr {
+            addr: "unix:/tmp/startup-module-http2-cleartext.sock".to_owned(),
+            http2: true,
+            ..Default::default()
+        };
+        assert!(create_service(
+            &vec![listen].into(),
+            None,
+            &Arc::new(ServerConf::default()),
+            TestApp
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn client_auth() {
+        const ADDR: &str = "127.0.0.1:18446";
+
+        let ca = generate_cert("Test CA", None, None);
+        let client = generate_cert("client", Some((&ca.0, &ca.1)), Some("client.example.com"));
+        let untrusted_ca = generate_cert("Test CA", None, None);
+        let untrusted = generate_cert(
+            "client",
+            Some((&untrusted_ca.0, &untrusted_ca.1)),
+            Some("client.example.com"),
+        );
+
+        let mut tls = tls_conf("client_auth");
+        tls.default.client_auth.ca_path =
+            Some(write_file("client_auth", "ca.pem", &ca.0.to_pem().unwrap()));
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls,
+        );
+
+        let stream = connect(ADDR, b"\x08http/1.1", Some(&client)).await;
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 CN=client,O=Example client.example.com")
+        );
+
+        let stream = connect(ADDR, b"\x08http/1.1", None).await;
+        assert_eq!(http1_request(stream).await, None);
+
+        let stream = connect(ADDR, b"\x08http/1.1", Some(&untrusted)).await;
+        assert_eq!(http1_request(stream).await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn client_auth_server_name() {
+        const ADDR: &str = "127.0.0.1:18447";
+
+        let ca = generate_cert("Test CA", None, None);
+        let client = generate_cert("client", Some((&ca.0, &ca.1)), None);
+        let untrusted_ca = generate_cert("Other CA", None, None);
+        let untrusted = generate_cert("client", Some((&untrusted_ca.0, &untrusted_ca.1)), None);
+
+        let mut tls = tls_conf("client_auth_server_name");
+        tls.default.client_auth.ca_path = Some(write_file(
+            "client_auth_server_name",
+            "ca.pem",
+            &ca.0.to_pem().unwrap(),
+        ));
+        tls.server_names.insert(
+            "localhost".to_owned(),
+            CertKeyConf {
+                client_auth: ClientAuthConf {
+                    ca_path: None,
+                    mode: Some(ClientAuthMode::Optional),
+                },
+                ..tls.default.clone()
+            },
+        );
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls,
+        );
+
+        let stream = connect(ADDR, b"\x08http/1.1", Some(&client)).await;
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 CN=client,O=Example ")
+        );
+
+        let stream = connect(ADDR, b"\x08http/1.1", None).await;
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 none")
+        );
+
+        // Optional verification accepts invalid certificates but records the failure
+        let stream = connect(ADDR, b"\x08http/1.1", Some(&untrusted)).await;
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 CN=client,O=Example unverified: unable to get local issuer certificate")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn tls_version() {
+        const ADDR: &str = "127.0.0.1:18448";
+
+        let mut tls = tls_conf("tls_version");
+        tls.default.min_version = Some(TlsVersion::Tls1_3);
+        tls.server_names.insert(
+            "legacy.example.com".to_owned(),
+            CertKeyConf {
+                min_version: Some(TlsVersion::Tls1_2),
+                ..tls.default.clone()
+            },
+        );
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls,
+        );
+
+        let tls12_connector = || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector
+                .set_max_proto_version(Some(SslVersion::TLS1_2))
+                .unwrap();
+            connector
+        };
+
+        let stream = connect(ADDR, b"\x08http/1.1", None).await;
+        assert_eq!(stream.ssl().version2(), Some(SslVersion::TLS1_3));
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 none")
+        );
+
+        let stream = connect_with(ADDR, tls12_connector(), "localhost").await;
+        assert_eq!(http1_request(stream).await, None);
+
+        let stream = connect_with(ADDR, tls12_connector(), "legacy.example.com").await;
+        assert_eq!(stream.ssl().version2(), Some(SslVersion::TLS1_2));
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 none")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn tls_ciphers() {
+        const ADDR: &str = "127.0.0.1:18449";
+
+        let mut tls = tls_conf("tls_ciphers");
+        tls.cipher_suites = Some("TLS_CHACHA20_POLY1305_SHA256".to_owned());
+        tls.groups = Some("X25519".to_owned());
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls,
+        );
+
+        let stream = connect(ADDR, b"\x08http/1.1", None).await;
+        assert_eq!(
+            stream.ssl().current_cipher().map(|cipher| cipher.name()),
+            Some("TLS_CHACHA20_POLY1305_SHA256")
+        );
+        assert_eq!(
+            http1_request(stream).await.as_deref(),
+            Some("HTTP/1.1 none")
+        );
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector
+            .set_ciphersuites("TLS_AES_128_GCM_SHA256")
+            .unwrap();
+        let stream = connect_with(ADDR, connector, "localhost").await;
+        assert_eq!(http1_request(stream).await, None);
+    }
+
+    #[test]
+    fn tls_invalid_settings() {
+        let mut tls = tls_conf("tls_invalid_settings");
+        tls.default.min_version = Some(TlsVersion::Tls1_3);
+        tls.default.max_version = Some(TlsVersion::Tls1_2);
+        assert!(tls.into_callbacks().is_err());
+
+        let mut tls = tls_conf("tls_invalid_settings");
+        tls.server_names.insert(
+            "localhost".to_owned(),
+            CertKeyConf {
+                cipher_list: Some("NO-SUCH-CIPHER".to_owned()),
+                ..tls.default.clone()
+            },
+        );
+        assert!(tls.into_callbacks().is_err());
+
+        let mut tls = tls_conf("tls_invalid_settings");
+        tls.groups = Some("no-such-group".to_owned());
+        assert!(tls.into_callbacks().is_err());
+
+        let mut tls = tls_conf("tls_invalid_settings");
+        tls.strict_transport_security = Some("max-age=1\n".to_owned());
+        assert!(tls.into_callbacks().is_err());
+
+        assert!(tls_conf("tls_invalid_settings").into_callbacks().is_ok());
+    }
+
+    #[test(tokio::test)]
+    async fn certificate_reload() {
+        const ADDR: &str = "127.0.0.1:18450";
+
+        let tls = tls_conf("certificate_reload");
+        let cert_path = tls.default.cert_path.clone().unwrap();
+        let key_path = tls.default.key_path.clone().unwrap();
+        let callbacks = tls.into_callbacks().unwrap();
+        let _shutdown = start_server_with(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            &callbacks,
+        );
+
+        async fn server_name() -> String {
+            let stream = connect(ADDR, b"\x08http/1.1", None).await;
+            let cert = stream.ssl().peer_certificate().unwrap();
+            let name = cert
+                .subject_name()
+                .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+                .next()
+                .unwrap()
+                .data()
+                .as_utf8()
+                .unwrap()
+                .to_string();
+            name
+        }
+        assert_eq!(server_name().await, "localhost");
+        assert!(!callbacks.certificates.reload_if_modified().unwrap());
+
+        // Certificate not matching the key should be rejected
+        let (cert, key) = generate_cert("renewed", None, None);
+        std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        assert!(callbacks.certificates.reload().is_err());
+        assert_eq!(server_name().await, "localhost");
 
-                service.add_tls_with_settings(
-                    &addr.addr,
-                    addr.to_socket_options(),
-                    TlsSettings::with_callbacks(Box::new(tls_callbacks.clone()))?,
-                );
+        // Broken key should be rejected
+        std::fs::write(&key_path, b"garbage").unwrap();
+        assert!(callbacks.certificates.reload().is_err());
+        assert_eq!(server_name().await, "localhost");
+
+        // Files are still considered modified after a failed reload
+        assert!(callbacks.certificates.reload_if_modified().is_err());
+        assert!(callbacks.certificates.reload_if_modified().is_err());
+
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        assert!(callbacks.certificates.reload_if_modified().unwrap());
+        assert_eq!(server_name().await, "renewed");
+        assert!(!callbacks.certificates.reload_if_modified().unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn server_name_selection() {
+        const ADDR: &str = "127.0.0.1:18454";
+
+        fn cert_conf(name: &str, san: Option<&str>) -> CertKeyConf {
+            let (cert, key) = generate_cert(name, None, san);
+            CertKeyConf {
+                cert_path: Some(write_file(
+                    "server_name_selection",
+                    &format!("{name}.cert.pem"),
+                    &cert.to_pem().unwrap(),
+                )),
+                key_path: Some(write_file(
+                    "server_name_selection",
+                    &format!("{name}.key.pem"),
+                    &key.private_key_to_pem_pkcs8().unwrap(),
+                )),
+                ..Default::default()
             }
         }
-        server.add_service(service);
 
-        Ok(server)
+        let mut tls = tls_conf("server_name_selection");
+        tls.server_names
+            .insert("*.example.com".to_owned(), cert_conf("wildcard", None));
+        tls.server_names.insert(
+            "www.example.com".to_owned(),
+            cert_conf("explicit", Some("*.example.net")),
+        );
+        tls.server_names.insert(
+            "Example.org".to_owned(),
+            cert_conf("san", Some("www.example.net")),
+        );
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls,
+        );
+
+        async fn server_name(name: &str) -> String {
+            let connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            let stream = connect_with(ADDR, connector, name).await;
+            let cert = stream.ssl().peer_certificate().unwrap();
+            let name = cert
+                .subject_name()
+                .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+                .next()
+                .unwrap()
+                .data()
+                .as_utf8()
+                .unwrap()
+                .to_string();
+            name
+        }
+
+        // Explicit names take precedence over wildcards
+        assert_eq!(server_name("www.example.com").await, "explicit");
+        assert_eq!(server_name("mail.example.com").await, "wildcard");
+        assert_eq!(server_name("MAIL.Example.COM").await, "wildcard");
+        assert_eq!(server_name("example.org").await, "san");
+
+        // Wildcards only cover a single label
+        assert_eq!(server_name("example.com").await, "localhost");
+        assert_eq!(server_name("a.b.example.com").await, "localhost");
+
+        // Subject alternative names, explicit ones take precedence over wildcards
+        assert_eq!(server_name("www.example.net").await, "san");
+        assert_eq!(server_name("mail.example.net").await, "explicit");
+        assert_eq!(server_name("example.net").await, "localhost");
+    }
+
+    #[test(tokio::test)]
+    async fn acme_tls_alpn() {
+        const ADDR: &str = "127.0.0.1:18451";
+        const NAME: &str = "acme.example.com";
+
+        let ca = generate_cert("Test CA", None, None);
+        let mut tls = tls_conf("acme_tls_alpn");
+        tls.default.client_auth.ca_path = Some(write_file(
+            "acme_tls_alpn",
+            "ca.pem",
+            &ca.0.to_pem().unwrap(),
+        ));
+        tls.acme = AcmeConf {
+            directory: Some("https://127.0.0.1:1/directory".to_owned()),
+            names: vec![NAME.to_owned()].into(),
+            storage_path: Some(write_file("acme_tls_alpn", "dummy", b"").with_file_name("acme")),
+            ..Default::default()
+        };
+        let callbacks = tls.clone().into_callbacks().unwrap();
+        let _shutdown = start_server_with(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            &callbacks,
+        );
+
+        async fn acme_connect() -> SslStream<TcpStream> {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_alpn_protos(b"\x0aacme-tls/1").unwrap();
+            connect_with(ADDR, connector, NAME).await
+        }
+
+        // No challenge pending, connection should be rejected
+        let stream = acme_connect().await;
+        assert!(stream.ssl().peer_certificate().is_none());
+
+        {
+            let _guard = callbacks
+                .challenges
+                .add(ChallengeType::TlsAlpn01, NAME, "token", "token.thumbprint")
+                .unwrap();
+
+            // Challenge certificate is presented despite client certificates being required
+            let stream = acme_connect().await;
+            assert_eq!(
+                stream.ssl().selected_alpn_protocol(),
+                Some(b"acme-tls/1".as_slice())
+            );
+            let cert = stream.ssl().peer_certificate().unwrap();
+            let text = String::from_utf8(cert.to_text().unwrap()).unwrap();
+            assert!(text.contains("1.3.6.1.5.5.7.1.31"), "{text}");
+
+            // Regular connections still require a client certificate
+            let stream = connect(ADDR, b"\x08http/1.1", None).await;
+            assert_eq!(http1_request(stream).await, None);
+        }
+
+        let stream = acme_connect().await;
+        assert!(stream.ssl().peer_certificate().is_none());
+
+        // Certificate paths cannot be configured for ACME names
+        let mut conflict = tls.clone();
+        conflict.server_names.insert(
+            NAME.to_owned(),
+            CertKeyConf {
+                cert_path: Some("cert.pem".into()),
+                ..Default::default()
+            },
+        );
+        assert!(conflict.into_callbacks().is_err());
+
+        let mut no_storage = tls.clone();
+        no_storage.acme.storage_path = None;
+        assert!(no_storage.into_callbacks().is_err());
+
+        let mut wildcard = tls;
+        wildcard.acme.names = vec!["*.example.com".to_owned()].into();
+        assert!(wildcard.into_callbacks().is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn ocsp_stapling() {
+        use crate::ocsp::tests::{generate_cert, issuer_response};
+        use openssl::ssl::StatusType;
+
+        const ADDR: &str = "127.0.0.1:18456";
+
+        let ca = generate_cert("Test CA", None, None);
+        let (cert, key) = generate_cert("localhost", Some(&ca), None);
+        let response = issuer_response(&cert, &ca);
+
+        let mut chain = cert.to_pem().unwrap();
+        chain.extend(ca.0.to_pem().unwrap());
+        let tls = TlsConf {
+            default: CertKeyConf {
+                cert_path: Some(write_file("ocsp_stapling", "cert.pem", &chain)),
+                key_path: Some(write_file(
+                    "ocsp_stapling",
+                    "key.pem",
+                    &key.private_key_to_pem_pkcs8().unwrap(),
+                )),
+                ocsp_path: Some(write_file("ocsp_stapling", "ocsp.der", &response)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let _shutdown = start_server(
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            tls.clone(),
+        );
+
+        async fn ocsp_status(request: bool) -> Option<Vec<u8>> {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let mut ssl = connector
+                .build()
+                .configure()
+                .unwrap()
+                .into_ssl("localhost")
+                .unwrap();
+            if request {
+                ssl.set_status_type(StatusType::OCSP).unwrap();
+            }
+
+            let tcp = loop {
+                if let Ok(tcp) = TcpStream::connect(ADDR).await {
+                    break tcp;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            };
+            let mut stream = SslStream::new(ssl, tcp).unwrap();
+            Pin::new(&mut stream).connect().await.unwrap();
+            stream.ssl().ocsp_status().map(|status| status.to_vec())
+        }
+
+        assert_eq!(ocsp_status(true).await, Some(response));
+        assert_eq!(ocsp_status(false).await, None);
+
+        // Invalid OCSP response file should be rejected
+        let mut invalid = tls;
+        invalid.default.ocsp_path = Some(write_file("ocsp_stapling", "invalid.der", b"garbage"));
+        assert!(invalid.into_callbacks().is_err());
+    }
+
+    struct ClientAddrHandler;
+
+    #[async_trait]
+    impl RequestFilter for ClientAddrHandler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let text = session
+                .client_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            let mut header = ResponseHeader::build(200, Some(1))?;
+            header.append_header("Content-Length", text.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(text.into()).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    async fn proxied_request(addr: &str, header: &[u8]) -> Option<String> {
+        let mut attempts = 0;
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(err) => {
+                    attempts += 1;
+                    assert!(attempts < 50, "failed connecting to server: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        };
+
+        stream.write_all(header).await.ok()?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .ok()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.ok()?;
+        let response = String::from_utf8(response).ok()?;
+        let (_, body) = response.split_once("\r\n\r\n")?;
+        Some(body.to_owned())
+    }
+
+    #[test(tokio::test)]
+    async fn proxy_protocol() {
+        const ADDR: &str = "127.0.0.1:18457";
+        const UNTRUSTED_ADDR: &str = "127.0.0.1:18458";
+
+        let server_conf = Arc::new(ServerConf::default());
+        let listen = vec![
+            ListenAddr {
+                addr: ADDR.to_owned(),
+                proxy_protocol: true,
+                trusted_proxies: vec!["127.0.0.0/8".parse().unwrap()],
+                ..Default::default()
+            },
+            ListenAddr {
+                addr: UNTRUSTED_ADDR.to_owned(),
+                proxy_protocol: true,
+                trusted_proxies: vec!["192.0.2.0/24".parse().unwrap()],
+                ..Default::default()
+            },
+        ];
+        let mut service = create_service(
+            &listen.into(),
+            None,
+            &server_conf,
+            StartupApp::new(Arc::new(DefaultApp::new(ClientAddrHandler)), None),
+        )
+        .unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        assert_eq!(
+            proxied_request(ADDR, b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\n")
+                .await
+                .as_deref(),
+            Some("192.0.2.1:12345")
+        );
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend_from_slice(
+            &"2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend_from_slice(&[0x30, 0x39, 0x00, 0x50]);
+        assert_eq!(
+            proxied_request(ADDR, &v2).await.as_deref(),
+            Some("[2001:db8::1]:12345")
+        );
+
+        // Without a PROXY header the connection is closed
+        assert_eq!(proxied_request(ADDR, b"").await, None);
+
+        // Connections from untrusted addresses are closed
+        assert_eq!(
+            proxied_request(
+                UNTRUSTED_ADDR,
+                b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\n"
+            )
+            .await,
+            None
+        );
+
+        // Connecting to the internal listener directly doesn't bypass the checks
+        let internal = crate::relay::internal_addr_for(UNTRUSTED_ADDR).unwrap();
+        let mut stream = TcpStream::connect(internal).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(
+            response.starts_with(b"HTTP/1.1 403 "),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+    }
+
+    #[test]
+    fn proxy_protocol_requires_trusted_proxies() {
+        let listen = ListenAddr {
+            addr: "127.0.0.1:18483".to_owned(),
+            proxy_protocol: true,
+            ..Default::default()
+        };
+        assert!(create_service(
+            &vec![listen].into(),
+            None,
+            &Arc::new(ServerConf::default()),
+            TestApp
+        )
+        .is_err());
+    }
+
+    struct ListenerNameHandler;
+
+    #[async_trait]
+    impl RequestFilter for ListenerNameHandler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let text = session.listener_name().unwrap_or("none").to_owned();
+            let mut header = ResponseHeader::build(200, Some(1))?;
+            header.append_header("Content-Length", text.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(text.into()).await?;
+            Ok(RequestFilterResult::ResponseSent)
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn listener_names() {
+        const NAMED_ADDR: &str = "127.0.0.1:18462";
+        const WILDCARD_ADDR: &str = "0.0.0.0:18463";
+        const UNNAMED_ADDR: &str = "127.0.0.1:18464";
+        const PROXY_ADDR: &str = "127.0.0.1:18465";
+
+        let server_conf = Arc::new(ServerConf::default());
+        let listen = vec![
+            ListenAddr {
+                addr: NAMED_ADDR.to_owned(),
+                name: Some("named".to_owned()),
+                ..Default::default()
+            },
+            ListenAddr {
+                addr: WILDCARD_ADDR.to_owned(),
+                name: Some("wildcard".to_owned()),
+                ..Default::default()
+            },
+            UNNAMED_ADDR.into(),
+            ListenAddr {
+                addr: PROXY_ADDR.to_owned(),
+                name: Some("proxy".to_owned()),
+                proxy_protocol: true,
+                trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+                ..Default::default()
+            },
+        ];
+        let mut service = create_service(
+            &listen.into(),
+            None,
+            &server_conf,
+            DefaultApp::new(ListenerNameHandler),
+        )
+        .unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        assert_eq!(
+            proxied_request(NAMED_ADDR, b"").await.as_deref(),
+            Some("named")
+        );
+        assert_eq!(
+            proxied_request("127.0.0.1:18463", b"").await.as_deref(),
+            Some("wildcard")
+        );
+        assert_eq!(
+            proxied_request(UNNAMED_ADDR, b"").await.as_deref(),
+            Some("none")
+        );
+        assert_eq!(
+            proxied_request(PROXY_ADDR, b"PROXY UNKNOWN\r\n")
+                .await
+                .as_deref(),
+            Some("proxy")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn strict_transport_security() {
+        const TLS_ADDR: &str = "127.0.0.1:18470";
+        const PLAIN_ADDR: &str = "127.0.0.1:18471";
+
+        let tls = TlsConf {
+            strict_transport_security: Some("max-age=31536000".to_owned()),
+            ..tls_conf("strict_transport_security")
+        };
+        let server_conf = Arc::new(ServerConf::default());
+        let listen = vec![
+            ListenAddr {
+                addr: TLS_ADDR.to_owned(),
+                tls: true,
+                ..Default::default()
+            },
+            PLAIN_ADDR.into(),
+        ];
+        let mut service = create_service(
+            &listen.into(),
+            Some(&tls.into_callbacks().unwrap()),
+            &server_conf,
+            StartupApp::new(Arc::new(DefaultApp::new(ListenerNameHandler)), None),
+        )
+        .unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        let mut stream = connect(TLS_ADDR, b"\x08http/1.1", None).await;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response
+            .to_ascii_lowercase()
+            .contains("\r\nstrict-transport-security: max-age=31536000\r\n"));
+
+        let mut stream = TcpStream::connect(PLAIN_ADDR).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(!response
+            .to_ascii_lowercase()
+            .contains("strict-transport-security"));
+    }
+
+    async fn health_request(addr: &str, path: &str) -> Option<(u16, String)> {
+        let mut stream = TcpStream::connect(addr).await.ok()?;
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await
+            .ok()?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.ok()?;
+        let response = String::from_utf8(response).ok()?;
+        let (head, body) = response.split_once("\r\n\r\n")?;
+        let status = head.split(' ').nth(1)?.parse().ok()?;
+        Some((status, body.to_owned()))
+    }
+
+    #[test(tokio::test)]
+    async fn health_endpoints() {
+        const ADMIN_ADDR: &str = "127.0.0.1:18466";
+        const PUBLIC_ADDR: &str = "127.0.0.1:18467";
+
+        assert!(HealthEndpoints::from_conf(HealthConf::default())
+            .unwrap()
+            .is_none());
+        assert!(HealthEndpoints::from_conf(HealthConf {
+            liveness_path: Some("healthz".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+
+        let health = HealthEndpoints::from_conf(HealthConf {
+            liveness_path: Some("/healthz".to_owned()),
+            readiness_path: Some("/readyz".to_owned()),
+            listeners: vec!["admin".to_owned()].into(),
+            expose_problems: true,
+        })
+        .unwrap()
+        .map(Arc::new);
+
+        let server_conf = Arc::new(ServerConf::default());
+        let listen = vec![
+            ListenAddr {
+                addr: ADMIN_ADDR.to_owned(),
+                name: Some("admin".to_owned()),
+                ..Default::default()
+            },
+            PUBLIC_ADDR.into(),
+        ];
+        let mut service = create_service(
+            &listen.into(),
+            None,
+            &server_conf,
+            StartupApp::new(Arc::new(DefaultApp::new(ListenerNameHandler)), health),
+        )
+        .unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        // Wait for the server to come up
+        proxied_request(ADMIN_ADDR, b"").await;
+
+        assert_eq!(
+            health_request(ADMIN_ADDR, "/healthz").await,
+            Some((200, "OK\n".to_owned()))
+        );
+        assert_eq!(
+            health_request(ADMIN_ADDR, "/other").await,
+            Some((200, "admin".to_owned()))
+        );
+        assert_eq!(
+            health_request(PUBLIC_ADDR, "/healthz").await,
+            Some((200, "none".to_owned()))
+        );
+
+        set_unhealthy("health_endpoints test", "not ready yet");
+        let (status, body) = health_request(ADMIN_ADDR, "/readyz").await.unwrap();
+        assert_eq!(status, 503);
+        assert!(body.contains("health_endpoints test: not ready yet\n"));
+        assert_eq!(
+            health_request(ADMIN_ADDR, "/healthz").await,
+            Some((200, "OK\n".to_owned()))
+        );
+
+        set_healthy("health_endpoints test");
+        let (_, body) = health_request(ADMIN_ADDR, "/readyz").await.unwrap();
+        assert!(!body.contains("health_endpoints test"));
+    }
+
+    #[test(tokio::test)]
+    async fn bound_early() {
+        const TCP_ADDR: &str = "127.0.0.1:18468";
+        const PROXY_ADDR: &str = "127.0.0.1:18469";
+
+        let socket_path =
+            std::env::temp_dir().join(format!("startup-module-early-{}.sock", std::process::id()));
+        let unix_addr = format!("unix:{}", socket_path.display());
+
+        let server_conf = Arc::new(ServerConf::default());
+        let listen: OneOrMany<ListenAddr> = vec![
+            TCP_ADDR.into(),
+            ListenAddr {
+                addr: PROXY_ADDR.to_owned(),
+                proxy_protocol: true,
+                trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+                ..Default::default()
+            },
+            ListenAddr {
+                addr: unix_addr,
+                mode: Some(0o600),
+                ..Default::default()
+            },
+        ]
+        .into();
+        bind_early(&listen).unwrap();
+
+        // The addresses are taken now
+        assert!(std::net::TcpListener::bind(TCP_ADDR).is_err());
+        assert_eq!(
+            std::fs::metadata(&socket_path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+
+        let mut service = create_service(
+            &listen,
+            None,
+            &server_conf,
+            DefaultApp::new(ListenerNameHandler),
+        )
+        .unwrap();
+        let fds = Arc::new(tokio::sync::Mutex::new(Fds::new()));
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(Some(fds), watch).await });
+
+        assert_eq!(
+            proxied_request(TCP_ADDR, b"").await.as_deref(),
+            Some("none")
+        );
+        assert_eq!(
+            proxied_request(PROXY_ADDR, b"PROXY UNKNOWN\r\n")
+                .await
+                .as_deref(),
+            Some("none")
+        );
+
+        let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .ends_with("\r\n\r\nnone"));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[test]
+    fn unix_socket_bind_failure() {
+        let path = std::env::temp_dir()
+            .join(format!("startup-module-missing-{}", std::process::id()))
+            .join("server.sock");
+        let conf = StartupConf {
+            listen: vec![ListenAddr {
+                addr: format!("unix:{}", path.display()),
+                owner: Some(nix::unistd::Uid::current().to_string()),
+                ..Default::default()
+            }]
+            .into(),
+            ..Default::default()
+        };
+        match conf.into_server(TestApp, None) {
+            Ok(_) => panic!("binding Unix socket should fail"),
+            Err(err) => assert_eq!(err.etype, ErrorType::BindError),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unix_socket_default_mode() {
+        let socket_path =
+            std::env::temp_dir().join(format!("startup-module-mode-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+
+        let server_conf = Arc::new(ServerConf::default());
+        let listen: OneOrMany<ListenAddr> =
+            vec![format!("unix:{}", socket_path.display()).into()].into();
+        let mut service = create_service(
+            &listen,
+            None,
+            &server_conf,
+            DefaultApp::new(ListenerNameHandler),
+        )
+        .unwrap();
+        let (_shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        let mut stream = loop {
+            if let Ok(stream) = tokio::net::UnixStream::connect(&socket_path).await {
+                break stream;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+
+        // Socket is accessible to everybody regardless of umask
+        assert_eq!(
+            std::fs::metadata(&socket_path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o666
+        );
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .ends_with("\r\n\r\nnone"));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[test]
+    fn deserialize_tls_conf() {
+        use pandora_module_utils::FromYaml;
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                tls:
+                    min_version: 1.2
+                    cipher_list: ECDHE-ECDSA-AES256-GCM-SHA384
+                    server_names:
+                        example.com:
+                            min_version: "1.3"
+                            max_version: TLSv1.3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.tls.default.min_version, Some(TlsVersion::Tls1_2));
+        assert_eq!(
+            conf.tls.default.cipher_list.as_deref(),
+            Some("ECDHE-ECDSA-AES256-GCM-SHA384")
+        );
+        let server_name = conf.tls.server_names.get("example.com").unwrap();
+        assert_eq!(server_name.min_version, Some(TlsVersion::Tls1_3));
+        assert_eq!(server_name.max_version, Some(TlsVersion::Tls1_3));
+
+        assert!(StartupConf::from_yaml("tls:\n  min_version: 1.4").is_err());
+    }
+
+    #[test]
+    fn deserialize_privileges() {
+        use pandora_module_utils::FromYaml;
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                user: pandora
+                group: www-data
+                chroot: /srv/www
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.user.as_deref(), Some("pandora"));
+        assert_eq!(conf.group.as_deref(), Some("www-data"));
+        assert_eq!(conf.chroot, Some(PathBuf::from("/srv/www")));
+
+        // Pingora would only apply these when daemonizing, before the sockets are bound
+        assert_eq!(conf.server.user, None);
+        assert_eq!(conf.server.group, None);
+    }
+
+    #[test]
+    fn deserialize_listen_addr() {
+        use pandora_module_utils::FromYaml;
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen:
+                - 127.0.0.1:8080
+                - addr: "[::]:443"
+                  tls: true
+                  http2: true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.listen,
+            vec![
+                "127.0.0.1:8080".into(),
+                ListenAddr {
+                    addr: "[::]:443".to_owned(),
+                    tls: true,
+                    http2: true,
+                    ..Default::default()
+                }
+            ]
+            .into()
+        );
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen:
+                - addr: 0.0.0.0:80
+                  proxy_protocol: true
+                  trusted_proxies: [10.0.0.0/8, "2001:db8::/32", 192.0.2.1]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.listen,
+            vec![ListenAddr {
+                addr: "0.0.0.0:80".to_owned(),
+                proxy_protocol: true,
+                trusted_proxies: vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "2001:db8::/32".parse().unwrap(),
+                    "192.0.2.1/32".parse().unwrap(),
+                ],
+                ..Default::default()
+            }]
+            .into()
+        );
+
+        assert!(StartupConf::from_yaml(
+            "listen:\n- addr: 0.0.0.0:80\n  trusted_proxies: 10.0.0.0/33"
+        )
+        .is_err());
+
+        let conf = StartupConf::from_yaml(
+            r#"
+                listen:
+                - addr: 0.0.0.0:80
+                  name: public
+                  reuse_port: true
+                  tcp_keepalive: true
+                  tcp_keepalive_idle: 60
+                  tcp_keepalive_interval: 10
+                  tcp_keepalive_count: 5
+                  tcp_nodelay: true
+                  backlog: 1024
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.listen,
+            vec![ListenAddr {
+                addr: "0.0.0.0:80".to_owned(),
+                name: Some("public".to_owned()),
+                reuse_port: true,
+                tcp_keepalive: true,
+                tcp_keepalive_idle: Some(60),
+                tcp_keepalive_interval: Some(10),
+                tcp_keepalive_count: Some(5),
+                tcp_nodelay: true,
+                backlog: Some(1024),
+                ..Default::default()
+            }]
+            .into()
+        );
     }
 }
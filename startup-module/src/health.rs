@@ -0,0 +1,231 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Built-in liveness and readiness endpoints
+
+use http::{header, Method, StatusCode};
+use log::warn;
+use pandora_module_utils::health::unhealthy_components;
+use pandora_module_utils::pingora::{Error, ResponseHeader, Session};
+use pingora::ErrorType;
+
+use crate::configuration::HealthConf;
+use crate::listener::listener_name;
+
+/// Paths answering health probes along with the listeners to answer them on
+#[derive(Debug)]
+pub(crate) struct HealthEndpoints {
+    liveness_path: Option<String>,
+    readiness_path: Option<String>,
+    listeners: Vec<String>,
+    expose_problems: bool,
+}
+
+impl HealthEndpoints {
+    /// Validates the configuration, returns `None` if no health endpoints are configured.
+    pub(crate) fn from_conf(conf: HealthConf) -> Result<Option<Self>, Box<Error>> {
+        for path in [&conf.liveness_path, &conf.readiness_path]
+            .into_iter()
+            .flatten()
+        {
+            if !path.starts_with('/') {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("health endpoint path {path} has to start with /"),
+                ));
+            }
+        }
+
+        if conf.liveness_path.is_none() && conf.readiness_path.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(Self {
+                liveness_path: conf.liveness_path,
+                readiness_path: conf.readiness_path,
+                listeners: conf.listeners.into(),
+                expose_problems: conf.expose_problems,
+            }))
+        }
+    }
+
+    /// Answers health probes, returns `true` if a response has been sent.
+    pub(crate) async fn respond(&self, session: &mut Session) -> Result<bool, Box<Error>> {
+        let path = session.req_header().uri.path();
+        let readiness = if self.liveness_path.as_deref() == Some(path) {
+            false
+        } else if self.readiness_path.as_deref() == Some(path) {
+            true
+        } else {
+            return Ok(false);
+        };
+
+        if !self.listeners.is_empty() {
+            let name = session.server_addr().and_then(listener_name);
+            if !name.is_some_and(|name| self.listeners.contains(&name)) {
+                return Ok(false);
+            }
+        }
+
+        let problems = if readiness {
+            unhealthy_components()
+        } else {
+            Vec::new()
+        };
+        let (status, text) = if problems.is_empty() {
+            (StatusCode::OK, "OK\n".to_owned())
+        } else {
+            let problems = problems
+                .into_iter()
+                .map(|(component, problem)| format!("{component}: {problem}\n"))
+                .collect::<String>();
+            warn!("Answering readiness probe, server not ready:\n{problems}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                if self.expose_problems {
+                    problems
+                } else {
+                    "Service Unavailable\n".to_owned()
+                },
+            )
+        };
+
+        let mut header = ResponseHeader::build(status, Some(3))?;
+        header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+        header.append_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")?;
+        header.append_header(header::CACHE_CONTROL, "no-store")?;
+        session.write_response_header(Box::new(header)).await?;
+
+        if session.req_header().method != Method::HEAD {
+            session.write_response_body(text.into()).await?;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::health::{set_healthy, set_unhealthy};
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn endpoints(listeners: &[&str], expose_problems: bool) -> HealthEndpoints {
+        HealthEndpoints::from_conf(HealthConf {
+            liveness_path: Some("/healthz".to_owned()),
+            readiness_path: Some("/readyz".to_owned()),
+            listeners: listeners
+                .iter()
+                .map(|name| (*name).to_owned())
+                .collect::<Vec<_>>()
+                .into(),
+            expose_problems,
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Sends a request to the endpoints, returns status code, headers and body if answered.
+    async fn request(
+        endpoints: &HealthEndpoints,
+        method: &str,
+        path: &str,
+    ) -> Option<(u16, String, String)> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+
+        let mut session = Session::new_h1(Box::new(server));
+        assert!(session.read_request().await.unwrap());
+        if !endpoints.respond(&mut session).await.unwrap() {
+            return None;
+        }
+        drop(session);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        Some((status, head.to_ascii_lowercase(), body.to_owned()))
+    }
+
+    #[test]
+    fn invalid_conf() {
+        assert!(HealthEndpoints::from_conf(HealthConf::default())
+            .unwrap()
+            .is_none());
+        assert!(HealthEndpoints::from_conf(HealthConf {
+            readiness_path: Some("readyz".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn liveness() {
+        let endpoints = endpoints(&[], false);
+        set_unhealthy("health liveness test", "broken");
+
+        // Liveness doesn’t depend on reported problems
+        let (status, head, body) = request(&endpoints, "GET", "/healthz").await.unwrap();
+        assert_eq!(status, 200);
+        assert!(head.contains("\r\ncache-control: no-store"));
+        assert_eq!(body, "OK\n");
+
+        set_healthy("health liveness test");
+
+        assert_eq!(request(&endpoints, "GET", "/other").await, None);
+        assert_eq!(request(&endpoints, "GET", "/healthz/").await, None);
+    }
+
+    #[test(tokio::test)]
+    async fn readiness() {
+        let endpoints = endpoints(&[], false);
+        set_unhealthy("health readiness test", "not ready yet");
+
+        // Problems are logged but not exposed by default
+        let (status, _, body) = request(&endpoints, "GET", "/readyz").await.unwrap();
+        assert_eq!(status, 503);
+        assert_eq!(body, "Service Unavailable\n");
+
+        let endpoints = self::endpoints(&[], true);
+        let (status, _, body) = request(&endpoints, "GET", "/readyz").await.unwrap();
+        assert_eq!(status, 503);
+        assert!(body.contains("health readiness test: not ready yet\n"));
+
+        set_healthy("health readiness test");
+        let (_, _, body) = request(&endpoints, "GET", "/readyz").await.unwrap();
+        assert!(!body.contains("health readiness test"));
+    }
+
+    #[test(tokio::test)]
+    async fn head() {
+        let endpoints = endpoints(&[], false);
+        let (status, head, body) = request(&endpoints, "HEAD", "/healthz").await.unwrap();
+        assert_eq!(status, 200);
+        assert!(head.contains("\r\ncontent-length: 3"));
+        assert_eq!(body, "");
+    }
+
+    #[test(tokio::test)]
+    async fn listener_restriction() {
+        // Connection without a named listener
+        let endpoints = endpoints(&["admin"], false);
+        assert_eq!(request(&endpoints, "GET", "/healthz").await, None);
+        assert_eq!(request(&endpoints, "GET", "/readyz").await, None);
+    }
+}
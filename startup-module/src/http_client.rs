@@ -0,0 +1,107 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal HTTP client for requests made by the server itself (ACME, OCSP)
+
+use http::{header, HeaderMap, Method, StatusCode, Uri};
+use openssl::x509::X509;
+use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, RequestHeader};
+use pingora::connectors::http::Connector;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout for reading and writing
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Response to an HTTP request
+#[derive(Debug)]
+pub(crate) struct HttpResponse {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Sends an HTTP request, `body` being the content type and request body if any
+///
+/// `ca` are additional CA certificates to trust for HTTPS connections. Errors are reported with
+/// the given error type.
+pub(crate) async fn http_request(
+    connector: &Connector,
+    error_type: ErrorType,
+    method: Method,
+    url: &str,
+    body: Option<(&str, Vec<u8>)>,
+    ca: Option<Arc<Box<[X509]>>>,
+) -> Result<HttpResponse, Box<Error>> {
+    let uri = Uri::from_str(url)
+        .map_err(|err| Error::because(error_type.clone(), format!("invalid URL {url}"), err))?;
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => return Err(Error::explain(error_type, format!("unsupported URL {url}"))),
+    };
+    let (Some(host), Some(authority)) = (uri.host(), uri.authority()) else {
+        return Err(Error::explain(error_type, format!("unsupported URL {url}")));
+    };
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| {
+            Error::explain(error_type.clone(), format!("failed resolving host {host}"))
+        })?;
+
+    let mut peer = HttpPeer::new(addr, tls, host.to_owned());
+    peer.options.ca = ca;
+    let (mut session, _) = connector.get_http_session(&peer).await?;
+    session.set_read_timeout(REQUEST_TIMEOUT);
+    session.set_write_timeout(REQUEST_TIMEOUT);
+
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut request = RequestHeader::build(method, path.as_bytes(), None)?;
+    request.insert_header(header::HOST, authority.as_str())?;
+    request.insert_header(
+        header::USER_AGENT,
+        concat!("pandora-web-server/", env!("CARGO_PKG_VERSION")),
+    )?;
+    if let Some((content_type, body)) = &body {
+        request.insert_header(header::CONTENT_TYPE, *content_type)?;
+        request.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    }
+    session.write_request_header(Box::new(request)).await?;
+    if let Some((_, body)) = body {
+        session.write_request_body(body.into(), true).await?;
+    }
+    session.finish_request_body().await?;
+
+    session.read_response_header().await?;
+    let header = session
+        .response_header()
+        .ok_or_else(|| Error::explain(error_type, format!("missing response from {host}")))?;
+    let status = header.status;
+    let headers = header.headers.clone();
+
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_response_body().await? {
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
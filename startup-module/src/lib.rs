@@ -14,20 +14,41 @@
 
 #![doc = include_str!("../README.md")]
 
+mod acme;
+mod app;
+mod certificates;
+mod client_auth;
 mod configuration;
+mod health;
+mod http_client;
+mod listener;
+mod ocsp;
+mod privileges;
+mod proxy_protocol;
 mod redirector;
+mod relay;
+mod systemd;
+mod tls_policy;
 
 use async_trait::async_trait;
 pub use configuration::{
-    CertKeyConf, ListenAddr, StartupConf, StartupOpt, TlsConf, TlsRedirectorConf,
+    AcmeConf, CertKeyConf, ClientAuthConf, ClientAuthMode, HealthConf, ListenAddr, StartupConf,
+    StartupOpt, TlsConf, TlsRedirectorConf, TlsVersion,
 };
 use http::Extensions;
 use pandora_module_utils::pingora::{
-    Error, HttpPeer, ProxyHttp, ResponseHeader, Session, SessionWrapper,
+    ClientCertificate, Error, HttpPeer, ProxyHttp, ResponseHeader, ServerConf, Service, Session,
+    SessionWrapper, SocketAddr,
 };
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
 use pingora::ErrorType;
+pub use proxy_protocol::IpNetwork;
 use std::ops::{Deref, DerefMut};
+pub use systemd::inherit_systemd_sockets;
+
+use crate::client_auth::client_certificate;
+use crate::listener::{add_strict_transport_security, listener_name};
+use crate::relay::relayed_client_addr;
 
 /// A basic Pingora app implementation, to be passed to [`StartupConf::into_server`]
 ///
@@ -107,6 +128,37 @@ where
         }
     }
 
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
+        self.handler
+            .fail_to_connect(&mut session, peer, &mut ctx.handler, e)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        // Same as Pingora’s default: only retry on reused client connections if the retry buffer
+        // is still complete
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+
+        let mut session = SessionWrapperImpl::new(session, &self.handler, &mut ctx.extensions);
+        self.handler
+            .error_while_proxy(&mut session, peer, &mut ctx.handler, e, client_reused)
+    }
+
     fn upstream_response_filter(
         &self,
         session: &mut Session,
@@ -126,6 +178,25 @@ where
     }
 }
 
+/// A Pingora app that can be passed to [`StartupConf::into_server`]
+pub trait ServerApp: ProxyHttp {
+    /// Creates the services the app needs to run in the background, e.g. upstream health checks.
+    /// These are added to the server along with the app.
+    fn background_services(&self, _server_conf: &ServerConf) -> Vec<Box<dyn Service>> {
+        Vec::new()
+    }
+}
+
+impl<H> ServerApp for DefaultApp<H>
+where
+    H: RequestFilter + Sync,
+    H::CTX: Send,
+{
+    fn background_services(&self, server_conf: &ServerConf) -> Vec<Box<dyn Service>> {
+        self.handler.background_services(server_conf)
+    }
+}
+
 struct SessionWrapperImpl<'a, H> {
     inner: &'a mut Session,
     handler: &'a H,
@@ -137,12 +208,35 @@ impl<'a, H> SessionWrapperImpl<'a, H> {
     fn new(inner: &'a mut Session, handler: &'a H, extensions: &'a mut Extensions) -> Self
     where
         H: RequestFilter,
+        for<'b> &'b H: Send,
     {
-        Self {
+        if extensions.get::<ClientCertificate>().is_none() {
+            if let Some(certificate) = client_certificate(inner) {
+                extensions.insert(certificate);
+            }
+        }
+
+        if extensions.get::<SocketAddr>().is_none() {
+            if let Some(SocketAddr::Inet(addr)) = inner.client_addr() {
+                if let Some(client_addr) = relayed_client_addr(addr) {
+                    extensions.insert(SocketAddr::Inet(client_addr));
+                }
+            }
+        }
+
+        let mut session = Self {
             inner,
             handler,
             extensions,
+        };
+
+        if session.listener_name().is_none() {
+            if let Some(name) = session.inner.server_addr().and_then(listener_name) {
+                session.set_listener_name(name);
+            }
         }
+
+        session
     }
 }
 
@@ -164,6 +258,7 @@ where
         &mut self,
         mut resp: Box<ResponseHeader>,
     ) -> Result<(), Box<Error>> {
+        add_strict_transport_security(self, &mut resp)?;
         self.handler.response_filter(self, &mut resp, None);
 
         self.deref_mut().write_response_header(resp).await
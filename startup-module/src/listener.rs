@@ -0,0 +1,597 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wrapper service setting up listening sockets that Pingora cannot configure on its own
+
+use async_trait::async_trait;
+use http::{header, HeaderValue};
+use log::{debug, error};
+use nix::unistd::{Gid, Group, Uid, User};
+use pandora_module_utils::pingora::{
+    Error, ErrorType, ResponseHeader, Session, SocketAddr as PingoraSocketAddr,
+};
+use pandora_module_utils::OneOrMany;
+use pingora::listeners::{ServerAddress, TlsSettings};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::services::listening::Service as ListeningService;
+use pingora::services::Service;
+use socket2::{Domain, Socket, TcpKeepalive, Type};
+use std::fs::{remove_file, set_permissions, Permissions};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::os::unix::io::{IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::configuration::ListenAddr;
+use crate::relay::RelayListener;
+use crate::systemd::inherited_fd;
+
+/// Local address of a listener in a [`ListenerRegistry`]
+#[derive(Debug)]
+enum NamedAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Per-listener values, looked up by the local address of a connection
+struct ListenerRegistry<T>(Mutex<Vec<(NamedAddr, T)>>);
+
+impl<T: Clone> ListenerRegistry<T> {
+    const fn new() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+
+    /// Associates a value with the listener bound to the given address
+    fn register(&self, address: &ServerAddress, value: T) {
+        let addrs = match address {
+            ServerAddress::Tcp(addr, _) => match addr.to_socket_addrs() {
+                Ok(addrs) => addrs.map(NamedAddr::Tcp).collect(),
+                Err(err) => {
+                    error!("Failed resolving listen address {addr}: {err}");
+                    return;
+                }
+            },
+            ServerAddress::Uds(path, _) => vec![NamedAddr::Unix(path.into())],
+        };
+
+        let mut values = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        for addr in addrs {
+            values.push((addr, value.clone()));
+        }
+    }
+
+    /// Determines the value for the listener a connection with the given local address was
+    /// accepted by
+    ///
+    /// Listeners bound to an unspecified IP address like `0.0.0.0` only match by port, more
+    /// specific addresses take precedence.
+    fn get(&self, local: &PingoraSocketAddr) -> Option<T> {
+        let values = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        match local {
+            PingoraSocketAddr::Inet(local) => {
+                let local_ip = match local.ip() {
+                    IpAddr::V6(ip) => ip
+                        .to_ipv4_mapped()
+                        .map(IpAddr::V4)
+                        .unwrap_or(IpAddr::V6(ip)),
+                    ip => ip,
+                };
+                let find = |exact: bool| {
+                    values.iter().find_map(|(addr, value)| match addr {
+                        NamedAddr::Tcp(addr) if addr.port() == local.port() => {
+                            let matches = if exact {
+                                addr.ip() == local_ip
+                            } else {
+                                // IPv6 wildcard addresses might accept IPv4 connections as well
+                                addr.ip().is_unspecified() && (addr.is_ipv6() || local_ip.is_ipv4())
+                            };
+                            matches.then(|| value.clone())
+                        }
+                        _ => None,
+                    })
+                };
+                find(true).or_else(|| find(false))
+            }
+            PingoraSocketAddr::Unix(local) => {
+                let path = local.as_pathname()?;
+                values.iter().find_map(|(addr, value)| match addr {
+                    NamedAddr::Unix(addr) if addr == path => Some(value.clone()),
+                    _ => None,
+                })
+            }
+        }
+    }
+}
+
+/// Listener names by their local addresses
+static LISTENER_NAMES: ListenerRegistry<String> = ListenerRegistry::new();
+
+/// `Strict-Transport-Security` header values of TLS listeners by their local addresses
+static STRICT_TRANSPORT_SECURITY: ListenerRegistry<HeaderValue> = ListenerRegistry::new();
+
+/// Registers a name for the listener bound to the given address
+pub(crate) fn register_listener_name(address: &ServerAddress, name: &str) {
+    LISTENER_NAMES.register(address, name.to_owned());
+}
+
+/// Determines the name of the listener a connection with the given local address was accepted by
+pub(crate) fn listener_name(local: &PingoraSocketAddr) -> Option<String> {
+    LISTENER_NAMES.get(local)
+}
+
+/// Adds the `Strict-Transport-Security` header configured for the listener a connection was
+/// accepted by, unless the response has this header already
+pub(crate) fn add_strict_transport_security(
+    session: &Session,
+    response: &mut ResponseHeader,
+) -> Result<(), Box<Error>> {
+    if response
+        .headers
+        .contains_key(header::STRICT_TRANSPORT_SECURITY)
+    {
+        return Ok(());
+    }
+
+    if let Some(value) = session
+        .server_addr()
+        .and_then(|local| STRICT_TRANSPORT_SECURITY.get(local))
+    {
+        response.insert_header(header::STRICT_TRANSPORT_SECURITY, value)?;
+    }
+    Ok(())
+}
+
+/// Unix socket that needs to be bound before Pingora gets to it
+#[derive(Debug)]
+struct UnixSocket {
+    path: String,
+    mode: u32,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+}
+
+impl UnixSocket {
+    fn bind(&self) -> std::io::Result<OwnedFd> {
+        match remove_file(&self.path) {
+            Ok(()) => debug!("removed stale Unix socket {}", self.path),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let listener = UnixListener::bind(&self.path)?;
+        listener.set_nonblocking(true)?;
+        set_permissions(&self.path, Permissions::from_mode(self.mode))?;
+        chown(
+            &self.path,
+            self.owner.map(Uid::as_raw),
+            self.group.map(Gid::as_raw),
+        )?;
+        Ok(listener.into())
+    }
+}
+
+/// Listen backlog that Pingora uses for the sockets it binds itself
+const DEFAULT_BACKLOG: u32 = 65535;
+
+/// TCP socket settings, most of which Pingora cannot apply on its own
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TcpOptions {
+    pub(crate) ipv6_only: Option<bool>,
+    pub(crate) reuse_port: bool,
+    pub(crate) keepalive: Option<TcpKeepalive>,
+    pub(crate) nodelay: bool,
+    pub(crate) backlog: Option<u32>,
+}
+
+impl TcpOptions {
+    /// Extracts the TCP socket settings from a listen address
+    pub(crate) fn from_addr(addr: &ListenAddr) -> Self {
+        let keepalive = (addr.tcp_keepalive
+            || addr.tcp_keepalive_idle.is_some()
+            || addr.tcp_keepalive_interval.is_some()
+            || addr.tcp_keepalive_count.is_some())
+        .then(|| {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(idle) = addr.tcp_keepalive_idle {
+                keepalive = keepalive.with_time(Duration::from_secs(idle));
+            }
+            if let Some(interval) = addr.tcp_keepalive_interval {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval));
+            }
+            if let Some(count) = addr.tcp_keepalive_count {
+                keepalive = keepalive.with_retries(count);
+            }
+            keepalive
+        });
+
+        Self {
+            ipv6_only: addr.ipv6_only,
+            reuse_port: addr.reuse_port,
+            keepalive,
+            nodelay: addr.tcp_nodelay,
+            backlog: addr.backlog,
+        }
+    }
+
+    /// Checks whether the socket has to be bound here because Pingora cannot apply the settings
+    fn requires_binding(&self) -> bool {
+        self.reuse_port || self.keepalive.is_some() || self.nodelay || self.backlog.is_some()
+    }
+
+    /// Binds a non-blocking listening socket with these settings
+    ///
+    /// Accepted connections inherit the keepalive and `TCP_NODELAY` settings of the listening
+    /// socket.
+    pub(crate) fn bind(&self, addr: &str) -> std::io::Result<TcpListener> {
+        let addr: SocketAddr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing")
+        })?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if let (Some(ipv6_only), SocketAddr::V6(_)) = (self.ipv6_only, addr) {
+            socket.set_only_v6(ipv6_only)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(keepalive)?;
+        }
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        socket.bind(&addr.into())?;
+        let backlog = self.backlog.unwrap_or(DEFAULT_BACKLOG);
+        socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    }
+}
+
+fn resolve_user(name: &str) -> Result<Uid, Box<Error>> {
+    if let Ok(uid) = name.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    match User::from_name(name) {
+        Ok(Some(user)) => Ok(user.uid),
+        Ok(None) => Err(Error::explain(
+            ErrorType::InternalError,
+            format!("unknown user {name}"),
+        )),
+        Err(err) => Err(Error::because(
+            ErrorType::InternalError,
+            format!("failed looking up user {name}"),
+            err,
+        )),
+    }
+}
+
+pub(crate) fn resolve_group(name: &str) -> Result<Gid, Box<Error>> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    match Group::from_name(name) {
+        Ok(Some(group)) => Ok(group.gid),
+        Ok(None) => Err(Error::explain(
+            ErrorType::InternalError,
+            format!("unknown group {name}"),
+        )),
+        Err(err) => Err(Error::because(
+            ErrorType::InternalError,
+            format!("failed looking up group {name}"),
+            err,
+        )),
+    }
+}
+
+/// Sockets bound before dropping privileges, keyed by listen address
+static BOUND_EARLY: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+
+/// Binds the listening sockets right away rather than on service start
+///
+/// This is required before giving up the privileges necessary to bind them. Sockets passed on by
+/// systemd don’t need to be bound.
+pub(crate) fn bind_early(listen: &[ListenAddr]) -> Result<(), Box<Error>> {
+    let mut bound = BOUND_EARLY.lock().unwrap_or_else(|err| err.into_inner());
+    for addr in listen {
+        if inherited_fd(&addr.addr).is_some() {
+            continue;
+        }
+
+        let fd = if let Some(path) = addr.unix_path() {
+            UnixSocket {
+                path: path.to_owned(),
+                mode: addr.socket_mode(),
+                owner: addr.owner.as_deref().map(resolve_user).transpose()?,
+                group: addr.group.as_deref().map(resolve_group).transpose()?,
+            }
+            .bind()
+        } else {
+            let mut options = TcpOptions::from_addr(addr);
+            // Relaying listeners need to be able to bind again during an upgrade
+            options.reuse_port |= addr.is_relayed();
+            options.bind(&addr.addr).map(OwnedFd::from)
+        };
+
+        let fd = fd.map_err(|err| {
+            Error::because(
+                ErrorType::BindError,
+                format!("failed binding listen address {}", addr.addr),
+                err,
+            )
+        })?;
+        bound.push((addr.addr.clone(), fd));
+    }
+    Ok(())
+}
+
+/// Adds the endpoints for a listen address to a service
+///
+/// If connections have to be relayed, the service gets the internal endpoints and the relaying
+/// listener is added to the list.
+pub(crate) fn add_endpoints<A>(
+    service: &mut ListeningService<A>,
+    addr: &ListenAddr,
+    tls_settings: Option<TlsSettings>,
+    strict_transport_security: Option<&HeaderValue>,
+    relay_listeners: &mut Vec<RelayListener>,
+) -> Result<(), Box<Error>> {
+    // The internal HTTP/2 endpoint uses TLS but the client connection doesn’t, so only the first
+    // endpoint gets the `Strict-Transport-Security` header
+    let mut endpoints = Vec::new();
+    if let Some(listener) = RelayListener::new(addr)? {
+        endpoints.push((
+            ServerAddress::Tcp(listener.internal_addr(), None),
+            tls_settings,
+            strict_transport_security,
+        ));
+        if let Some((h2c_addr, h2c_settings)) = listener.h2c_endpoint()? {
+            endpoints.push((ServerAddress::Tcp(h2c_addr, None), Some(h2c_settings), None));
+        }
+        relay_listeners.push(listener);
+    } else {
+        endpoints.push((
+            addr.to_server_address(),
+            tls_settings,
+            strict_transport_security,
+        ));
+    }
+
+    for (server_address, tls_settings, strict_transport_security) in endpoints {
+        if let Some(name) = &addr.name {
+            register_listener_name(&server_address, name);
+        }
+        if let Some(value) = strict_transport_security {
+            STRICT_TRANSPORT_SECURITY.register(&server_address, value.clone());
+        }
+        service
+            .endpoints()
+            .add_endpoint(server_address, tls_settings);
+    }
+    Ok(())
+}
+
+/// Retrieves the socket bound for a listen address by [`bind_early`] if any
+pub(crate) fn take_bound_early(addr: &str) -> Option<OwnedFd> {
+    let mut bound = BOUND_EARLY.lock().unwrap_or_else(|err| err.into_inner());
+    let index = bound
+        .iter()
+        .position(|(bound_addr, _)| bound_addr == addr)?;
+    Some(bound.swap_remove(index).1)
+}
+
+/// A service wrapper binding some listening sockets itself before starting the wrapped service
+///
+/// The sockets are placed in Pingora’s file descriptor table where Pingora will pick them up
+/// instead of binding on its own. Sockets already present in the table (e.g. passed on by the old
+/// process during a graceful upgrade) are left untouched.
+///
+/// Sockets passed on by systemd are placed in the table the same way. TCP sockets with settings
+/// that Pingora doesn’t support are bound here as well. Relaying listeners (PROXY protocol,
+/// cleartext HTTP/2) are started here too, relaying connections to the internal addresses that the
+/// wrapped service listens on.
+#[derive(Debug)]
+pub(crate) struct ListenerService<S> {
+    inner: S,
+    unix_sockets: Vec<UnixSocket>,
+    tcp_sockets: Vec<(String, TcpOptions)>,
+    inherited: Vec<(String, RawFd)>,
+    bound_early: Vec<(String, String)>,
+    relay_listeners: Vec<RelayListener>,
+}
+
+impl<S> ListenerService<S> {
+    /// Wraps a service, preparing the sockets for the listen addresses that require it
+    pub(crate) fn new(
+        inner: S,
+        listen: &OneOrMany<ListenAddr>,
+        relay_listeners: Vec<RelayListener>,
+    ) -> Result<Self, Box<Error>> {
+        let mut unix_sockets = Vec::new();
+        let mut tcp_sockets = Vec::new();
+        let mut inherited = Vec::new();
+        let mut bound_early = Vec::new();
+        for addr in listen {
+            let key = addr.unix_path().unwrap_or(&addr.addr);
+            if let Some(fd) = inherited_fd(&addr.addr) {
+                inherited.push((key.to_owned(), fd));
+                continue;
+            }
+            if !addr.is_relayed() {
+                bound_early.push((addr.addr.clone(), key.to_owned()));
+            }
+
+            let Some(path) = addr.unix_path() else {
+                // Relaying listeners bind the public socket themselves
+                let options = TcpOptions::from_addr(addr);
+                if !addr.is_relayed() && options.requires_binding() {
+                    tcp_sockets.push((addr.addr.clone(), options));
+                }
+                continue;
+            };
+
+            // Pingora can handle permissions, ownership is what it cannot set
+            if addr.owner.is_none() && addr.group.is_none() {
+                continue;
+            }
+
+            unix_sockets.push(UnixSocket {
+                path: path.to_owned(),
+                mode: addr.socket_mode(),
+                owner: addr.owner.as_deref().map(resolve_user).transpose()?,
+                group: addr.group.as_deref().map(resolve_group).transpose()?,
+            });
+        }
+
+        Ok(Self {
+            inner,
+            unix_sockets,
+            tcp_sockets,
+            inherited,
+            bound_early,
+            relay_listeners,
+        })
+    }
+}
+
+#[async_trait]
+impl<S> Service for ListenerService<S>
+where
+    S: Service,
+{
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        if let Some(fds) = &fds {
+            let mut table = fds.lock().await;
+            for (addr, fd) in &self.inherited {
+                if table.get(addr).is_none() {
+                    table.add(addr.clone(), *fd);
+                }
+            }
+
+            for (addr, key) in &self.bound_early {
+                if let Some(fd) = take_bound_early(addr) {
+                    if table.get(key).is_none() {
+                        table.add(key.clone(), fd.into_raw_fd());
+                    }
+                }
+            }
+
+            for socket in &self.unix_sockets {
+                if table.get(&socket.path).is_some() {
+                    continue;
+                }
+
+                // Pingora would bind the socket without the requested settings, don't continue
+                match socket.bind() {
+                    Ok(fd) => table.add(socket.path.clone(), fd.into_raw_fd()),
+                    Err(err) => {
+                        error!("Failed setting up Unix socket {}: {err}", socket.path);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            for (addr, options) in &self.tcp_sockets {
+                if table.get(addr).is_some() {
+                    continue;
+                }
+
+                match options.bind(addr) {
+                    Ok(listener) => table.add(addr.clone(), listener.into_raw_fd()),
+                    Err(err) => {
+                        error!("Failed setting up TCP socket {addr}: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+
+        for listener in &mut self.relay_listeners {
+            listener.start(fds.as_ref(), shutdown.clone()).await;
+        }
+
+        self.inner.start_service(fds, shutdown).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn threads(&self) -> Option<usize> {
+        self.inner.threads()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::sys::socket::{getsockopt, sockopt};
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn tcp_options() {
+        let addr = ListenAddr {
+            addr: "127.0.0.1:0".to_owned(),
+            reuse_port: true,
+            tcp_keepalive_idle: Some(60),
+            tcp_keepalive_interval: Some(10),
+            tcp_keepalive_count: Some(5),
+            ..Default::default()
+        };
+        let options = TcpOptions::from_addr(&addr);
+        assert!(options.requires_binding());
+
+        let listener = options.bind(&addr.addr).unwrap();
+        assert!(getsockopt(listener.as_raw_fd(), sockopt::ReusePort).unwrap());
+
+        // Keepalive settings are inherited by accepted connections
+        listener.set_nonblocking(false).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let fd = stream.as_raw_fd();
+        assert!(getsockopt(fd, sockopt::KeepAlive).unwrap());
+        assert_eq!(getsockopt(fd, sockopt::TcpKeepIdle).unwrap(), 60);
+        assert_eq!(getsockopt(fd, sockopt::TcpKeepInterval).unwrap(), 10);
+        assert_eq!(getsockopt(fd, sockopt::TcpKeepCount).unwrap(), 5);
+
+        assert!(!getsockopt(fd, sockopt::TcpNoDelay).unwrap());
+
+        let options = TcpOptions::from_addr(&ListenAddr {
+            addr: "127.0.0.1:0".to_owned(),
+            tcp_nodelay: true,
+            backlog: Some(16),
+            ..Default::default()
+        });
+        assert!(options.requires_binding());
+        let listener = options.bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(false).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(getsockopt(stream.as_raw_fd(), sockopt::TcpNoDelay).unwrap());
+
+        let options = TcpOptions::from_addr(&"127.0.0.1:0".into());
+        assert!(!options.requires_binding());
+        let listener = options.bind("127.0.0.1:0").unwrap();
+        assert!(!getsockopt(listener.as_raw_fd(), sockopt::ReusePort).unwrap());
+        assert!(!getsockopt(listener.as_raw_fd(), sockopt::KeepAlive).unwrap());
+    }
+}
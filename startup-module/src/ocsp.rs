@@ -0,0 +1,572 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OCSP stapling, fetching OCSP responses from the responders listed in the certificates
+
+use async_trait::async_trait;
+use http::{Method, StatusCode};
+use log::{debug, error, info, warn};
+use openssl::asn1::{Asn1GeneralizedTimeRef, Asn1Time};
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::connectors::http::Connector;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::utils::CertKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::certificates::Certificates;
+use crate::http_client::http_request;
+
+pub(crate) const OCSP_ERR: ErrorType = ErrorType::Custom("OCSPError");
+
+/// Delay before retrying after a failed request
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Maximal delay between refreshing responses
+const MAX_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Minimal delay between refreshing responses
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Allowed clock difference when checking response validity
+const MAX_CLOCK_SKEW: u32 = 5 * 60;
+
+fn crypto_error(err: openssl::error::ErrorStack) -> Box<Error> {
+    Error::because(OCSP_ERR, "OpenSSL error", err)
+}
+
+/// Makes sure that the data is a successful OCSP response
+pub(crate) fn check_response(response: &[u8]) -> Result<(), Box<Error>> {
+    let response = OcspResponse::from_der(response)
+        .map_err(|err| Error::because(OCSP_ERR, "failed parsing OCSP response", err))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(Error::explain(
+            OCSP_ERR,
+            format!(
+                "unsuccessful OCSP response status {}",
+                response.status().as_raw()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Determines the number of seconds until the given time, negative values for times in the past
+fn seconds_until(time: &Asn1GeneralizedTimeRef) -> Option<i64> {
+    // There is no direct conversion, so this has to go through the string representation which
+    // looks like `Oct 18 12:00:00 2026 GMT`.
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let text = time.to_string();
+    let mut parts = text.split_whitespace();
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? + 1;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let time = parts.next()?.split('.').next()?.replace(':', "");
+    let year: u32 = parts.next()?.parse().ok()?;
+
+    let time = Asn1Time::from_str(&format!("{year:04}{month:02}{day:02}{time}Z")).ok()?;
+    let diff = Asn1Time::days_from_now(0).ok()?.diff(&time).ok()?;
+    Some(i64::from(diff.days) * 24 * 60 * 60 + i64::from(diff.secs))
+}
+
+/// OCSP response fetched from a responder
+#[derive(Debug)]
+struct FetchedResponse {
+    response: Vec<u8>,
+    refresh: Duration,
+    expires: Option<Instant>,
+}
+
+/// Requests an OCSP response for the certificate
+///
+/// `None` means that the certificate doesn’t list an OCSP responder.
+async fn fetch_response(
+    connector: &Connector,
+    cert: &CertKey,
+) -> Result<Option<FetchedResponse>, Box<Error>> {
+    let leaf = cert.leaf();
+    let Some(url) = leaf
+        .ocsp_responders()
+        .ok()
+        .and_then(|responders| responders.iter().next().map(|url| url.to_string()))
+    else {
+        return Ok(None);
+    };
+
+    let issuer = cert.intermediates().first().ok_or_else(|| {
+        Error::explain(
+            OCSP_ERR,
+            "issuer certificate missing from the chain, cannot request OCSP response",
+        )
+    })?;
+    let id = || OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).map_err(crypto_error);
+
+    let mut request = OcspRequest::new().map_err(crypto_error)?;
+    request.add_id(id()?).map_err(crypto_error)?;
+    let request = request.to_der().map_err(crypto_error)?;
+
+    let response = http_request(
+        connector,
+        OCSP_ERR,
+        Method::POST,
+        &url,
+        Some(("application/ocsp-request", request)),
+        None,
+    )
+    .await?;
+    if response.status != StatusCode::OK {
+        return Err(Error::explain(
+            OCSP_ERR,
+            format!("OCSP responder {url} returned status {}", response.status),
+        ));
+    }
+    check_response(&response.body)?;
+
+    let parsed = OcspResponse::from_der(&response.body).map_err(crypto_error)?;
+    let basic = parsed.basic().map_err(crypto_error)?;
+
+    // The response is signed by the issuer or by a responder certificate issued by it
+    let mut store = X509StoreBuilder::new().map_err(crypto_error)?;
+    store.add_cert(issuer.clone()).map_err(crypto_error)?;
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(crypto_error)?;
+    let store = store.build();
+    let mut certs = Stack::new().map_err(crypto_error)?;
+    certs.push(issuer.clone()).map_err(crypto_error)?;
+    basic
+        .verify(&certs, &store, OcspFlag::empty())
+        .map_err(|err| Error::because(OCSP_ERR, "OCSP response signature invalid", err))?;
+
+    let id = id()?;
+    let status = basic
+        .find_status(&id)
+        .ok_or_else(|| Error::explain(OCSP_ERR, "OCSP response doesn't cover the certificate"))?;
+    status
+        .check_validity(MAX_CLOCK_SKEW, None)
+        .map_err(|err| Error::because(OCSP_ERR, "OCSP response outdated", err))?;
+    if status.status == OcspCertStatus::REVOKED {
+        warn!(
+            "OCSP responder {url} reports certificate {:?} as revoked",
+            leaf.subject_name()
+        );
+    }
+
+    // Refresh halfway to expiration
+    let valid_for = seconds_until(status.next_update)
+        .map(|seconds| Duration::from_secs(u64::try_from(seconds).unwrap_or(0)));
+    let refresh = valid_for
+        .map(|valid_for| valid_for / 2)
+        .unwrap_or(MAX_REFRESH_INTERVAL)
+        .clamp(MIN_REFRESH_INTERVAL, MAX_REFRESH_INTERVAL);
+
+    Ok(Some(FetchedResponse {
+        response: response.body,
+        refresh,
+        expires: valid_for.map(|valid_for| Instant::now() + valid_for),
+    }))
+}
+
+/// Background service keeping OCSP responses for certificates up to date
+#[derive(Debug)]
+pub(crate) struct OcspFetcher {
+    certificates: Arc<Certificates>,
+    expires: Mutex<HashMap<String, Instant>>,
+}
+
+impl OcspFetcher {
+    pub(crate) fn new(certificates: Arc<Certificates>) -> Self {
+        Self {
+            certificates,
+            expires: Default::default(),
+        }
+    }
+
+    /// Fetches responses for all certificates, returns the delay until the next refresh
+    async fn refresh(&self, connector: &Connector) -> Duration {
+        let mut next = MAX_REFRESH_INTERVAL;
+        for name in self.certificates.names_without_ocsp_file() {
+            let Some(cert) = self.certificates.get().get(name).cloned() else {
+                continue;
+            };
+
+            match fetch_response(connector, &cert).await {
+                Ok(Some(fetched)) => {
+                    debug!("Fetched OCSP response for server name {name:?}");
+                    self.certificates
+                        .set_ocsp_response(name, Some(fetched.response));
+                    let mut expires = self.expires.lock().unwrap();
+                    if let Some(time) = fetched.expires {
+                        expires.insert(name.to_owned(), time);
+                    } else {
+                        expires.remove(name);
+                    }
+                    next = next.min(fetched.refresh);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("Failed fetching OCSP response for server name {name:?}: {err}");
+                    next = next.min(RETRY_INTERVAL);
+
+                    // Keep the previous response unless it expired already
+                    let expired = self
+                        .expires
+                        .lock()
+                        .unwrap()
+                        .get(name)
+                        .is_some_and(|time| *time <= Instant::now());
+                    if expired {
+                        self.certificates.set_ocsp_response(name, None);
+                    }
+                }
+            }
+        }
+        next
+    }
+}
+
+#[async_trait]
+impl BackgroundService for OcspFetcher {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!("Starting OCSP response fetcher");
+        let connector = Connector::new(None);
+        loop {
+            let next = self.refresh(&connector).await;
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(next) => {}
+                _ = self.certificates.reloaded() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use openssl::asn1::{Asn1Object, Asn1OctetString};
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509};
+    use pandora_module_utils::pingora::{HttpPeer, ProxyHttp, ResponseHeader, Session};
+    use pingora::proxy::http_proxy_service;
+    use pingora::server::configuration::ServerConf;
+    use pingora::services::Service;
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use test_log::test;
+    use tokio::sync::watch;
+
+    use crate::configuration::CertKeyConf;
+
+    /// Encodes a DER element
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut result = vec![tag];
+        let len = content.len();
+        if len < 0x80 {
+            result.push(len as u8);
+        } else if len < 0x100 {
+            result.extend([0x81, len as u8]);
+        } else {
+            result.extend([0x82, (len >> 8) as u8, len as u8]);
+        }
+        result.extend_from_slice(content);
+        result
+    }
+
+    /// Splits off the first DER element, returns element content and remaining data
+    fn der_next(data: &[u8]) -> (&[u8], &[u8]) {
+        let (header, len) = match data[1] {
+            0x81 => (3, usize::from(data[2])),
+            0x82 => (4, usize::from(data[2]) << 8 | usize::from(data[3])),
+            len => (2, usize::from(len)),
+        };
+        (&data[header..header + len], &data[header + len..])
+    }
+
+    /// Encodes a time as DER `GeneralizedTime`
+    fn generalized_time(offset: i64) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let time = now as i64 + offset;
+        let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+
+        // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        let text = format!(
+            "{year:04}{month:02}{day:02}{:02}{:02}{:02}Z",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+        der(0x18, text.as_bytes())
+    }
+
+    pub(crate) fn generate_cert(
+        name: &str,
+        issuer: Option<&(X509, PKey<Private>)>,
+        ocsp_url: Option<&str>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::generate_ed25519().unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        cert.set_not_after(Asn1Time::days_from_now(1).unwrap().as_ref())
+            .unwrap();
+        if let Some(url) = ocsp_url {
+            // Authority Information Access extension with an OCSP access method
+            let method = der(0x06, &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01]);
+            let location = der(0x86, url.as_bytes());
+            let value = der(0x30, &der(0x30, &[method, location].concat()));
+            let extension = X509Extension::new_from_der(
+                Asn1Object::from_str("1.3.6.1.5.5.7.1.1").unwrap().as_ref(),
+                false,
+                Asn1OctetString::new_from_bytes(&value).unwrap().as_ref(),
+            )
+            .unwrap();
+            cert.append_extension(extension).unwrap();
+        }
+
+        if let Some((issuer, issuer_key)) = issuer {
+            cert.set_issuer_name(issuer.subject_name()).unwrap();
+            cert.sign(issuer_key, MessageDigest::null()).unwrap();
+        } else {
+            cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+            cert.set_issuer_name(&subject).unwrap();
+            cert.sign(&key, MessageDigest::null()).unwrap();
+        }
+        (cert.build(), key)
+    }
+
+    /// Extracts the certificate ID from an OCSP request
+    fn request_cert_id(request: &[u8]) -> Vec<u8> {
+        let (request, _) = der_next(request);
+        let (tbs_request, _) = der_next(request);
+        let (request_list, _) = der_next(tbs_request);
+        let (single_request, _) = der_next(request_list);
+        let (cert_id, _) = der_next(single_request);
+        der(0x30, cert_id)
+    }
+
+    /// Produces an OCSP response for the certificate ID, signed by the given certificate
+    fn create_response(signer: &(X509, PKey<Private>), cert_id: &[u8], valid_for: i64) -> Vec<u8> {
+        let single_response = der(
+            0x30,
+            &[
+                cert_id.to_vec(),
+                der(0x80, &[]),
+                generalized_time(-60),
+                der(0xa0, &generalized_time(valid_for)),
+            ]
+            .concat(),
+        );
+        let tbs = der(
+            0x30,
+            &[
+                der(0xa1, &signer.0.subject_name().to_der().unwrap()),
+                generalized_time(0),
+                der(0x30, &single_response),
+            ]
+            .concat(),
+        );
+
+        let mut signature = vec![0];
+        signature.extend(
+            Signer::new_without_digest(&signer.1)
+                .unwrap()
+                .sign_oneshot_to_vec(&tbs)
+                .unwrap(),
+        );
+        let algorithm = der(0x30, &der(0x06, &[0x2b, 0x65, 0x70]));
+        let basic = der(0x30, &[tbs, algorithm, der(0x03, &signature)].concat());
+
+        let basic_type = der(
+            0x06,
+            &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+        );
+        let response_bytes = der(0x30, &[basic_type, der(0x04, &basic)].concat());
+        der(
+            0x30,
+            &[der(0x0a, &[0]), der(0xa0, &response_bytes)].concat(),
+        )
+    }
+
+    /// Produces an OCSP response for a certificate, signed by its issuer
+    pub(crate) fn issuer_response(cert: &X509, issuer: &(X509, PKey<Private>)) -> Vec<u8> {
+        let mut request = OcspRequest::new().unwrap();
+        request
+            .add_id(OcspCertId::from_cert(MessageDigest::sha1(), cert, &issuer.0).unwrap())
+            .unwrap();
+        create_response(
+            issuer,
+            &request_cert_id(&request.to_der().unwrap()),
+            60 * 60,
+        )
+    }
+
+    /// OCSP responder stand-in, signing responses with the CA key unless `/untrusted` is requested
+    struct MockResponder {
+        ca: (X509, PKey<Private>),
+        untrusted: (X509, PKey<Private>),
+    }
+
+    #[async_trait]
+    impl ProxyHttp for MockResponder {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<bool, Box<Error>> {
+            let mut body = Vec::new();
+            while let Some(chunk) = session.read_request_body().await? {
+                body.extend_from_slice(&chunk);
+            }
+
+            let signer = if session.req_header().uri.path() == "/untrusted" {
+                &self.untrusted
+            } else {
+                &self.ca
+            };
+            let response = create_response(signer, &request_cert_id(&body), 60 * 60);
+
+            let mut header = ResponseHeader::build(200, Some(2))?;
+            header.append_header("Content-Type", "application/ocsp-response")?;
+            header.append_header("Content-Length", response.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(response.into()).await?;
+            Ok(true)
+        }
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>, Box<Error>> {
+            Err(Error::new(ErrorType::HTTPStatus(404)))
+        }
+    }
+
+    fn cert_conf(name: &str, cert: &X509, issuer: &X509, key: &PKey<Private>) -> CertKeyConf {
+        let dir = std::env::temp_dir().join(format!("startup-module-ocsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join(format!("{name}.cert.pem"));
+        let key_path = dir.join(format!("{name}.key.pem"));
+        let mut chain = cert.to_pem().unwrap();
+        chain.extend(issuer.to_pem().unwrap());
+        std::fs::write(&cert_path, chain).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        CertKeyConf {
+            cert_path: Some(cert_path),
+            key_path: Some(key_path),
+            ..Default::default()
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn fetch_responses() {
+        const ADDR: &str = "127.0.0.1:18455";
+
+        let ca = generate_cert("Test CA", None, None);
+        let untrusted = generate_cert("Test CA", None, None);
+        let mut service = http_proxy_service(
+            &Arc::new(ServerConf::default()),
+            MockResponder {
+                ca: ca.clone(),
+                untrusted,
+            },
+        );
+        service.add_tcp(ADDR);
+        let (_shutdown, watch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+
+        let good = generate_cert("good", Some(&ca), Some(&format!("http://{ADDR}/")));
+        let bad = generate_cert("bad", Some(&ca), Some(&format!("http://{ADDR}/untrusted")));
+        let none = generate_cert("none", Some(&ca), None);
+        let certificates = Arc::new(
+            Certificates::new(
+                HashMap::from([
+                    (
+                        "good".to_owned(),
+                        cert_conf("good", &good.0, &ca.0, &good.1),
+                    ),
+                    ("bad".to_owned(), cert_conf("bad", &bad.0, &ca.0, &bad.1)),
+                    (String::new(), cert_conf("none", &none.0, &ca.0, &none.1)),
+                ]),
+                None,
+            )
+            .unwrap(),
+        );
+
+        // Give the mock server a moment to start up
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let fetcher = OcspFetcher::new(certificates.clone());
+        let connector = Connector::new(None);
+        let next = fetcher.refresh(&connector).await;
+
+        // Failure for the untrusted response means retrying soon
+        assert_eq!(next, RETRY_INTERVAL);
+        assert!(certificates.ocsp_response("bad").is_none());
+        assert!(certificates.ocsp_response("").is_none());
+
+        let response = certificates.ocsp_response("good").unwrap();
+        check_response(&response).unwrap();
+
+        // Refresh should be scheduled halfway to expiration
+        let cert = certificates.get().get("good").cloned().unwrap();
+        let fetched = fetch_response(&connector, &cert).await.unwrap().unwrap();
+        assert!(fetched.refresh > Duration::from_secs(29 * 60));
+        assert!(fetched.refresh <= Duration::from_secs(30 * 60));
+
+        // Fetched response is kept on reload if the certificate didn't change
+        certificates.reload().unwrap();
+        assert_eq!(certificates.ocsp_response("good"), Some(response));
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dropping root privileges after the listening sockets are bound
+
+use log::{info, warn};
+use nix::unistd::{chdir, chroot, initgroups, setgid, setgroups, setuid, Gid, Uid, User};
+use pandora_module_utils::chroot::set_chroot;
+use pandora_module_utils::pingora::{Error, ErrorType, ServerConf};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::listener::resolve_group;
+
+/// User, group and root directory to switch to
+#[derive(Debug)]
+pub(crate) struct Privileges {
+    user: Option<User>,
+    group: Option<Gid>,
+    chroot: Option<PathBuf>,
+}
+
+fn lookup_user(name: &str) -> Result<User, Box<Error>> {
+    let result = match name.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(name),
+    };
+    match result {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(Error::explain(
+            ErrorType::InternalError,
+            format!("unknown user {name}"),
+        )),
+        Err(err) => Err(Error::because(
+            ErrorType::InternalError,
+            format!("failed looking up user {name}"),
+            err,
+        )),
+    }
+}
+
+/// Files needed to connect to ACME and OCSP servers by host name
+const NETWORK_FILES: [&str; 2] = ["/etc/resolv.conf", "/etc/hosts"];
+
+fn failed(what: &str, err: nix::Error) -> Box<Error> {
+    Error::because(
+        ErrorType::InternalError,
+        format!("failed dropping privileges: {what}"),
+        err,
+    )
+}
+
+impl Privileges {
+    /// Resolves user and group names, returns `None` if there is nothing to change.
+    ///
+    /// Names have to be resolved before changing the root directory, the user database might not
+    /// be accessible afterwards.
+    pub(crate) fn new(
+        user: Option<&str>,
+        group: Option<&str>,
+        chroot: Option<PathBuf>,
+    ) -> Result<Option<Self>, Box<Error>> {
+        if user.is_none() && group.is_none() && chroot.is_none() {
+            return Ok(None);
+        }
+
+        let chroot = chroot
+            .map(|path| {
+                path.canonicalize().map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        format!("failed accessing chroot directory {}", path.display()),
+                        err,
+                    )
+                })
+            })
+            .transpose()?;
+
+        Ok(Some(Self {
+            user: user.map(lookup_user).transpose()?,
+            group: group.map(resolve_group).transpose()?,
+            chroot,
+        }))
+    }
+
+    /// Maps a path used after changing the root directory to its current location
+    fn within_root(root: &Path, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Checks that files accessed after changing the root directory can be found there.
+    ///
+    /// `daemon` indicates that PID file and error log will be written, `network` that background
+    /// services need to resolve host names.
+    pub(crate) fn validate(
+        &self,
+        conf: &ServerConf,
+        daemon: bool,
+        network: bool,
+    ) -> Result<(), Box<Error>> {
+        let Some(root) = &self.chroot else {
+            return Ok(());
+        };
+
+        if daemon {
+            let files = [
+                ("pid_file", Some(&conf.pid_file)),
+                ("error_log", conf.error_log.as_ref()),
+            ];
+            for (name, path) in files {
+                let Some(path) = path else {
+                    continue;
+                };
+                let location = Self::within_root(root, path);
+                if !location.parent().is_some_and(Path::is_dir) {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "{name} {path} is written after changing root directory but directory {} doesn't exist",
+                            location.parent().unwrap_or(&location).display()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let upgrade_sock = Self::within_root(root, &conf.upgrade_sock);
+        if !upgrade_sock.parent().is_some_and(Path::is_dir) {
+            warn!(
+                "Directory of upgrade_sock {} doesn't exist within root directory, upgrades will fail",
+                conf.upgrade_sock
+            );
+        }
+
+        if network {
+            for file in NETWORK_FILES {
+                if !Self::within_root(root, file).exists() {
+                    warn!("{file} doesn't exist within root directory, host names of ACME or OCSP servers might not resolve");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the root directory, then switches to the configured group and user
+    pub(crate) fn apply(&self) -> Result<(), Box<Error>> {
+        if let Some(root) = &self.chroot {
+            chroot(root).map_err(|err| failed("changing root directory", err))?;
+            chdir("/").map_err(|err| failed("changing working directory", err))?;
+            set_chroot(root);
+            info!("Changed root directory to {}", root.display());
+
+            if self.user.is_none() {
+                warn!("Changing root directory without switching user, the process can escape");
+            }
+        }
+
+        let gid = self
+            .group
+            .or_else(|| self.user.as_ref().map(|user| user.gid));
+        if let Some(gid) = gid {
+            // Only root can change supplementary groups
+            if Uid::effective().is_root() {
+                match &self.user {
+                    Some(user) => {
+                        let name = CString::new(user.name.as_str())
+                            .map_err(|_| failed("invalid user name", nix::Error::EINVAL))?;
+                        initgroups(&name, gid)
+                    }
+                    None => setgroups(&[gid]),
+                }
+                .map_err(|err| failed("setting supplementary groups", err))?;
+            }
+            setgid(gid).map_err(|err| failed("switching group", err))?;
+        }
+
+        if let Some(user) = &self.user {
+            setuid(user.uid).map_err(|err| failed("switching user", err))?;
+            info!("Switched to user {}", user.name);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        assert!(Privileges::new(None, None, None).unwrap().is_none());
+
+        let privileges = Privileges::new(Some("0"), None, None).unwrap().unwrap();
+        assert_eq!(
+            privileges.user.as_ref().map(|user| user.uid),
+            Some(Uid::from_raw(0))
+        );
+        assert_eq!(privileges.group, None);
+
+        let privileges = Privileges::new(None, Some("0"), Some("/tmp/../".into()))
+            .unwrap()
+            .unwrap();
+        assert!(privileges.user.is_none());
+        assert_eq!(privileges.group, Some(Gid::from_raw(0)));
+        assert_eq!(privileges.chroot, Some(PathBuf::from("/")));
+
+        assert!(Privileges::new(Some("no-such-user-pandora"), None, None).is_err());
+        assert!(Privileges::new(None, Some("no-such-group-pandora"), None).is_err());
+        assert!(Privileges::new(None, None, Some("/no/such/directory".into())).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let root = std::env::temp_dir().join(format!("privileges-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let privileges = Privileges::new(None, None, Some(root.clone()))
+            .unwrap()
+            .unwrap();
+
+        let conf = ServerConf {
+            pid_file: "/run/pandora.pid".to_owned(),
+            ..Default::default()
+        };
+        assert!(privileges.validate(&conf, false, true).is_ok());
+        assert!(privileges.validate(&conf, true, false).is_err());
+
+        std::fs::create_dir_all(root.join("run")).unwrap();
+        assert!(privileges.validate(&conf, true, false).is_ok());
+
+        let conf = ServerConf {
+            pid_file: "/run/pandora.pid".to_owned(),
+            error_log: Some("/logs/error.log".to_owned()),
+            ..Default::default()
+        };
+        assert!(privileges.validate(&conf, true, false).is_err());
+
+        let unrestricted = Privileges::new(Some("0"), None, None).unwrap().unwrap();
+        assert!(unrestricted.validate(&conf, true, true).is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! PROXY protocol support
+//!
+//! Connections on listeners with PROXY protocol enabled are accepted by a [`RelayListener`],
+//! which reads the header before relaying the connection to Pingora.
+//!
+//! [`RelayListener`]: crate::relay::RelayListener
+
+use serde::de::{Deserialize, Deserializer, Error as _};
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+/// Signature starting a PROXY protocol version 2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximal length of a PROXY protocol version 1 header including the line break
+const V1_MAX_LENGTH: u64 = 107;
+
+/// IP network like `192.0.2.0/24` or `2001:db8::/32`, a single address is also accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Checks whether the network contains an IP address
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        fn matches(network: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+            let full_bytes = usize::from(prefix_len / 8);
+            let remaining_bits = prefix_len % 8;
+            if network[..full_bytes] != addr[..full_bytes] {
+                return false;
+            }
+            if remaining_bits == 0 {
+                return true;
+            }
+            let mask = 0xFFu8 << (8 - remaining_bits);
+            network[full_bytes] & mask == addr[full_bytes] & mask
+        }
+
+        // IPv4 clients connecting to an IPv6 socket appear as IPv4-mapped addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+            IpAddr::V4(_) => *addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP address in network {s}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in network {s}"))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Reads a PROXY protocol header, version 1 or 2
+///
+/// Returns the source address or `None` if the header doesn’t contain one (`LOCAL` or `UNKNOWN`
+/// connections, typically health checks of the load balancer).
+pub(crate) async fn read_header<R>(reader: &mut R) -> std::io::Result<Option<SocketAddr>>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    fn invalid(message: &str) -> std::io::Error {
+        std::io::Error::new(ErrorKind::InvalidData, message)
+    }
+
+    let mut start = [0; 12];
+    reader.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).await?;
+        let [version_command, family, len_high, len_low] = header;
+        if version_command >> 4 != 2 {
+            return Err(invalid("unsupported PROXY protocol version"));
+        }
+        let mut data = vec![0; usize::from(u16::from_be_bytes([len_high, len_low]))];
+        reader.read_exact(&mut data).await?;
+
+        // LOCAL command, connection established by the proxy itself
+        if version_command & 0x0F == 0 {
+            return Ok(None);
+        }
+
+        // Only stream connections can be relayed, transport is unspecified for unknown protocols
+        if family & 0x0F != 1 && family != 0 {
+            return Err(invalid("unsupported PROXY protocol transport"));
+        }
+
+        let addr = match family >> 4 {
+            1 if data.len() >= 12 => {
+                let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+                let port = u16::from_be_bytes([data[8], data[9]]);
+                Some(SocketAddr::new(ip.into(), port))
+            }
+            2 if data.len() >= 36 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&data[..16]);
+                let port = u16::from_be_bytes([data[32], data[33]]);
+                Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+            }
+            // Unix sockets and unspecified addresses
+            0 | 3 => None,
+            _ => return Err(invalid("malformed PROXY protocol address")),
+        };
+        return Ok(addr);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("PROXY protocol header missing"));
+    }
+
+    let mut line = start.to_vec();
+    reader
+        .take(V1_MAX_LENGTH - start.len() as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| invalid("malformed PROXY protocol header"))?;
+
+    let parts: Vec<_> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid source address in PROXY protocol header"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid source port in PROXY protocol header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+    use tokio::io::BufReader;
+
+    async fn parse(data: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+        let mut reader = BufReader::new(data);
+        read_header(&mut reader).await
+    }
+
+    #[test(tokio::test)]
+    async fn header_v1() {
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\r\nGET /")
+                .await
+                .unwrap(),
+            Some("192.0.2.1:12345".parse().unwrap())
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 443\r\n")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:12345".parse().unwrap())
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(
+            parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n")
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 12345\r\n")
+            .await
+            .is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 123456 80\r\n")
+            .await
+            .is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 12345 80\n")
+            .await
+            .is_err());
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        assert!(parse(&long).await.is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn header_v2() {
+        let mut ipv4 = V2_SIGNATURE.to_vec();
+        ipv4.extend_from_slice(&[0x21, 0x11, 0x00, 0x0F]);
+        ipv4.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0x30, 0x39, 0x00, 0x50]);
+        // TLV data is ignored
+        ipv4.extend_from_slice(&[0x04, 0x00, 0x00]);
+        assert_eq!(
+            parse(&ipv4).await.unwrap(),
+            Some("192.0.2.1:12345".parse().unwrap())
+        );
+
+        let mut ipv6 = V2_SIGNATURE.to_vec();
+        ipv6.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0x30, 0x39, 0x01, 0xBB]);
+        assert_eq!(
+            parse(&ipv6).await.unwrap(),
+            Some("[2001:db8::1]:12345".parse().unwrap())
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&local).await.unwrap(), None);
+
+        let mut wrong_version = V2_SIGNATURE.to_vec();
+        wrong_version.extend_from_slice(&[0x11, 0x11, 0x00, 0x00]);
+        assert!(parse(&wrong_version).await.is_err());
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C, 192, 0, 2, 1]);
+        assert!(parse(&truncated).await.is_err());
+
+        let mut datagram = V2_SIGNATURE.to_vec();
+        datagram.extend_from_slice(&[0x21, 0x12, 0x00, 0x0C]);
+        datagram.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0x30, 0x39, 0x00, 0x50]);
+        assert!(parse(&datagram).await.is_err());
+
+        let mut unspecified = V2_SIGNATURE.to_vec();
+        unspecified.extend_from_slice(&[0x21, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&unspecified).await.unwrap(), None);
+
+        let mut short_address = V2_SIGNATURE.to_vec();
+        short_address.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 192, 0, 2, 1]);
+        assert!(parse(&short_address).await.is_err());
+    }
+
+    #[test]
+    fn ip_network() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(network.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!network.contains(&"::1".parse().unwrap()));
+
+        let network: IpNetwork = "192.0.2.128/25".parse().unwrap();
+        assert!(network.contains(&"192.0.2.200".parse().unwrap()));
+        assert!(!network.contains(&"192.0.2.100".parse().unwrap()));
+
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!network.contains(&"2001:db9::1".parse().unwrap()));
+
+        let network: IpNetwork = "192.0.2.1".parse().unwrap();
+        assert_eq!(network.to_string(), "192.0.2.1/32");
+        assert!(network.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!network.contains(&"192.0.2.2".parse().unwrap()));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNetwork>()
+            .unwrap()
+            .contains(&"203.0.113.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("2001:db8::/129".parse::<IpNetwork>().is_err());
+        assert!("example.com/8".parse::<IpNetwork>().is_err());
+    }
+}
@@ -12,51 +12,153 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use async_trait::async_trait;
+use http::uri::Authority;
 use http::{header, Method, StatusCode};
-use pandora_module_utils::pingora::{
-    Error, ErrorType, HttpPeer, ProxyHttp, ResponseHeader, ServerConf, Session,
-};
+use pandora_module_utils::pingora::{Error, ProxyHttp, ResponseHeader, ServerConf, Session};
 use pandora_module_utils::standard_response::response_text;
+use percent_encoding::percent_decode_str;
 use pingora::{proxy::http_proxy_service, services::Service};
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::acme::AcmeChallenges;
+use crate::app::StartupApp;
 use crate::configuration::{TlsRedirectorConf, TLS_CONF_ERR};
+use crate::listener::{add_endpoints, ListenerService};
+use crate::systemd::expand_listen;
 
-struct RedirectorApp {
+/// Path pattern exempt from redirecting
+#[derive(Debug)]
+enum ExemptPath {
+    Exact(String),
+    Prefix(String),
+}
+
+/// Checks whether a path segment is `.` or `..`, possibly percent-encoded
+fn is_dot_segment(segment: &str) -> bool {
+    let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+    segment == "." || segment == ".."
+}
+
+impl ExemptPath {
+    fn matches(&self, path: &str) -> bool {
+        // Match against the path as the handler will see it, e.g. `%2F` is a slash
+        let Ok(path) = percent_decode_str(path).decode_utf8() else {
+            return false;
+        };
+
+        // The handler might resolve dot segments to a path outside the exempt one
+        if path.split('/').any(is_dot_segment) {
+            return false;
+        }
+
+        match self {
+            Self::Exact(exact) => path == exact.as_str(),
+            Self::Prefix(prefix) => path.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Validated redirector settings
+#[derive(Debug)]
+pub(crate) struct Redirect {
+    status: StatusCode,
     redirect_to: String,
     redirect_by_name: HashMap<String, String>,
+    keep_host: bool,
+    https_port: Option<u16>,
+    exempt: Vec<ExemptPath>,
+    challenges: Option<Arc<AcmeChallenges>>,
 }
 
-#[async_trait]
-impl ProxyHttp for RedirectorApp {
-    type CTX = ();
-    fn new_ctx(&self) -> Self::CTX {}
+impl Redirect {
+    pub(crate) fn from_conf(
+        conf: &TlsRedirectorConf,
+        challenges: Option<Arc<AcmeChallenges>>,
+    ) -> Result<Self, Box<Error>> {
+        if conf.redirect_to.is_empty() && !conf.keep_host {
+            return Err(Error::explain(
+                TLS_CONF_ERR,
+                "tls.redirector.redirect_to setting has to be specified for TLS redirector unless keep_host is enabled",
+            ));
+        }
 
-    async fn request_filter(
-        &self,
-        session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<bool, Box<Error>> {
-        let status = StatusCode::PERMANENT_REDIRECT;
-        let text = response_text(status);
+        let status = match conf.status {
+            Some(status) => StatusCode::from_u16(status)
+                .ok()
+                .filter(|status| {
+                    [
+                        StatusCode::MOVED_PERMANENTLY,
+                        StatusCode::FOUND,
+                        StatusCode::SEE_OTHER,
+                        StatusCode::TEMPORARY_REDIRECT,
+                        StatusCode::PERMANENT_REDIRECT,
+                    ]
+                    .contains(status)
+                })
+                .ok_or_else(|| {
+                    Error::explain(
+                        TLS_CONF_ERR,
+                        format!("tls.redirector.status setting {status} is not a redirect status, expected one of 301, 302, 303, 307, 308"),
+                    )
+                })?,
+            None => StatusCode::PERMANENT_REDIRECT,
+        };
 
-        let server_name = session
-            .get_header(header::HOST)
-            .and_then(|host| host.to_str().ok())
-            .map(|host| host.split_once(':').map(|(host, _)| host).unwrap_or(host))
-            .or_else(|| session.req_header().uri.host());
-        let target = server_name
-            .and_then(|host| self.redirect_by_name.get(host))
-            .unwrap_or(&self.redirect_to);
-
-        let mut header = ResponseHeader::build(status, Some(4))?;
-        header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
-        header.append_header(header::CONTENT_TYPE, "text/html; charset=utf-8")?;
-        header.append_header(
-            header::LOCATION,
-            format!(
+        let exempt = conf
+            .exempt
+            .iter()
+            .map(|pattern| {
+                if !pattern.starts_with('/') {
+                    return Err(Error::explain(
+                        TLS_CONF_ERR,
+                        format!("tls.redirector.exempt entry {pattern} has to start with /"),
+                    ));
+                }
+                Ok(match pattern.strip_suffix('*') {
+                    Some(prefix) => ExemptPath::Prefix(prefix.to_owned()),
+                    None => ExemptPath::Exact(pattern.clone()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            status,
+            redirect_to: conf.redirect_to.clone(),
+            redirect_by_name: conf.redirect_by_name.clone(),
+            keep_host: conf.keep_host,
+            https_port: conf.https_port,
+            exempt,
+            challenges,
+        })
+    }
+
+    /// Responds to the request unless it is exempt from redirecting, returns `true` if a
+    /// response has been sent.
+    pub(crate) async fn respond(&self, session: &mut Session) -> Result<bool, Box<Error>> {
+        if let Some(key_authorization) = self
+            .challenges
+            .as_ref()
+            .and_then(|challenges| challenges.http_response(session.req_header().uri.path()))
+        {
+            let mut header = ResponseHeader::build(StatusCode::OK, Some(2))?;
+            header.append_header(header::CONTENT_LENGTH, key_authorization.len().to_string())?;
+            header.append_header(header::CONTENT_TYPE, "application/octet-stream")?;
+            session.write_response_header(Box::new(header)).await?;
+            if session.req_header().method != Method::HEAD {
+                session
+                    .write_response_body(key_authorization.into())
+                    .await?;
+            }
+            return Ok(true);
+        }
+
+        if self.is_exempt(session.req_header().uri.path()) {
+            return Ok(false);
+        }
+
+        if let Some(target) = self.target(session) {
+            let location = format!(
                 "https://{}{}",
                 target,
                 session
@@ -65,44 +167,81 @@ impl ProxyHttp for RedirectorApp {
                     .path_and_query()
                     .map(|p| p.as_str())
                     .unwrap_or_default()
-            ),
-        )?;
-        session.write_response_header(Box::new(header)).await?;
-
-        if session.req_header().method != Method::HEAD {
-            session.write_response_body(text.into()).await?;
+            );
+            send_response(session, self.status, Some(location)).await?;
+        } else {
+            send_response(session, StatusCode::BAD_REQUEST, None).await?;
         }
 
         Ok(true)
     }
 
-    async fn upstream_peer(
-        &self,
-        _session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>, Box<Error>> {
-        Err(Error::new(ErrorType::HTTPStatus(404)))
+    fn is_exempt(&self, path: &str) -> bool {
+        self.exempt.iter().any(|exempt| exempt.matches(path))
+    }
+
+    /// Determines the host and port to redirect to, `None` if the request cannot be redirected.
+    fn target(&self, session: &Session) -> Option<String> {
+        let authority = session
+            .get_header(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+            .or_else(|| session.req_header().uri.authority().cloned());
+        let host = authority.as_ref().map(|authority| authority.host());
+
+        if let Some(target) = host.and_then(|host| self.redirect_by_name.get(host)) {
+            return Some(target.clone());
+        }
+
+        match host {
+            Some(host) if self.keep_host => Some(match self.https_port {
+                Some(port) if port != 443 => format!("{host}:{port}"),
+                _ => host.to_owned(),
+            }),
+            _ if !self.redirect_to.is_empty() => Some(self.redirect_to.clone()),
+            _ => None,
+        }
+    }
+}
+
+async fn send_response(
+    session: &mut Session,
+    status: StatusCode,
+    location: Option<String>,
+) -> Result<(), Box<Error>> {
+    let text = response_text(status);
+
+    let mut header = ResponseHeader::build(status, Some(3))?;
+    header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
+    header.append_header(header::CONTENT_TYPE, "text/html; charset=utf-8")?;
+    if let Some(location) = location {
+        header.append_header(header::LOCATION, location)?;
     }
+    session.write_response_header(Box::new(header)).await?;
+
+    if session.req_header().method != Method::HEAD {
+        session.write_response_body(text.into()).await?;
+    }
+
+    Ok(())
 }
 
-pub(crate) fn create_redirector(
+pub(crate) fn create_redirector<SV>(
     conf: &TlsRedirectorConf,
     server_conf: &Arc<ServerConf>,
-) -> Result<impl Service + 'static, Box<Error>> {
-    if conf.redirect_to.is_empty() {
-        return Err(Error::explain(
-            TLS_CONF_ERR,
-            "tls.redirector.redirect_to setting has to be specified for TLS redirector",
-        ));
-    }
-
-    let app = RedirectorApp {
-        redirect_to: conf.redirect_to.clone(),
-        redirect_by_name: conf.redirect_by_name.to_owned(),
-    };
+    app: StartupApp<SV>,
+    challenges: Option<Arc<AcmeChallenges>>,
+) -> Result<impl Service + 'static, Box<Error>>
+where
+    SV: ProxyHttp + Send + Sync + 'static,
+    SV::CTX: Send + Sync,
+{
+    let app = app.with_redirect(Redirect::from_conf(conf, challenges)?);
     let mut service = http_proxy_service(server_conf, app);
+    let mut relay_listeners = Vec::new();
 
-    for addr in &conf.listen {
+    let listen = expand_listen(&conf.listen)?;
+    for addr in &listen {
         if addr.tls {
             return Err(Error::explain(
                 TLS_CONF_ERR,
@@ -110,12 +249,215 @@ pub(crate) fn create_redirector(
             ));
         }
 
-        if let Some(socket_options) = addr.to_socket_options() {
-            service.add_tcp_with_settings(&addr.addr, socket_options);
-        } else {
-            service.add_tcp(&addr.addr);
+        add_endpoints(&mut service, addr, None, None, &mut relay_listeners)?;
+    }
+
+    ListenerService::new(service, &listen, relay_listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use pandora_module_utils::pingora::SessionWrapper;
+    use pandora_module_utils::{RequestFilter, RequestFilterResult};
+    use pingora::server::ShutdownWatch;
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    use crate::DefaultApp;
+
+    struct Handler;
+
+    #[async_trait]
+    impl RequestFilter for Handler {
+        type Conf = ();
+        type CTX = ();
+        fn new_ctx() -> Self::CTX {}
+
+        async fn request_filter(
+            &self,
+            session: &mut impl SessionWrapper,
+            _ctx: &mut Self::CTX,
+        ) -> Result<RequestFilterResult, Box<Error>> {
+            let text = "handled";
+            let mut header = ResponseHeader::build(200, Some(1))?;
+            header.append_header("Content-Length", text.len().to_string())?;
+            session.write_response_header(Box::new(header)).await?;
+            session.write_response_body(text.into()).await?;
+            Ok(RequestFilterResult::ResponseSent)
         }
     }
 
-    Ok(service)
+    fn start_redirector(conf: TlsRedirectorConf) -> watch::Sender<bool> {
+        let server_conf = Arc::new(ServerConf::default());
+        let mut service = create_redirector(
+            &conf,
+            &server_conf,
+            StartupApp::new(Arc::new(DefaultApp::new(Handler)), None),
+            None,
+        )
+        .unwrap();
+
+        let (shutdown, watch): (_, ShutdownWatch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch).await });
+        shutdown
+    }
+
+    async fn request(addr: &str, path: &str, host: &str) -> String {
+        let mut attempts = 0;
+        let mut stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(err) => {
+                    attempts += 1;
+                    assert!(attempts < 50, "failed connecting to server: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        };
+
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.to_ascii_lowercase()
+    }
+
+    #[test(tokio::test)]
+    async fn redirect() {
+        const ADDR: &str = "127.0.0.1:18459";
+
+        let _shutdown = start_redirector(TlsRedirectorConf {
+            listen: vec![ADDR.into()].into(),
+            redirect_to: "example.com".to_owned(),
+            redirect_by_name: [("localhost".to_owned(), "localhost:8443".to_owned())].into(),
+            ..Default::default()
+        });
+
+        let response = request(ADDR, "/path?query", "example.net").await;
+        assert!(response.starts_with("http/1.1 308 "));
+        assert!(response.contains("\r\nlocation: https://example.com/path?query\r\n"));
+
+        let response = request(ADDR, "/", "localhost:8080").await;
+        assert!(response.starts_with("http/1.1 308 "));
+        assert!(response.contains("\r\nlocation: https://localhost:8443/\r\n"));
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_keep_host() {
+        const ADDR: &str = "127.0.0.1:18460";
+
+        let _shutdown = start_redirector(TlsRedirectorConf {
+            listen: vec![ADDR.into()].into(),
+            redirect_by_name: [("localhost".to_owned(), "example.com".to_owned())].into(),
+            keep_host: true,
+            https_port: Some(8443),
+            status: Some(301),
+            ..Default::default()
+        });
+
+        let response = request(ADDR, "/path", "example.net:8080").await;
+        assert!(response.starts_with("http/1.1 301 "));
+        assert!(response.contains("\r\nlocation: https://example.net:8443/path\r\n"));
+
+        let response = request(ADDR, "/", "[::1]:8080").await;
+        assert!(response.contains("\r\nlocation: https://[::1]:8443/\r\n"));
+
+        let response = request(ADDR, "/", "localhost").await;
+        assert!(response.contains("\r\nlocation: https://example.com/\r\n"));
+
+        // Without a valid host name there is nothing to redirect to
+        let response = request(ADDR, "/", "invalid host").await;
+        assert!(response.starts_with("http/1.1 400 "));
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_exempt() {
+        const ADDR: &str = "127.0.0.1:18461";
+
+        let _shutdown = start_redirector(TlsRedirectorConf {
+            listen: vec![ADDR.into()].into(),
+            redirect_to: "example.com".to_owned(),
+            exempt: vec!["/health".to_owned(), "/.well-known/*".to_owned()].into(),
+            ..Default::default()
+        });
+
+        let response = request(ADDR, "/health", "localhost").await;
+        assert!(response.starts_with("http/1.1 200 "));
+        assert!(response.ends_with("\r\n\r\nhandled"));
+
+        let response = request(ADDR, "/.well-known/test", "localhost").await;
+        assert!(response.starts_with("http/1.1 200 "));
+
+        let response = request(ADDR, "/health/check", "localhost").await;
+        assert!(response.starts_with("http/1.1 308 "));
+
+        // Dot segments could escape the exempt path
+        let response = request(ADDR, "/.well-known/acme-challenge/../../admin", "localhost").await;
+        assert!(response.starts_with("http/1.1 308 "));
+
+        let response = request(ADDR, "/.well-known/%2E%2e/admin", "localhost").await;
+        assert!(response.starts_with("http/1.1 308 "));
+
+        let response = request(ADDR, "/.well-known/./test", "localhost").await;
+        assert!(response.starts_with("http/1.1 308 "));
+
+        let response = request(ADDR, "/.well-known/..test", "localhost").await;
+        assert!(response.starts_with("http/1.1 200 "));
+
+        // Encoded slashes are decoded before matching
+        let response = request(ADDR, "/.well-known%2ftest", "localhost").await;
+        assert!(response.starts_with("http/1.1 200 "));
+
+        let response = request(ADDR, "/%68ealth", "localhost").await;
+        assert!(response.starts_with("http/1.1 200 "));
+
+        let response = request(ADDR, "/health%2fcheck", "localhost").await;
+        assert!(response.starts_with("http/1.1 308 "));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let conf = TlsRedirectorConf {
+            redirect_to: "example.com".to_owned(),
+            ..Default::default()
+        };
+        assert!(Redirect::from_conf(&conf, None).is_ok());
+
+        assert!(Redirect::from_conf(&TlsRedirectorConf::default(), None).is_err());
+        assert!(Redirect::from_conf(
+            &TlsRedirectorConf {
+                keep_host: true,
+                ..Default::default()
+            },
+            None
+        )
+        .is_ok());
+        assert!(Redirect::from_conf(
+            &TlsRedirectorConf {
+                status: Some(200),
+                ..conf.clone()
+            },
+            None
+        )
+        .is_err());
+        assert!(Redirect::from_conf(
+            &TlsRedirectorConf {
+                exempt: vec!["health".to_owned()].into(),
+                ..conf
+            },
+            None
+        )
+        .is_err());
+    }
 }
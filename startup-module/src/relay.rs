@@ -0,0 +1,467 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relaying connections to internal listeners
+//!
+//! Pingora cannot process data before the TLS handshake or HTTP parsing, and it only switches to
+//! HTTP/2 if it was negotiated via ALPN. So connections on listeners with PROXY protocol or
+//! cleartext HTTP/2 enabled are accepted here and relayed to internal loopback listeners handled
+//! by Pingora. The address of the relaying connection is mapped to the original client address,
+//! so that it can be restored for the session. Connections to the internal listeners that don’t
+//! come from the relay are rejected.
+//!
+//! Cleartext HTTP/2 connections are recognized by the HTTP/2 connection preface (prior knowledge,
+//! no `Upgrade: h2c`). These are relayed via TLS to an internal listener which negotiates HTTP/2
+//! and uses a generated self-signed certificate. All other connections are relayed to an internal
+//! listener without TLS.
+//!
+//! Handling this in a custom server app instead isn’t possible: that app would need to pass
+//! connections on to Pingora’s `HttpProxy`, which can only be created inside a `Service` that
+//! doesn’t expose it.
+
+use log::{debug, error, info};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::listeners::{TlsAccept, TlsSettings, ALPN};
+use pingora::server::{ListenFds, ShutdownWatch};
+use pingora::tls::tokio_ssl::SslStream;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::os::unix::io::IntoRawFd;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{
+    copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::acme::{generate_key, self_signed_certificate};
+use crate::configuration::ListenAddr;
+use crate::listener::{take_bound_early, TcpOptions};
+use crate::proxy_protocol::{read_header, IpNetwork};
+
+/// Maximal time to wait for the PROXY header or the HTTP/2 connection preface
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection preface sent by HTTP/2 clients
+const H2_PREFACE: &[u8; 24] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Client addresses by the address of the relaying connection
+static CLIENT_ADDRS: Mutex<BTreeMap<SocketAddr, SocketAddr>> = Mutex::new(BTreeMap::new());
+
+/// Internal listener addresses by the public listen address they relay connections from
+static INTERNAL_ADDRS: Mutex<Vec<(String, SocketAddr)>> = Mutex::new(Vec::new());
+
+/// Checks whether a connection was accepted on an internal listener without being relayed
+///
+/// Any local process can connect to the internal listeners directly, bypassing the
+/// `trusted_proxies` check. Only connections established by the relay should be accepted.
+pub(crate) fn is_unrelayed(local_addr: &SocketAddr, peer_addr: &SocketAddr) -> bool {
+    let internal = INTERNAL_ADDRS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(_, addr)| addr == local_addr);
+    internal && !CLIENT_ADDRS.lock().unwrap().contains_key(peer_addr)
+}
+
+/// Looks up the internal listener address for a public listen address
+#[cfg(test)]
+pub(crate) fn internal_addr_for(addr: &str) -> Option<SocketAddr> {
+    INTERNAL_ADDRS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(public, _)| public == addr)
+        .map(|(_, internal)| *internal)
+}
+
+/// Looks up the original client address for a relayed connection
+pub(crate) fn relayed_client_addr(addr: &SocketAddr) -> Option<SocketAddr> {
+    if !addr.ip().is_loopback() {
+        return None;
+    }
+    CLIENT_ADDRS.lock().unwrap().get(addr).copied()
+}
+
+/// Keeps the client address registered while the connection is being relayed
+#[derive(Debug)]
+struct Registration(SocketAddr);
+
+impl Registration {
+    fn new(relay_addr: SocketAddr, client_addr: SocketAddr) -> Self {
+        CLIENT_ADDRS.lock().unwrap().insert(relay_addr, client_addr);
+        Self(relay_addr)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        CLIENT_ADDRS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Certificate callbacks for the internal HTTP/2 listener, the certificate is set up front
+struct InternalCertificate;
+
+impl TlsAccept for InternalCertificate {}
+
+/// Binds an internal loopback listener on a random port
+fn bind_internal() -> Result<(StdTcpListener, SocketAddr), Box<Error>> {
+    let internal = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0)).map_err(|err| {
+        Error::because(
+            ErrorType::BindError,
+            "failed binding internal listener",
+            err,
+        )
+    })?;
+    internal.set_nonblocking(true).map_err(|err| {
+        Error::because(
+            ErrorType::BindError,
+            "failed setting up internal listener",
+            err,
+        )
+    })?;
+    let internal_addr = internal.local_addr().map_err(|err| {
+        Error::because(
+            ErrorType::BindError,
+            "failed determining internal listener address",
+            err,
+        )
+    })?;
+    Ok((internal, internal_addr))
+}
+
+/// Internal listener receiving relayed cleartext HTTP/2 connections via TLS
+#[derive(Debug)]
+struct H2cRelay {
+    internal: Option<StdTcpListener>,
+    internal_addr: SocketAddr,
+    connector: SslConnector,
+}
+
+impl H2cRelay {
+    fn new() -> Result<Self, Box<Error>> {
+        let crypto_error = |err| {
+            Error::because(
+                ErrorType::InternalError,
+                "failed setting up TLS for cleartext HTTP/2",
+                err,
+            )
+        };
+
+        let (internal, internal_addr) = bind_internal()?;
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).map_err(crypto_error)?;
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(b"\x02h2").map_err(crypto_error)?;
+
+        Ok(Self {
+            internal: Some(internal),
+            internal_addr,
+            connector: connector.build(),
+        })
+    }
+
+    fn tls_settings() -> Result<TlsSettings, Box<Error>> {
+        let crypto_error = |err| {
+            Error::because(
+                ErrorType::InternalError,
+                "failed setting up TLS for cleartext HTTP/2",
+                err,
+            )
+        };
+
+        // The certificate is never verified, it merely has to exist
+        let key = generate_key().map_err(crypto_error)?;
+        let cert = self_signed_certificate("localhost", &key, 3650, None).map_err(crypto_error)?;
+
+        let mut tls_settings = TlsSettings::with_callbacks(Box::new(InternalCertificate))?;
+        tls_settings.set_certificate(&cert).map_err(crypto_error)?;
+        tls_settings.set_private_key(&key).map_err(crypto_error)?;
+        tls_settings.set_alpn(ALPN::H2);
+        Ok(tls_settings)
+    }
+}
+
+/// Listener accepting connections and relaying them to Pingora’s internal listeners
+#[derive(Debug)]
+pub(crate) struct RelayListener {
+    addr: String,
+    options: TcpOptions,
+    trusted: Option<Vec<IpNetwork>>,
+    internal: Option<StdTcpListener>,
+    internal_addr: SocketAddr,
+    h2c: Option<H2cRelay>,
+}
+
+impl RelayListener {
+    /// Reserves the internal addresses that Pingora should listen on for this listen address
+    ///
+    /// Returns `None` if the connections can be handled by Pingora directly.
+    pub(crate) fn new(addr: &ListenAddr) -> Result<Option<Self>, Box<Error>> {
+        let h2c = addr.http2 && !addr.tls;
+        if !addr.proxy_protocol && !h2c {
+            return Ok(None);
+        }
+
+        if addr.unix_path().is_some() {
+            let setting = if addr.proxy_protocol {
+                "`proxy_protocol`"
+            } else {
+                "`http2` without TLS"
+            };
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "{setting} setting for listen address {} is only supported for TCP",
+                    addr.addr
+                ),
+            ));
+        }
+
+        if addr.proxy_protocol && addr.trusted_proxies.is_empty() {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "`proxy_protocol` setting for listen address {} requires `trusted_proxies`",
+                    addr.addr
+                ),
+            ));
+        }
+
+        let (internal, internal_addr) = bind_internal()?;
+        let h2c = h2c.then(H2cRelay::new).transpose()?;
+
+        let mut internal_addrs = INTERNAL_ADDRS.lock().unwrap();
+        internal_addrs.push((addr.addr.clone(), internal_addr));
+        if let Some(h2c) = &h2c {
+            internal_addrs.push((addr.addr.clone(), h2c.internal_addr));
+        }
+
+        Ok(Some(Self {
+            addr: addr.addr.clone(),
+            options: TcpOptions {
+                reuse_port: true,
+                ..TcpOptions::from_addr(addr)
+            },
+            trusted: addr.proxy_protocol.then(|| addr.trusted_proxies.clone()),
+            internal: Some(internal),
+            internal_addr,
+            h2c,
+        }))
+    }
+
+    /// Internal address Pingora should listen on for regular connections
+    pub(crate) fn internal_addr(&self) -> String {
+        self.internal_addr.to_string()
+    }
+
+    /// Internal address and TLS settings Pingora should listen on for cleartext HTTP/2 connections
+    pub(crate) fn h2c_endpoint(&self) -> Result<Option<(String, TlsSettings)>, Box<Error>> {
+        self.h2c
+            .as_ref()
+            .map(|h2c| Ok((h2c.internal_addr.to_string(), H2cRelay::tls_settings()?)))
+            .transpose()
+    }
+
+    /// Sets up the listening sockets and starts accepting connections in background
+    ///
+    /// The internal sockets are placed in Pingora’s file descriptor table. The public socket is
+    /// bound with `SO_REUSEPORT` instead, so that the new process can bind it as well during a
+    /// graceful upgrade.
+    pub(crate) async fn start(&mut self, fds: Option<&ListenFds>, shutdown: ShutdownWatch) {
+        // Without a file descriptor table the sockets are dropped, Pingora will bind them again
+        let mut internal = vec![(self.internal_addr, self.internal.take())];
+        if let Some(h2c) = &mut self.h2c {
+            internal.push((h2c.internal_addr, h2c.internal.take()));
+        }
+        if let Some(fds) = fds {
+            let mut fds = fds.lock().await;
+            for (addr, listener) in internal {
+                if let Some(listener) = listener {
+                    fds.add(addr.to_string(), listener.into_raw_fd());
+                }
+            }
+        }
+
+        let listener = match take_bound_early(&self.addr)
+            .map(|fd| Ok(StdTcpListener::from(fd)))
+            .unwrap_or_else(|| self.options.bind(&self.addr))
+            .and_then(TcpListener::from_std)
+        {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Failed setting up relaying listener {}: {err}", self.addr);
+                return;
+            }
+        };
+
+        info!(
+            "Accepting connections on {}, relaying to {}",
+            self.addr, self.internal_addr
+        );
+        tokio::spawn(accept_loop(
+            listener,
+            Target {
+                trusted: self.trusted.clone(),
+                internal_addr: self.internal_addr,
+                h2c: self
+                    .h2c
+                    .as_ref()
+                    .map(|h2c| (h2c.internal_addr, h2c.connector.clone())),
+            },
+            shutdown,
+        ));
+    }
+}
+
+/// Settings determining where connections should be relayed to
+#[derive(Debug, Clone)]
+struct Target {
+    trusted: Option<Vec<IpNetwork>>,
+    internal_addr: SocketAddr,
+    h2c: Option<(SocketAddr, SslConnector)>,
+}
+
+async fn accept_loop(listener: TcpListener, target: Target, mut shutdown: ShutdownWatch) {
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(result) => result,
+                Err(err) => {
+                    error!("Accepting connection to relay failed: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+
+        if let Some(trusted) = &target.trusted {
+            if !trusted.iter().any(|net| net.contains(&peer_addr.ip())) {
+                debug!("Rejecting PROXY protocol connection from untrusted address {peer_addr}");
+                continue;
+            }
+        }
+
+        let target = target.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay(stream, peer_addr, target).await {
+                debug!("Relaying connection from {peer_addr} failed: {err}");
+            }
+        });
+    }
+}
+
+/// Reads the start of the connection, as long as it matches the HTTP/2 connection preface
+async fn read_preface<R>(reader: &mut R) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    while data.len() < H2_PREFACE.len() && H2_PREFACE.starts_with(&data) {
+        let mut buf = [0; H2_PREFACE.len()];
+        let len = reader
+            .read(&mut buf[..H2_PREFACE.len() - data.len()])
+            .await?;
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+    Ok(data)
+}
+
+async fn forward<S, U>(stream: &mut S, upstream: &mut U, data: &[u8]) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    upstream.write_all(data).await?;
+    copy_bidirectional(stream, upstream).await?;
+    Ok(())
+}
+
+async fn relay(stream: TcpStream, peer_addr: SocketAddr, target: Target) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let client_addr = if target.trusted.is_some() {
+        tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "no PROXY protocol header"))??
+            .unwrap_or(peer_addr)
+    } else {
+        peer_addr
+    };
+
+    let (data, h2c) = if let Some(h2c) = target.h2c {
+        let data = tokio::time::timeout(HEADER_TIMEOUT, read_preface(&mut stream))
+            .await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "no request received"))??;
+        let h2c = (data == H2_PREFACE).then_some(h2c);
+        (data, h2c)
+    } else {
+        (Vec::new(), None)
+    };
+
+    let socket = TcpSocket::new_v4()?;
+    socket.bind((Ipv4Addr::LOCALHOST, 0).into())?;
+    let _registration = Registration::new(socket.local_addr()?, client_addr);
+    if let Some((internal_addr, connector)) = h2c {
+        let upstream = socket.connect(internal_addr).await?;
+        let ssl = connector.configure()?.into_ssl("localhost")?;
+        let mut upstream = SslStream::new(ssl, upstream)?;
+        Pin::new(&mut upstream)
+            .connect()
+            .await
+            .map_err(std::io::Error::other)?;
+        forward(&mut stream, &mut upstream, &data).await
+    } else {
+        let mut upstream = socket.connect(target.internal_addr).await?;
+        forward(&mut stream, &mut upstream, &data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test(tokio::test)]
+    async fn preface() {
+        let mut data = &b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\x12\x04"[..];
+        assert_eq!(read_preface(&mut data).await.unwrap(), H2_PREFACE);
+        assert_eq!(data, b"\0\0\x12\x04");
+
+        let mut data = &b"POST / HTTP/1.1\r\n\r\n"[..];
+        assert_eq!(
+            read_preface(&mut data).await.unwrap(),
+            b"POST / HTTP/1.1\r\n\r\n"
+        );
+
+        // Data is read as long as it could be the preface
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        let read = tokio::spawn(async move { read_preface(&mut server).await.unwrap() });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.write_all(b"\r\nGET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(read.await.unwrap(), b"PRI * HTTP/2.0\r\n\r\nGET / ");
+
+        let mut data = &b"PRI"[..];
+        assert_eq!(read_preface(&mut data).await.unwrap(), b"PRI");
+    }
+}
@@ -0,0 +1,451 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! systemd integration: socket activation and readiness notifications
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockType, SockaddrStorage};
+use nix::time::{clock_gettime, ClockId};
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pandora_module_utils::OneOrMany;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::configuration::ListenAddr;
+
+/// File descriptor of the first socket passed on by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Address prefix referring to sockets passed on by systemd
+const SYSTEMD_PREFIX: &str = "systemd";
+
+/// Listening socket passed on by systemd
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InheritedSocket {
+    fd: RawFd,
+    name: String,
+    /// Socket address in the format used by [`ListenAddr::addr`]
+    addr: String,
+}
+
+impl InheritedSocket {
+    fn from_fd(fd: RawFd, name: String) -> Option<Self> {
+        // Sockets are passed on in blocking mode but Pingora expects non-blocking sockets
+        let flags = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .and_then(|_| fcntl(fd, FcntlArg::F_GETFL))
+            .and_then(|flags| {
+                let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+                fcntl(fd, FcntlArg::F_SETFL(flags))
+            });
+        if let Err(err) = flags {
+            warn!("Ignoring file descriptor {fd} passed on by systemd: {err}");
+            return None;
+        }
+
+        let listening = getsockopt(fd, sockopt::AcceptConn).unwrap_or(false);
+        let stream = getsockopt(fd, sockopt::SockType).ok() == Some(SockType::Stream);
+        if !listening || !stream {
+            warn!(
+                "Ignoring file descriptor {fd} passed on by systemd, not a listening stream socket"
+            );
+            return None;
+        }
+
+        let addr = match getsockname::<SockaddrStorage>(fd) {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn!("Ignoring file descriptor {fd} passed on by systemd: {err}");
+                return None;
+            }
+        };
+        let addr = if let Some(addr) = addr.as_sockaddr_in() {
+            addr.to_string()
+        } else if let Some(addr) = addr.as_sockaddr_in6() {
+            addr.to_string()
+        } else if let Some(path) = addr.as_unix_addr().and_then(|addr| addr.path()) {
+            format!("unix:{}", path.display())
+        } else {
+            warn!("Ignoring file descriptor {fd} passed on by systemd, unsupported address type");
+            return None;
+        };
+
+        Some(Self { fd, name, addr })
+    }
+}
+
+/// Reads the sockets passed on by systemd from the environment variables
+fn read_inherited_sockets() -> Vec<InheritedSocket> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let Some(count) = count.and_then(|count| count.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    let names: Vec<_> = names
+        .as_deref()
+        .unwrap_or("")
+        .split(':')
+        .map(ToOwned::to_owned)
+        .collect();
+
+    let sockets: Vec<_> = (0..count)
+        .filter_map(|index| {
+            let name = names
+                .get(index as usize)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_owned());
+            InheritedSocket::from_fd(LISTEN_FDS_START + index, name)
+        })
+        .collect();
+    for socket in &sockets {
+        debug!(
+            "Inherited socket {} ({}) from systemd as file descriptor {}",
+            socket.addr, socket.name, socket.fd
+        );
+    }
+    sockets
+}
+
+/// Sockets passed on by systemd
+static SOCKETS: OnceLock<Vec<InheritedSocket>> = OnceLock::new();
+
+/// Takes over the listening sockets passed on by systemd
+///
+/// The `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` environment variables are removed
+/// afterwards, so that child processes won’t consider them. Modifying the environment isn’t
+/// thread-safe, so this has to be called at the start of `main` before any threads are started.
+/// Without this call, the environment variables are read when the sockets are first needed and
+/// left in place.
+pub fn inherit_systemd_sockets() {
+    SOCKETS.get_or_init(read_inherited_sockets);
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+}
+
+fn inherited_sockets() -> &'static [InheritedSocket] {
+    SOCKETS.get_or_init(read_inherited_sockets)
+}
+
+fn expand(
+    listen: &OneOrMany<ListenAddr>,
+    sockets: &[InheritedSocket],
+) -> Result<OneOrMany<ListenAddr>, Box<Error>> {
+    let mut result = Vec::new();
+    for addr in listen {
+        let name = match addr.addr.strip_prefix(SYSTEMD_PREFIX) {
+            Some("") => None,
+            Some(name) if name.starts_with(':') => name.get(1..),
+            _ => {
+                result.push(addr.clone());
+                continue;
+            }
+        };
+
+        if addr.is_relayed() {
+            let setting = if addr.proxy_protocol {
+                "`proxy_protocol`"
+            } else {
+                "`http2` without TLS"
+            };
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "{setting} setting is not supported for sockets passed on by systemd ({})",
+                    addr.addr
+                ),
+            ));
+        }
+
+        let mut matched = false;
+        for socket in sockets {
+            if name.map_or(true, |name| name == socket.name) {
+                matched = true;
+
+                // A socket can only be used once, first match determines the settings
+                if result
+                    .iter()
+                    .all(|addr: &ListenAddr| addr.addr != socket.addr)
+                {
+                    result.push(ListenAddr {
+                        addr: socket.addr.clone(),
+                        ..addr.clone()
+                    });
+                }
+            }
+        }
+        if !matched {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!(
+                    "listen address {} doesn’t match any sockets passed on by systemd",
+                    addr.addr
+                ),
+            ));
+        }
+    }
+    Ok(result.into())
+}
+
+/// Replaces `systemd` and `systemd:<name>` listen addresses by the addresses of the sockets
+/// passed on by systemd
+pub(crate) fn expand_listen(
+    listen: &OneOrMany<ListenAddr>,
+) -> Result<OneOrMany<ListenAddr>, Box<Error>> {
+    expand(listen, inherited_sockets())
+}
+
+/// Returns listen addresses for all sockets passed on by systemd
+pub(crate) fn inherited_listen() -> OneOrMany<ListenAddr> {
+    inherited_sockets()
+        .iter()
+        .map(|socket| socket.addr.as_str().into())
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Returns the file descriptor of a socket passed on by systemd for a listen address if any
+pub(crate) fn inherited_fd(addr: &str) -> Option<RawFd> {
+    inherited_sockets()
+        .iter()
+        .find(|socket| socket.addr == addr)
+        .map(|socket| socket.fd)
+}
+
+fn notify_to(socket: &str, state: &str) -> std::io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        sender.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    sender.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// Sends a state notification to systemd if the server is running as a `Type=notify` service
+pub(crate) fn notify(state: &str) {
+    if let Ok(socket) = std::env::var("NOTIFY_SOCKET") {
+        if let Err(err) = notify_to(&socket, state) {
+            warn!("Failed notifying systemd: {err}");
+        }
+    }
+}
+
+/// Notifies systemd that configuration is being reloaded
+pub(crate) fn notify_reloading() {
+    let usec = clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map(|time| time.tv_sec() * 1_000_000 + time.tv_nsec() / 1_000)
+        .unwrap_or(0);
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+}
+
+/// Notifies systemd that the server is ready, to be called once the listening sockets are set up
+pub(crate) fn notify_ready() {
+    // MAINPID is required for the process to be accepted after a daemonizing fork or upgrade
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// Checks whether the server is running as a `Type=notify` service
+pub(crate) fn notify_enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Background service acknowledging reloads and notifying systemd when the server is shutting
+/// down
+#[derive(Debug)]
+pub(crate) struct SystemdNotifier {
+    /// If `true`, reloads triggered by SIGHUP are acknowledged right away as there is nothing to
+    /// reload.
+    pub(crate) acknowledge_reload: bool,
+}
+
+#[async_trait]
+impl BackgroundService for SystemdNotifier {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = if self.acknowledge_reload {
+            signal(SignalKind::hangup())
+                .map_err(|err| warn!("Failed listening to SIGHUP: {err}"))
+                .ok()
+        } else {
+            None
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                Some(_) = async { hangup.as_mut()?.recv().await } => {
+                    notify_reloading();
+                    notify("READY=1");
+                }
+            }
+        }
+
+        notify("STOPPING=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixListener;
+    use test_log::test;
+
+    fn sockets() -> (TcpListener, UnixListener, Vec<InheritedSocket>) {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "startup-module-systemd-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+
+        let sockets = vec![
+            InheritedSocket::from_fd(tcp.as_raw_fd(), "http".to_owned()).unwrap(),
+            InheritedSocket::from_fd(unix.as_raw_fd(), "local".to_owned()).unwrap(),
+        ];
+        assert_eq!(sockets[0].addr, tcp.local_addr().unwrap().to_string());
+        assert_eq!(sockets[1].addr, format!("unix:{}", path.display()));
+        (tcp, unix, sockets)
+    }
+
+    #[test]
+    fn inherited_socket() {
+        let (_tcp, _unix, sockets) = sockets();
+
+        let listen = expand(
+            &vec![
+                "127.0.0.1:8080".into(),
+                ListenAddr {
+                    addr: "systemd:http".to_owned(),
+                    tls: true,
+                    http2: true,
+                    ..Default::default()
+                },
+            ]
+            .into(),
+            &sockets,
+        )
+        .unwrap();
+        assert_eq!(
+            listen,
+            vec![
+                "127.0.0.1:8080".into(),
+                ListenAddr {
+                    addr: sockets[0].addr.clone(),
+                    tls: true,
+                    http2: true,
+                    ..Default::default()
+                },
+            ]
+            .into()
+        );
+
+        let listen = expand(&vec!["systemd".into()].into(), &sockets).unwrap();
+        assert_eq!(
+            listen,
+            vec![
+                sockets[0].addr.as_str().into(),
+                sockets[1].addr.as_str().into()
+            ]
+            .into()
+        );
+
+        let listen = expand(
+            &vec![
+                ListenAddr {
+                    addr: "systemd:http".to_owned(),
+                    tls: true,
+                    http2: true,
+                    ..Default::default()
+                },
+                "systemd".into(),
+            ]
+            .into(),
+            &sockets,
+        )
+        .unwrap();
+        assert_eq!(
+            listen,
+            vec![
+                ListenAddr {
+                    addr: sockets[0].addr.clone(),
+                    tls: true,
+                    http2: true,
+                    ..Default::default()
+                },
+                sockets[1].addr.as_str().into()
+            ]
+            .into()
+        );
+
+        assert!(expand(&vec!["systemd:https".into()].into(), &sockets).is_err());
+        assert!(expand(&vec!["systemdfoo".into()].into(), &sockets).is_ok());
+        assert!(expand(
+            &vec![ListenAddr {
+                addr: "systemd:http".to_owned(),
+                proxy_protocol: true,
+                ..Default::default()
+            }]
+            .into(),
+            &sockets
+        )
+        .is_err());
+        assert!(expand(
+            &vec![ListenAddr {
+                addr: "systemd:http".to_owned(),
+                http2: true,
+                ..Default::default()
+            }]
+            .into(),
+            &sockets
+        )
+        .is_err());
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(
+            InheritedSocket::from_fd(udp.as_raw_fd(), "udp".to_owned()),
+            None
+        );
+    }
+
+    #[test]
+    fn notification() {
+        let path =
+            std::env::temp_dir().join(format!("startup-module-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
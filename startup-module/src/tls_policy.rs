@@ -0,0 +1,109 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS protocol version and cipher settings
+//!
+//! Session ticket keys aren’t configurable: OpenSSL’s `SSL_CTX_set_tlsext_ticket_key_evp_cb` and
+//! `SSL_CTX_set_tlsext_ticket_keys` have no safe wrappers in the `openssl` crate.
+
+use pingora::tls::error::ErrorStack;
+use pingora::tls::ssl::{SslContextBuilder, SslMethod, SslRef, SslVersion};
+
+use crate::configuration::TlsVersion;
+
+impl TlsVersion {
+    fn to_ssl_version(self) -> SslVersion {
+        match self {
+            Self::Tls1_0 => SslVersion::TLS1,
+            Self::Tls1_1 => SslVersion::TLS1_1,
+            Self::Tls1_2 => SslVersion::TLS1_2,
+            Self::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+
+    fn from_ssl_version(version: SslVersion) -> Option<Self> {
+        [Self::Tls1_0, Self::Tls1_1, Self::Tls1_2, Self::Tls1_3]
+            .into_iter()
+            .find(|v| v.to_ssl_version() == version)
+    }
+}
+
+/// Protocol settings for a server name
+#[derive(Debug, Clone)]
+pub(crate) struct TlsPolicy {
+    pub(crate) min_version: TlsVersion,
+    pub(crate) max_version: TlsVersion,
+    pub(crate) cipher_list: Option<String>,
+}
+
+impl TlsPolicy {
+    /// Makes sure that OpenSSL accepts the cipher settings.
+    pub(crate) fn validate(&self) -> Result<(), ErrorStack> {
+        if let Some(cipher_list) = &self.cipher_list {
+            SslContextBuilder::new(SslMethod::tls())?.set_cipher_list(cipher_list)?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the negotiated protocol version is allowed.
+    ///
+    /// The protocol version has already been negotiated by the time the server name is known, so
+    /// all the settings can do is rejecting the connection.
+    pub(crate) fn allows_version(&self, ssl: &SslRef) -> bool {
+        ssl.version2()
+            .and_then(TlsVersion::from_ssl_version)
+            .is_some_and(|version| version >= self.min_version && version <= self.max_version)
+    }
+
+    /// Restricts the TLS 1.2 ciphers the connection can use.
+    pub(crate) fn apply(&self, ssl: &mut SslRef) -> Result<(), ErrorStack> {
+        if let Some(cipher_list) = &self.cipher_list {
+            ssl.set_cipher_list(cipher_list)?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings that have to be applied to the TLS acceptor as a whole
+///
+/// OpenSSL chooses protocol version, TLS 1.3 cipher suite and key exchange group before the
+/// server name is processed, so these cannot be changed per server name.
+#[derive(Debug, Clone)]
+pub(crate) struct AcceptorPolicy {
+    pub(crate) min_version: TlsVersion,
+    pub(crate) max_version: TlsVersion,
+    pub(crate) cipher_suites: Option<String>,
+    pub(crate) groups: Option<String>,
+}
+
+impl AcceptorPolicy {
+    /// Makes sure that OpenSSL accepts the cipher suites and key exchange groups.
+    pub(crate) fn validate(&self) -> Result<(), ErrorStack> {
+        let mut context = SslContextBuilder::new(SslMethod::tls())?;
+        self.apply(&mut context)
+    }
+
+    /// Applies the settings to the TLS acceptor of a listener.
+    pub(crate) fn apply(&self, context: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+        context.set_min_proto_version(Some(self.min_version.to_ssl_version()))?;
+        context.set_max_proto_version(Some(self.max_version.to_ssl_version()))?;
+        if let Some(cipher_suites) = &self.cipher_suites {
+            context.set_ciphersuites(cipher_suites)?;
+        }
+        if let Some(groups) = &self.groups {
+            context.set_groups_list(groups)?;
+        }
+        Ok(())
+    }
+}
@@ -21,6 +21,15 @@ use std::path::PathBuf;
 
 use crate::compression_algorithm::CompressionAlgorithm;
 
+fn validate_index_file(index_file: &OneOrMany<String>) -> Result<(), String> {
+    for name in index_file {
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(format!("`{name}` isn't a valid file name"));
+        }
+    }
+    Ok(())
+}
+
 /// Command line options of the static files module
 #[derive(Debug, Default, Parser)]
 pub struct StaticFilesOpt {
@@ -58,6 +67,7 @@ pub struct StaticFilesConf {
     pub canonicalize_uri: bool,
 
     /// List of index files to look for in a directory.
+    #[pandora(validate = "validate_index_file")]
     pub index_file: OneOrMany<String>,
 
     /// URI path of the page to display instead of the default Not Found page, e.g. /404.html
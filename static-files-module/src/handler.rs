@@ -17,6 +17,7 @@
 use async_trait::async_trait;
 use http::{method::Method, status::StatusCode};
 use log::{debug, info, warn};
+use pandora_module_utils::chroot::chroot_path;
 use pandora_module_utils::pingora::{Error, ErrorType, SessionWrapper};
 use pandora_module_utils::standard_response::{error_response, redirect_response};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
@@ -49,7 +50,7 @@ impl RequestFilter for StaticFilesHandler {
         _ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
         let root = if let Some(root) = self.conf.root.as_ref() {
-            root
+            chroot_path(root)
         } else {
             debug!("received request but static files handler is not configured, ignoring");
             return Ok(RequestFilterResult::Unhandled);
@@ -58,14 +59,14 @@ impl RequestFilter for StaticFilesHandler {
         let uri = session.uri();
         debug!("received URI path {}", uri.path());
 
-        let (mut path, not_found) = match resolve_uri(uri.path(), root) {
+        let (mut path, not_found) = match resolve_uri(uri.path(), &root) {
             Ok(path) => (path, false),
             Err(err) if err.kind() == ErrorKind::NotFound => {
                 debug!("canonicalizing resulted in NotFound error");
 
                 let path = self.conf.page_404.as_ref().and_then(|page_404| {
                     debug!("error page is {page_404}");
-                    match resolve_uri(page_404, root) {
+                    match resolve_uri(page_404, &root) {
                         Ok(path) => Some(path),
                         Err(err) => {
                             warn!("Failed resolving error page {page_404}: {err}");
@@ -108,7 +109,7 @@ impl RequestFilter for StaticFilesHandler {
         debug!("translated into file path {path:?}");
 
         if self.conf.canonicalize_uri && !not_found {
-            if let Some(mut canonical) = path_to_uri(&path, root) {
+            if let Some(mut canonical) = path_to_uri(&path, &root) {
                 if canonical != uri.path() {
                     if let Some(query) = uri.query() {
                         canonical.push('?');
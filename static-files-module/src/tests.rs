@@ -156,6 +156,12 @@ async fn text_file() -> Result<(), Box<Error>> {
     Ok(())
 }
 
+#[test]
+fn dir_index_invalid() {
+    StaticFilesConf::from_yaml(extended_conf("index_file: [index.html, ../index.html]"))
+        .expect_err("index file with path separators should be rejected");
+}
+
 #[test(tokio::test)]
 async fn dir_index() -> Result<(), Box<Error>> {
     let meta = Metadata::from_path(&root_path("index.html"), None).unwrap();
@@ -0,0 +1,823 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upstream server selection
+
+use http::header;
+use http::uri::{Scheme, Uri};
+use log::error;
+use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, SessionWrapper};
+use pingora_ketama::{Bucket, Continuum};
+use rand::Rng;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::breaker::CircuitBreaker;
+use crate::configuration::{
+    CircuitBreakerConf, HashKey, LoadBalancing, UpstreamConf, UpstreamServer,
+};
+
+/// An upstream server with its address resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Upstream {
+    pub(crate) addr: SocketAddr,
+    pub(crate) tls: bool,
+    pub(crate) sni: String,
+    pub(crate) host_port: String,
+    path: String,
+    weight: u32,
+}
+
+impl Upstream {
+    fn resolve(server: &UpstreamServer) -> Result<Self, Box<Error>> {
+        let upstream = &server.url;
+        let scheme = upstream.scheme().ok_or_else(|| {
+            error!("provided upstream URL has no scheme: {upstream}");
+            Error::new(ErrorType::InternalError)
+        })?;
+
+        let tls = if scheme == &Scheme::HTTP {
+            false
+        } else if scheme == &Scheme::HTTPS {
+            true
+        } else {
+            error!("provided upstream URL is neither HTTP nor HTTPS: {upstream}");
+            return Err(Error::new(ErrorType::InternalError));
+        };
+
+        let host = upstream.host().ok_or_else(|| {
+            error!("provided upstream URL has no host name: {upstream}");
+            Error::new(ErrorType::InternalError)
+        })?;
+
+        let port = upstream.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|err| {
+                error!("failed resolving upstream host name {host}: {err}");
+                Error::new(ErrorType::InternalError)
+            })?
+            .next()
+            .ok_or_else(|| {
+                error!("DNS lookup of upstream host name {host} didn't produce any results");
+                Error::new(ErrorType::InternalError)
+            })?;
+
+        let mut host_port = host.to_owned();
+        if let Some(port) = upstream.port() {
+            host_port.push(':');
+            host_port.push_str(port.as_str());
+        }
+
+        Ok(Self {
+            addr,
+            tls,
+            sni: host.to_owned(),
+            host_port,
+            path: upstream.path().trim_end_matches('/').to_owned(),
+            weight: server.weight,
+        })
+    }
+
+    /// Prepends the path of the upstream URL to the request URI
+    pub(crate) fn map_uri(&self, uri: &Uri) -> Uri {
+        if self.path.is_empty() {
+            return uri.clone();
+        }
+
+        let mut parts = uri.clone().into_parts();
+        let mut path_and_query = self.path.clone();
+        match &parts.path_and_query {
+            Some(original) if original.as_str().starts_with('/') => {
+                path_and_query.push_str(original.as_str())
+            }
+            Some(_) => return uri.clone(),
+            None => path_and_query.push('/'),
+        }
+        parts.path_and_query = path_and_query.parse().ok();
+        parts.try_into().unwrap_or_else(|_| uri.clone())
+    }
+
+    /// Creates a peer to connect to this server
+    fn to_peer(&self) -> Box<HttpPeer> {
+        Box::new(HttpPeer::new(self.addr, self.tls, self.sni.clone()))
+    }
+}
+
+/// Timeouts applying to connections to upstream servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Timeouts {
+    connection: Option<Duration>,
+    total_connection: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    fn new(conf: &UpstreamConf) -> Self {
+        Self {
+            connection: conf.connection_timeout.map(Duration::from_secs),
+            total_connection: conf.total_connection_timeout.map(Duration::from_secs),
+            read: conf.read_timeout.map(Duration::from_secs),
+            write: conf.write_timeout.map(Duration::from_secs),
+            idle: conf.idle_timeout.map(Duration::from_secs),
+        }
+    }
+
+    fn apply(&self, peer: &mut HttpPeer) {
+        peer.options.connection_timeout = self.connection;
+        peer.options.total_connection_timeout = self.total_connection;
+        peer.options.read_timeout = self.read;
+        peer.options.write_timeout = self.write;
+        peer.options.idle_timeout = self.idle;
+    }
+}
+
+/// Runtime state shared by all copies of a balancer
+#[derive(Debug)]
+struct State {
+    next: AtomicUsize,
+    active: Vec<AtomicUsize>,
+    healthy: Vec<AtomicBool>,
+    breakers: Option<Vec<Mutex<CircuitBreaker>>>,
+    current_weights: Mutex<Vec<i64>>,
+}
+
+/// An active request to an upstream server, counted until this is dropped
+#[derive(Debug)]
+pub(crate) struct ActiveRequest {
+    state: Arc<State>,
+    index: usize,
+}
+
+impl ActiveRequest {
+    /// Index of the upstream server handling the request
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.state.active[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Hash ring for consistent hashing
+struct Ring {
+    continuum: Continuum,
+    indices: HashMap<SocketAddr, usize>,
+}
+
+impl std::fmt::Debug for Ring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ring")
+            .field("indices", &self.indices)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Selects an upstream server for each request according to the configured strategy
+#[derive(Debug, Clone)]
+pub(crate) struct Balancer {
+    upstreams: Vec<Upstream>,
+    strategy: LoadBalancing,
+    hash_key: HashKey,
+    circuit_breaker: Option<CircuitBreakerConf>,
+    timeouts: Timeouts,
+    ring: Option<Arc<Ring>>,
+    state: Arc<State>,
+}
+
+impl PartialEq for Balancer {
+    fn eq(&self, other: &Self) -> bool {
+        self.upstreams == other.upstreams
+            && self.strategy == other.strategy
+            && self.hash_key == other.hash_key
+            && self.circuit_breaker == other.circuit_breaker
+            && self.timeouts == other.timeouts
+    }
+}
+
+impl Eq for Balancer {}
+
+impl Balancer {
+    /// Resolves the configured upstream servers, returns `None` if there are none.
+    pub(crate) fn new(conf: UpstreamConf) -> Result<Option<Self>, Box<Error>> {
+        if conf.upstream.is_empty() {
+            return Ok(None);
+        }
+
+        let upstreams = conf
+            .upstream
+            .iter()
+            .map(Upstream::resolve)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let ring = (conf.load_balancing == LoadBalancing::ConsistentHash).then(|| {
+            let buckets = upstreams
+                .iter()
+                .map(|upstream| Bucket::new(upstream.addr, upstream.weight))
+                .collect::<Vec<_>>();
+            let mut indices = HashMap::new();
+            for (index, upstream) in upstreams.iter().enumerate() {
+                indices.entry(upstream.addr).or_insert(index);
+            }
+            Arc::new(Ring {
+                continuum: Continuum::new(&buckets),
+                indices,
+            })
+        });
+
+        let state = Arc::new(State {
+            next: AtomicUsize::new(0),
+            active: upstreams.iter().map(|_| AtomicUsize::new(0)).collect(),
+            healthy: upstreams.iter().map(|_| AtomicBool::new(true)).collect(),
+            breakers: conf.circuit_breaker.as_ref().map(|conf| {
+                upstreams
+                    .iter()
+                    .map(|_| Mutex::new(CircuitBreaker::new(conf)))
+                    .collect()
+            }),
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+        });
+
+        let timeouts = Timeouts::new(&conf);
+        Ok(Some(Self {
+            upstreams,
+            strategy: conf.load_balancing,
+            hash_key: conf.hash_key,
+            circuit_breaker: conf.circuit_breaker,
+            timeouts,
+            ring,
+            state,
+        }))
+    }
+
+    /// Returns the upstream server with the given index
+    pub(crate) fn upstream(&self, index: usize) -> &Upstream {
+        &self.upstreams[index]
+    }
+
+    /// Creates a peer to connect to the upstream server with the given index
+    pub(crate) fn peer(&self, index: usize) -> Box<HttpPeer> {
+        let mut peer = self.upstreams[index].to_peer();
+        self.timeouts.apply(&mut peer);
+        peer
+    }
+
+    /// Counts an active request to the upstream server with the given index
+    pub(crate) fn acquire(&self, index: usize) -> ActiveRequest {
+        self.state.active[index].fetch_add(1, Ordering::Relaxed);
+        ActiveRequest {
+            state: self.state.clone(),
+            index,
+        }
+    }
+
+    /// Returns all upstream servers
+    pub(crate) fn upstreams(&self) -> &[Upstream] {
+        &self.upstreams
+    }
+
+    /// Marks the upstream server with the given index as healthy or unhealthy
+    pub(crate) fn set_healthy(&self, index: usize, healthy: bool) {
+        self.state.healthy[index].store(healthy, Ordering::Relaxed);
+    }
+
+    /// Checks whether the upstream server with the given index is considered healthy
+    pub(crate) fn is_healthy(&self, index: usize) -> bool {
+        self.state.healthy[index].load(Ordering::Relaxed)
+    }
+
+    /// Locks the circuit breaker of the upstream server with the given index if configured
+    fn breaker(&self, index: usize) -> Option<MutexGuard<'_, CircuitBreaker>> {
+        self.state.breakers.as_ref().map(|breakers| {
+            breakers[index]
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+        })
+    }
+
+    /// Records the outcome of a request to the upstream server with the given index
+    pub(crate) fn record(&self, index: usize, success: bool) {
+        if let Some(mut breaker) = self.breaker(index) {
+            let addr = &self.upstreams[index].addr;
+            if success {
+                breaker.record_success(addr);
+            } else {
+                breaker.record_failure(addr, Instant::now());
+            }
+        }
+    }
+
+    /// Checks whether the upstream server with the given index can receive requests, meaning that
+    /// it is healthy and its circuit breaker isn’t open
+    fn is_usable(&self, index: usize, now: Instant) -> bool {
+        self.is_healthy(index)
+            && !self
+                .breaker(index)
+                .is_some_and(|mut breaker| breaker.is_open(&self.upstreams[index].addr, now))
+    }
+
+    /// Chooses the upstream server for a request, returns its index
+    ///
+    /// Unhealthy servers and servers with an open circuit breaker are only chosen if no other
+    /// servers are left. The same is true for excluded servers, e.g. ones that already failed to
+    /// handle this request. Choosing a server with a half-open circuit breaker uses up one of its
+    /// trial requests.
+    pub(crate) fn select(&self, session: &impl SessionWrapper, excluded: &[usize]) -> usize {
+        if self.upstreams.len() == 1 {
+            return 0;
+        }
+
+        let now = Instant::now();
+        let mut usable = (0..self.upstreams.len())
+            .map(|index| !excluded.contains(&index) && self.is_usable(index, now))
+            .collect::<Vec<_>>();
+        while usable.contains(&true) {
+            let index = self.choose(session, &|index| usable[index]);
+
+            // Another request might have taken the last trial request of a half-open breaker
+            if self.breaker(index).map_or(true, |mut breaker| {
+                breaker.start_request(&self.upstreams[index].addr, now)
+            }) {
+                return index;
+            }
+            usable[index] = false;
+        }
+
+        let remaining = (0..self.upstreams.len())
+            .map(|index| !excluded.contains(&index))
+            .collect::<Vec<_>>();
+        let all = !remaining.contains(&true);
+        self.choose(session, &|index| all || remaining[index])
+    }
+
+    /// Chooses one of the eligible servers according to the configured strategy
+    fn choose(&self, session: &impl SessionWrapper, eligible: &impl Fn(usize) -> bool) -> usize {
+        match self.strategy {
+            LoadBalancing::RoundRobin => self.round_robin(eligible),
+            LoadBalancing::Weighted => self.weighted(eligible),
+            LoadBalancing::LeastConnections => self.least_connections(eligible),
+            LoadBalancing::RandomTwoChoices => self.random_two_choices(eligible),
+            LoadBalancing::ConsistentHash => self.consistent_hash(session, eligible),
+        }
+    }
+
+    /// Iterates over all server indices, starting with a different one each time
+    fn rotated(&self) -> impl Iterator<Item = usize> {
+        let len = self.upstreams.len();
+        let start = self.state.next.fetch_add(1, Ordering::Relaxed);
+        (0..len).map(move |offset| (start + offset) % len)
+    }
+
+    /// Compares the load of two servers, `true` if the first one is less loaded
+    fn less_loaded(&self, index1: usize, index2: usize) -> bool {
+        let load = |index: usize, other: usize| {
+            self.state.active[index].load(Ordering::Relaxed) as u64
+                * u64::from(self.upstreams[other].weight)
+        };
+        load(index1, index2) < load(index2, index1)
+    }
+
+    fn round_robin(&self, eligible: &impl Fn(usize) -> bool) -> usize {
+        self.rotated().find(|index| eligible(*index)).unwrap_or(0)
+    }
+
+    /// Smooth weighted round robin, spreading out the requests to servers with higher weight
+    fn weighted(&self, eligible: &impl Fn(usize) -> bool) -> usize {
+        let mut current = self
+            .state
+            .current_weights
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let mut total = 0;
+        let mut best = None;
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            if !eligible(index) {
+                continue;
+            }
+            current[index] += i64::from(upstream.weight);
+            total += i64::from(upstream.weight);
+            if best.map_or(true, |best| current[index] > current[best]) {
+                best = Some(index);
+            }
+        }
+
+        let best = best.unwrap_or(0);
+        current[best] -= total;
+        best
+    }
+
+    fn least_connections(&self, eligible: &impl Fn(usize) -> bool) -> usize {
+        // Start with a different server each time so that ties are resolved evenly
+        self.rotated()
+            .filter(|index| eligible(*index))
+            .reduce(|best, index| {
+                if self.less_loaded(index, best) {
+                    index
+                } else {
+                    best
+                }
+            })
+            .unwrap_or(0)
+    }
+
+    fn random_two_choices(&self, eligible: &impl Fn(usize) -> bool) -> usize {
+        let mut rng = rand::thread_rng();
+        let weight = |index: usize| u64::from(self.upstreams[index].weight);
+        let pick = |rng: &mut rand::rngs::ThreadRng, skip: Option<usize>| {
+            let candidates = || {
+                (0..self.upstreams.len()).filter(|index| eligible(*index) && Some(*index) != skip)
+            };
+            let total = candidates().map(weight).sum::<u64>();
+            if total == 0 {
+                return None;
+            }
+
+            let mut value = rng.gen_range(0..total);
+            candidates().find(|index| {
+                if value < weight(*index) {
+                    true
+                } else {
+                    value -= weight(*index);
+                    false
+                }
+            })
+        };
+
+        let Some(first) = pick(&mut rng, None) else {
+            return 0;
+        };
+        match pick(&mut rng, Some(first)) {
+            Some(second) if self.less_loaded(second, first) => second,
+            _ => first,
+        }
+    }
+
+    fn consistent_hash(
+        &self,
+        session: &impl SessionWrapper,
+        eligible: &impl Fn(usize) -> bool,
+    ) -> usize {
+        let Some(ring) = &self.ring else {
+            return self.round_robin(eligible);
+        };
+
+        // The ring contains all servers, so this will find an eligible one eventually
+        let key = self.hash_key(session);
+        ring.continuum
+            .node_iter(key.as_bytes())
+            .filter_map(|addr| ring.indices.get(addr).copied())
+            .find(|index| eligible(*index))
+            .unwrap_or(0)
+    }
+
+    /// Extracts the key to hash from the request, empty if the request doesn’t have it
+    fn hash_key<'a>(&self, session: &'a impl SessionWrapper) -> Cow<'a, str> {
+        match &self.hash_key {
+            HashKey::ClientIp => session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip().to_string().into())
+                .unwrap_or_default(),
+            HashKey::Uri => session
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str().into())
+                .unwrap_or_default(),
+            HashKey::Path => session.uri().path().into(),
+            HashKey::Header(name) => session
+                .req_header()
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .into(),
+            HashKey::Cookie(cookie) => session
+                .req_header()
+                .headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim() == cookie)
+                .map(|(_, value)| value.trim())
+                .unwrap_or_default()
+                .into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_balancer(conf: &str) -> Balancer {
+        Balancer::new(UpstreamConf::from_yaml(conf).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn make_session(path: &str, headers: &[(&'static str, &str)]) -> TestSession {
+        let mut header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            header.append_header(*name, *value).unwrap();
+        }
+        TestSession::from(header).await
+    }
+
+    fn select_many(balancer: &Balancer, session: &TestSession, excluded: &[usize]) -> Vec<usize> {
+        (0..6).map(|_| balancer.select(session, excluded)).collect()
+    }
+
+    #[test]
+    fn resolve() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1
+                - https://localhost:8443/api/
+                - http://127.0.0.1:8080
+            "#,
+        );
+
+        let upstream = balancer.upstream(0);
+        assert_eq!(upstream.addr, "127.0.0.1:80".parse().unwrap());
+        assert!(!upstream.tls);
+        assert_eq!(upstream.sni, "127.0.0.1");
+        assert_eq!(upstream.host_port, "127.0.0.1");
+        assert_eq!(upstream.path, "");
+
+        let upstream = balancer.upstream(1);
+        assert_eq!(upstream.addr.port(), 8443);
+        assert!(upstream.tls);
+        assert_eq!(upstream.sni, "localhost");
+        assert_eq!(upstream.host_port, "localhost:8443");
+        assert_eq!(upstream.path, "/api");
+
+        assert_eq!(balancer.upstream(2).host_port, "127.0.0.1:8080");
+
+        assert!(Balancer::new(UpstreamConf::default()).unwrap().is_none());
+        assert!(Balancer::new(
+            UpstreamConf::from_yaml(
+                r#"
+                    upstream: ftp://127.0.0.1
+                "#
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn map_uri() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082/api/
+            "#,
+        );
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+
+        let unmapped = balancer.upstream(0);
+        assert_eq!(unmapped.map_uri(&uri("/test?x=1")), uri("/test?x=1"));
+
+        let mapped = balancer.upstream(1);
+        assert_eq!(mapped.map_uri(&uri("/test?x=1")), uri("/api/test?x=1"));
+        assert_eq!(mapped.map_uri(&uri("/")), uri("/api/"));
+        assert_eq!(mapped.map_uri(&uri("*")), uri("*"));
+    }
+
+    #[test(tokio::test)]
+    async fn round_robin() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - url: http://127.0.0.1:8082
+                  weight: 5
+                - http://127.0.0.1:8083
+            "#,
+        );
+        let session = make_session("/", &[]).await;
+
+        // Weights are ignored
+        assert_eq!(select_many(&balancer, &session, &[]), [0, 1, 2, 0, 1, 2]);
+
+        // Excluded and unhealthy servers are skipped
+        let chosen = select_many(&balancer, &session, &[2]);
+        assert!(chosen.contains(&0) && chosen.contains(&1) && !chosen.contains(&2));
+        balancer.set_healthy(0, false);
+        assert_eq!(select_many(&balancer, &session, &[2]), [1, 1, 1, 1, 1, 1]);
+
+        // Unhealthy servers are used if nothing else is left, excluded ones only as a last resort
+        assert_eq!(
+            select_many(&balancer, &session, &[1, 2]),
+            [0, 0, 0, 0, 0, 0]
+        );
+        balancer.set_healthy(1, false);
+        balancer.set_healthy(2, false);
+        assert!(balancer.select(&session, &[0, 1, 2]) < 3);
+        let mut chosen = select_many(&balancer, &session, &[]);
+        chosen.sort();
+        assert_eq!(chosen, [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test(tokio::test)]
+    async fn weighted() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - url: http://127.0.0.1:8081
+                  weight: 5
+                - http://127.0.0.1:8082
+                - http://127.0.0.1:8083
+                load_balancing: weighted
+            "#,
+        );
+        let session = make_session("/", &[]).await;
+
+        // Smooth weighted round robin doesn’t send all requests to the heavy server in a row
+        let chosen = (0..7)
+            .map(|_| balancer.select(&session, &[]))
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, [0, 0, 1, 0, 2, 0, 0]);
+
+        // Ineligible servers don’t accumulate weight
+        balancer.set_healthy(0, false);
+        assert_eq!(select_many(&balancer, &session, &[]), [1, 2, 1, 2, 1, 2]);
+        balancer.set_healthy(0, true);
+        let mut chosen = (0..14)
+            .map(|_| balancer.select(&session, &[]))
+            .collect::<Vec<_>>();
+        chosen.sort();
+        chosen.dedup_by_key(|index| *index);
+        assert_eq!(chosen, [0, 1, 2]);
+    }
+
+    #[test(tokio::test)]
+    async fn least_connections() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - url: http://127.0.0.1:8082
+                  weight: 3
+                load_balancing: least_connections
+            "#,
+        );
+        let session = make_session("/", &[]).await;
+
+        // Ties are resolved by rotating
+        let mut chosen = select_many(&balancer, &session, &[]);
+        chosen.sort();
+        assert_eq!(chosen, [0, 0, 0, 1, 1, 1]);
+
+        // Active requests are compared relative to server weight
+        let first = balancer.acquire(0);
+        let _second = [balancer.acquire(1), balancer.acquire(1)];
+        assert_eq!(select_many(&balancer, &session, &[]), [1, 1, 1, 1, 1, 1]);
+        let _third = balancer.acquire(1);
+        let _fourth = balancer.acquire(1);
+        assert_eq!(select_many(&balancer, &session, &[]), [0, 0, 0, 0, 0, 0]);
+
+        // Finished requests are no longer counted
+        assert_eq!(first.index(), 0);
+        drop(first);
+        assert_eq!(select_many(&balancer, &session, &[]), [0, 0, 0, 0, 0, 0]);
+        assert_eq!(select_many(&balancer, &session, &[0]), [1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test(tokio::test)]
+    async fn random_two_choices() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                - http://127.0.0.1:8083
+                load_balancing: random_two_choices
+            "#,
+        );
+        let session = make_session("/", &[]).await;
+
+        // The less loaded server of the two choices wins, so the busiest one is never chosen
+        let _active = [0, 0, 0, 1].map(|index| balancer.acquire(index));
+        let mut chosen = [0; 3];
+        for _ in 0..300 {
+            chosen[balancer.select(&session, &[])] += 1;
+        }
+        assert_eq!(chosen[0], 0);
+        assert!(chosen[2] > chosen[1]);
+
+        // Excluded servers aren’t chosen even if they are the least loaded
+        for _ in 0..20 {
+            assert_eq!(balancer.select(&session, &[2]), 1);
+        }
+
+        // Neither are unhealthy servers while others are available
+        balancer.set_healthy(1, false);
+        for _ in 0..20 {
+            assert_eq!(balancer.select(&session, &[2]), 0);
+            assert_eq!(balancer.select(&session, &[]), 2);
+        }
+        assert_eq!(balancer.select(&session, &[0, 2]), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn consistent_hash() {
+        let balancer = make_balancer(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                - http://127.0.0.1:8083
+                load_balancing: consistent_hash
+                hash_key: path
+            "#,
+        );
+
+        let mut sessions = Vec::new();
+        for user in 0..30 {
+            sessions.push(make_session(&format!("/user/{user}"), &[]).await);
+        }
+        let chosen = sessions
+            .iter()
+            .map(|session| balancer.select(session, &[]))
+            .collect::<Vec<_>>();
+        assert!(chosen.contains(&0) && chosen.contains(&1) && chosen.contains(&2));
+
+        // Only the keys of an unavailable server are moved to other servers
+        balancer.set_healthy(1, false);
+        for (session, previous) in sessions.iter().zip(&chosen) {
+            let index = balancer.select(session, &[]);
+            if *previous == 1 {
+                assert_ne!(index, 1);
+            } else {
+                assert_eq!(index, *previous);
+            }
+        }
+
+        // They return once it is available again
+        balancer.set_healthy(1, true);
+        for (session, previous) in sessions.iter().zip(&chosen) {
+            assert_eq!(balancer.select(session, &[]), *previous);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn hash_key() {
+        let key = |hash_key: &str| {
+            make_balancer(&format!(
+                r#"
+                    upstream: http://127.0.0.1:8081
+                    load_balancing: consistent_hash
+                    hash_key: {hash_key}
+                "#
+            ))
+        };
+        let session = make_session(
+            "/path?query",
+            &[
+                ("X-User", "me"),
+                ("Cookie", "theme=dark"),
+                ("Cookie", "user = 42; last=1"),
+            ],
+        )
+        .await;
+
+        assert_eq!(key("uri").hash_key(&session), "/path?query");
+        assert_eq!(key("path").hash_key(&session), "/path");
+        assert_eq!(key("http_x_user").hash_key(&session), "me");
+        assert_eq!(key("http_x_missing").hash_key(&session), "");
+        assert_eq!(key("cookie_user").hash_key(&session), "42");
+        assert_eq!(key("cookie_theme").hash_key(&session), "dark");
+        assert_eq!(key("cookie_missing").hash_key(&session), "");
+        assert_eq!(key("client_ip").hash_key(&session), "");
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passive health tracking of upstream servers
+
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::configuration::CircuitBreakerConf;
+
+/// Number of trial requests a half-open breaker lets through
+const HALF_OPEN_PROBES: usize = 1;
+
+/// State of a circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Requests are passed through, failures are counted
+    Closed,
+    /// No requests are passed through until the given time
+    Open { until: Instant },
+    /// Cooldown is over, a limited number of trial requests is passed through
+    HalfOpen { probes: usize, since: Instant },
+}
+
+/// Circuit breaker of an upstream server
+///
+/// The breaker opens if the server produces too many failed requests within the configured time
+/// window. While it is open, the server should not receive any requests. Once the cooldown period
+/// is over, the breaker becomes half-open and lets a trial request through. It closes if that
+/// request succeeds and opens again if it fails.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    recent_failures: VecDeque<Instant>,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(conf: &CircuitBreakerConf) -> Self {
+        Self {
+            failures: conf.failures as usize,
+            window: Duration::from_secs(conf.window),
+            cooldown: Duration::from_secs(conf.cooldown),
+            recent_failures: VecDeque::new(),
+            state: State::Closed,
+        }
+    }
+
+    /// Checks whether the breaker currently rejects requests, makes it half-open if the cooldown
+    /// period is over
+    pub(crate) fn is_open(&mut self, addr: &SocketAddr, now: Instant) -> bool {
+        match self.state {
+            State::Closed => false,
+            State::Open { until } if until > now => true,
+            State::Open { .. } => {
+                info!("circuit breaker for upstream server {addr} is half-open, cooldown period is over");
+                self.state = State::HalfOpen {
+                    probes: 0,
+                    since: now,
+                };
+                false
+            }
+            State::HalfOpen { probes, since } => {
+                if probes >= HALF_OPEN_PROBES
+                    && now.saturating_duration_since(since) >= self.cooldown
+                {
+                    // Trial requests didn’t report back within a cooldown period, allow new ones
+                    self.state = State::HalfOpen {
+                        probes: 0,
+                        since: now,
+                    };
+                    false
+                } else {
+                    probes >= HALF_OPEN_PROBES
+                }
+            }
+        }
+    }
+
+    /// Registers a request about to be sent to the server, counting trial requests of a half-open
+    /// breaker. Returns `false` if the breaker rejects the request.
+    pub(crate) fn start_request(&mut self, addr: &SocketAddr, now: Instant) -> bool {
+        if self.is_open(addr, now) {
+            return false;
+        }
+        if let State::HalfOpen { probes, .. } = &mut self.state {
+            *probes += 1;
+        }
+        true
+    }
+
+    /// Records a successful request, closing a half-open breaker
+    pub(crate) fn record_success(&mut self, addr: &SocketAddr) {
+        if let State::HalfOpen { .. } = self.state {
+            info!("circuit breaker for upstream server {addr} closed, trial request succeeded");
+            self.state = State::Closed;
+            self.recent_failures.clear();
+        }
+    }
+
+    /// Records a failed request, opening the breaker if there were too many failures recently
+    pub(crate) fn record_failure(&mut self, addr: &SocketAddr, now: Instant) {
+        match self.state {
+            State::Closed => {}
+            State::Open { until } if until > now => {
+                // Requests started before the breaker opened, these don’t count
+                return;
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                warn!(
+                    "circuit breaker for upstream server {addr} opened again after a failed trial request, excluding it for {} seconds",
+                    self.cooldown.as_secs()
+                );
+                self.state = State::Open {
+                    until: now + self.cooldown,
+                };
+                return;
+            }
+        }
+
+        while self
+            .recent_failures
+            .front()
+            .is_some_and(|time| now.saturating_duration_since(*time) > self.window)
+        {
+            self.recent_failures.pop_front();
+        }
+
+        self.recent_failures.push_back(now);
+        if self.recent_failures.len() >= self.failures {
+            warn!(
+                "circuit breaker for upstream server {addr} opened after {} failed requests within {} seconds, excluding it for {} seconds",
+                self.recent_failures.len(),
+                self.window.as_secs(),
+                self.cooldown.as_secs()
+            );
+            self.recent_failures.clear();
+            self.state = State::Open {
+                until: now + self.cooldown,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use test_log::test;
+
+    fn make_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConf {
+            failures: 3,
+            window: 10,
+            cooldown: 30,
+        })
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:8080".parse().unwrap()
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn open() {
+        let mut breaker = make_breaker();
+        let start = Instant::now();
+
+        // Failures outside the window don’t count
+        breaker.record_failure(&addr(), start);
+        breaker.record_failure(&addr(), start + secs(5));
+        breaker.record_failure(&addr(), start + secs(12));
+        assert!(!breaker.is_open(&addr(), start + secs(12)));
+
+        breaker.record_failure(&addr(), start + secs(13));
+        assert!(breaker.is_open(&addr(), start + secs(13)));
+        assert!(!breaker.start_request(&addr(), start + secs(13)));
+
+        // Failures of requests started before the breaker opened don’t extend the cooldown
+        breaker.record_failure(&addr(), start + secs(20));
+        assert!(breaker.is_open(&addr(), start + secs(42)));
+        assert!(!breaker.is_open(&addr(), start + secs(43)));
+    }
+
+    #[test]
+    fn half_open_probes() {
+        let mut breaker = make_breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(&addr(), start);
+        }
+
+        // Only a limited number of trial requests is let through
+        let now = start + secs(30);
+        for _ in 0..HALF_OPEN_PROBES {
+            assert!(!breaker.is_open(&addr(), now));
+            assert!(breaker.start_request(&addr(), now));
+        }
+        assert!(breaker.is_open(&addr(), now));
+        assert!(!breaker.start_request(&addr(), now + secs(29)));
+
+        // Trial requests that never report back don’t keep the breaker half-open forever
+        assert!(breaker.start_request(&addr(), now + secs(30)));
+        assert!(!breaker.start_request(&addr(), now + secs(30)));
+
+        // A failed trial request opens the breaker for another cooldown period
+        breaker.record_failure(&addr(), now + secs(31));
+        assert!(breaker.is_open(&addr(), now + secs(60)));
+        assert!(breaker.start_request(&addr(), now + secs(61)));
+    }
+
+    #[test]
+    fn reset_after_success() {
+        let mut breaker = make_breaker();
+        let start = Instant::now();
+
+        // Successes don’t affect a closed breaker
+        breaker.record_failure(&addr(), start);
+        breaker.record_failure(&addr(), start);
+        breaker.record_success(&addr());
+        breaker.record_failure(&addr(), start);
+        assert!(breaker.is_open(&addr(), start));
+
+        // A successful trial request closes the breaker and resets the failure count
+        let now = start + secs(30);
+        assert!(breaker.start_request(&addr(), now));
+        breaker.record_success(&addr());
+        breaker.record_failure(&addr(), now);
+        breaker.record_failure(&addr(), now);
+        assert!(!breaker.is_open(&addr(), now));
+        assert!(breaker.start_request(&addr(), now));
+        assert!(breaker.start_request(&addr(), now));
+
+        breaker.record_failure(&addr(), now);
+        assert!(breaker.is_open(&addr(), now));
+    }
+
+    #[test]
+    fn concurrent_trips() {
+        let breaker = Arc::new(Mutex::new(make_breaker()));
+        let start = Instant::now();
+        let threads = 16;
+
+        let run = |action: fn(&mut CircuitBreaker, Instant) -> bool, now: Instant| {
+            let barrier = Arc::new(Barrier::new(threads));
+            let handles = (0..threads)
+                .map(|_| {
+                    let breaker = breaker.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        action(&mut breaker.lock().unwrap(), now)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|result| *result)
+                .count()
+        };
+
+        // Simultaneous failures open the breaker once, the cooldown isn’t extended by the surplus
+        run(
+            |breaker, now| {
+                breaker.record_failure(&addr(), now);
+                true
+            },
+            start,
+        );
+        {
+            let mut breaker = breaker.lock().unwrap();
+            assert!(breaker.is_open(&addr(), start + secs(29)));
+            assert!(breaker.recent_failures.is_empty());
+        }
+
+        // Simultaneous requests after the cooldown only get the allowed number of trial requests
+        let allowed = run(
+            |breaker, now| breaker.start_request(&addr(), now),
+            start + secs(30),
+        );
+        assert_eq!(allowed, HALF_OPEN_PROBES);
+
+        // Simultaneous failed trial requests open the breaker again, once
+        run(
+            |breaker, now| {
+                breaker.record_failure(&addr(), now);
+                true
+            },
+            start + secs(31),
+        );
+        let mut breaker = breaker.lock().unwrap();
+        assert!(breaker.is_open(&addr(), start + secs(60)));
+        assert!(!breaker.is_open(&addr(), start + secs(61)));
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structures required to deserialize Upstream Module configuration from YAML configuration files.
+
+use clap::{value_parser, Parser};
+use http::header::HeaderName;
+use http::uri::Uri;
+use pandora_module_utils::{DeserializeMap, OneOrMany};
+use serde::de::{Deserializer, MapAccess, Unexpected, Visitor};
+use serde::Deserialize;
+
+/// Command line options of the upstream module
+#[derive(Debug, Default, Parser)]
+pub struct UpstreamOpt {
+    /// http:// or https:// URL identifying the server that requests should be forwarded for.
+    /// The request path is appended to the path of the URL, query part of the URL has no effect.
+    /// This command line flag can be specified multiple times.
+    #[clap(long, value_parser = value_parser!(String))]
+    pub upstream: Option<Vec<Uri>>,
+}
+
+/// An upstream server that requests can be forwarded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamServer {
+    /// http:// or https:// URL identifying the server. The request path is appended to the path
+    /// of the URL, query part of the URL has no effect.
+    pub url: Uri,
+
+    /// Relative weight of the server for load balancing, 1 by default
+    pub weight: u32,
+}
+
+impl From<Uri> for UpstreamServer {
+    fn from(url: Uri) -> Self {
+        Self { url, weight: 1 }
+    }
+}
+
+fn parse_uri<E: serde::de::Error>(uri: &str) -> Result<Uri, E> {
+    uri.parse()
+        .map_err(|err| E::custom(format!("URL {uri} could not be parsed: {err}")))
+}
+
+impl<'de> Deserialize<'de> for UpstreamServer {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ServerVisitor;
+
+        impl<'de> Visitor<'de> for ServerVisitor {
+            type Value = UpstreamServer;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("URL string or UpstreamServer structure")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(parse_uri::<E>(v)?.into())
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                use serde::de::Error as _;
+
+                const URL_FIELD: &str = "url";
+                const WEIGHT_FIELD: &str = "weight";
+
+                let mut url = None;
+                let mut weight = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        URL_FIELD => {
+                            if url.is_some() {
+                                return Err(A::Error::duplicate_field(URL_FIELD));
+                            }
+                            url = Some(parse_uri::<A::Error>(&map.next_value::<String>()?)?);
+                        }
+                        WEIGHT_FIELD => {
+                            if weight.is_some() {
+                                return Err(A::Error::duplicate_field(WEIGHT_FIELD));
+                            }
+                            let value = map.next_value()?;
+                            if value == 0 {
+                                return Err(A::Error::invalid_value(
+                                    Unexpected::Unsigned(0),
+                                    &"a positive weight",
+                                ));
+                            }
+                            weight = Some(value);
+                        }
+                        other => {
+                            return Err(A::Error::unknown_field(other, &[URL_FIELD, WEIGHT_FIELD]))
+                        }
+                    }
+                }
+
+                let url = url.ok_or_else(|| A::Error::missing_field(URL_FIELD))?;
+                Ok(UpstreamServer {
+                    url,
+                    weight: weight.unwrap_or(1),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ServerVisitor)
+    }
+}
+
+/// Strategy to choose an upstream server for a request
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// Choose each server in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    /// Choose each server in turn, proportionally to its weight
+    Weighted,
+    /// Choose the server with the fewest active requests relative to its weight
+    LeastConnections,
+    /// Choose two servers randomly, proportionally to their weight, then the one with fewer
+    /// active requests relative to its weight
+    RandomTwoChoices,
+    /// Choose the server by hashing a request property, see [`HashKey`]
+    ConsistentHash,
+}
+
+/// Request property to hash for consistent hashing
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum HashKey {
+    /// Client’s IP address, `client_ip` in config file
+    #[default]
+    ClientIp,
+    /// Request URI including query string, `uri` in config file
+    Uri,
+    /// Path part of the request URI, `path` in config file
+    Path,
+    /// A request header, `http_<header>` in config file
+    Header(HeaderName),
+    /// A request cookie, `cookie_<name>` in config file
+    Cookie(String),
+}
+
+impl TryFrom<&str> for HashKey {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "client_ip" => Ok(Self::ClientIp),
+            "uri" => Ok(Self::Uri),
+            "path" => Ok(Self::Path),
+            name => {
+                if let Some(header) = name.strip_prefix("http_") {
+                    let header = header.replace('_', "-");
+                    Ok(Self::Header(
+                        HeaderName::try_from(header).map_err(|err| err.to_string())?,
+                    ))
+                } else if let Some(cookie) = name.strip_prefix("cookie_") {
+                    Ok(Self::Cookie(cookie.to_owned()))
+                } else {
+                    Err(format!("Unsupported hash key {name}"))
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for HashKey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// Type of active health checks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    /// Check whether a TCP connection can be established
+    #[default]
+    Tcp,
+    /// Send a GET request and check the response status
+    Http,
+}
+
+/// Settings of active health checks for upstream servers
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct HealthCheckConf {
+    /// Type of the health check, `tcp` or `http`
+    #[pandora(rename = "type")]
+    pub check_type: HealthCheckType,
+
+    /// Path to request for HTTP health checks
+    pub path: String,
+
+    /// Response status indicating a healthy server for HTTP health checks
+    #[pandora(range = "100..=599")]
+    pub status: u16,
+
+    /// Time in seconds between two health checks of a server
+    #[pandora(range = "1..")]
+    pub interval: u64,
+
+    /// Time in seconds after which a health check fails if there is no response
+    #[pandora(range = "1..")]
+    pub timeout: u64,
+
+    /// Number of consecutive successful health checks before a server is considered healthy again
+    #[pandora(range = "1..")]
+    pub rise: u32,
+
+    /// Number of consecutive failed health checks before a server is considered unhealthy
+    #[pandora(range = "1..")]
+    pub fall: u32,
+}
+
+impl Default for HealthCheckConf {
+    fn default() -> Self {
+        Self {
+            check_type: Default::default(),
+            path: "/".to_owned(),
+            status: 200,
+            interval: 10,
+            timeout: 5,
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// Settings of passive health tracking, excluding upstream servers that fail real requests
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct CircuitBreakerConf {
+    /// Number of failed requests within `window` that will exclude a server
+    #[pandora(range = "1..")]
+    pub failures: u32,
+
+    /// Time span in seconds in which failed requests are counted
+    #[pandora(range = "1..")]
+    pub window: u64,
+
+    /// Time in seconds for which a server is excluded after too many failed requests
+    #[pandora(range = "1..")]
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConf {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            window: 10,
+            cooldown: 30,
+        }
+    }
+}
+
+/// Settings of automatic retries for failed upstream requests
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RetryConf {
+    /// Maximal number of attempts per request, including the first one
+    #[pandora(range = "1..")]
+    pub attempts: u32,
+
+    /// Retry requests if connecting to the upstream server failed
+    pub connect_errors: bool,
+
+    /// Retry requests with idempotent methods like `GET` or `PUT` if the upstream connection was
+    /// closed or reset before a response was received
+    pub reset: bool,
+
+    /// Percentage of requests that can be retried, protects upstream servers from load
+    /// amplification
+    #[pandora(range = "0..=100")]
+    pub budget: u32,
+}
+
+impl Default for RetryConf {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            connect_errors: true,
+            reset: false,
+            budget: 20,
+        }
+    }
+}
+
+/// Configuration settings of the upstream module
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct UpstreamConf {
+    /// Upstream servers that requests should be forwarded to, either URLs like
+    /// `http://127.0.0.1:8081` or structures with `url` and `weight` fields
+    pub upstream: OneOrMany<UpstreamServer>,
+
+    /// If `true`, the Host header of the client request will be forwarded. Otherwise it is
+    /// replaced by the host name and port of the upstream server.
+    pub preserve_host: bool,
+
+    /// Strategy to choose an upstream server for a request if there are multiple
+    pub load_balancing: LoadBalancing,
+
+    /// Request property to hash if `load_balancing` is `consistent_hash`
+    pub hash_key: HashKey,
+
+    /// Active health checks, unhealthy servers are excluded from load balancing
+    pub health_check: Option<HealthCheckConf>,
+
+    /// Passive health tracking, servers producing too many errors are excluded temporarily
+    pub circuit_breaker: Option<CircuitBreakerConf>,
+
+    /// Automatic retries of failed requests, trying a different server if possible
+    pub retry: Option<RetryConf>,
+
+    /// Time in seconds after which establishing a TCP connection to an upstream server fails
+    ///
+    /// If not set, the system default will be used.
+    pub connection_timeout: Option<u64>,
+
+    /// Time in seconds after which establishing a connection to an upstream server fails,
+    /// including the TLS handshake
+    pub total_connection_timeout: Option<u64>,
+
+    /// Time in seconds after which reading from an upstream connection fails, e.g. while waiting
+    /// for the response
+    pub read_timeout: Option<u64>,
+
+    /// Time in seconds after which writing to an upstream connection fails
+    pub write_timeout: Option<u64>,
+
+    /// Time in seconds after which idle upstream connections are removed from the connection pool
+    ///
+    /// If not set, idle connections are kept until the pool is full.
+    pub idle_timeout: Option<u64>,
+}
+
+impl UpstreamConf {
+    /// Merges the command line options into the current configuration. Any command line options
+    /// present overwrite existing settings.
+    pub fn merge_with_opt(&mut self, opt: UpstreamOpt) {
+        if let Some(upstream) = opt.upstream {
+            self.upstream = upstream
+                .into_iter()
+                .map(UpstreamServer::from)
+                .collect::<Vec<_>>()
+                .into();
+        }
+    }
+}
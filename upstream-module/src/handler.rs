@@ -0,0 +1,207 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handler for the `request_filter` and `upstream_peer` phases.
+
+use async_trait::async_trait;
+use http::{header, Uri};
+use pandora_module_utils::pingora::{Error, HttpPeer, ServerConf, Service, SessionWrapper};
+use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pingora::ErrorSource;
+
+use crate::balancer::{ActiveRequest, Balancer};
+use crate::configuration::{HealthCheckConf, UpstreamConf};
+use crate::health::HealthChecker;
+use crate::retry::RetryPolicy;
+
+/// Context data of the handler
+#[derive(Debug, Default)]
+pub struct UpstreamContext {
+    active: Option<ActiveRequest>,
+    attempts: u32,
+    failed: Vec<usize>,
+    uri: Option<Uri>,
+}
+
+impl UpstreamContext {
+    /// Marks the current attempt as failed, so that a retry will choose a different server
+    fn fail(&mut self, balancer: &Balancer) {
+        if let Some(active) = self.active.take() {
+            balancer.record(active.index(), false);
+            self.failed.push(active.index());
+        }
+    }
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamHandler {
+    balancer: Option<Balancer>,
+    health_check: Option<HealthCheckConf>,
+    retry: Option<RetryPolicy>,
+    preserve_host: bool,
+}
+
+impl TryFrom<UpstreamConf> for UpstreamHandler {
+    type Error = Box<Error>;
+
+    fn try_from(mut conf: UpstreamConf) -> Result<Self, Self::Error> {
+        let health_check = conf.health_check.take();
+        let retry = conf.retry.take().map(RetryPolicy::new);
+        let preserve_host = conf.preserve_host;
+        let balancer = Balancer::new(conf)?;
+        Ok(Self {
+            balancer,
+            health_check,
+            retry,
+            preserve_host,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for UpstreamHandler {
+    type Conf = UpstreamConf;
+    type CTX = Option<UpstreamContext>;
+    fn new_ctx() -> Self::CTX {
+        None
+    }
+
+    async fn request_filter(
+        &self,
+        _session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        if self.balancer.is_some() {
+            *ctx = Some(UpstreamContext::default());
+            Ok(RequestFilterResult::Handled)
+        } else {
+            Ok(RequestFilterResult::Unhandled)
+        }
+    }
+
+    async fn upstream_peer(
+        &self,
+        session: &mut impl SessionWrapper,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
+        let (Some(balancer), Some(context)) = (&self.balancer, ctx) else {
+            return Ok(None);
+        };
+
+        context.attempts += 1;
+        if context.attempts == 1 {
+            if let Some(retry) = &self.retry {
+                retry.request_started();
+            }
+        }
+
+        let index = balancer.select(session, &context.failed);
+        let upstream = balancer.upstream(index);
+        if !self.preserve_host {
+            session
+                .req_header_mut()
+                .insert_header(header::HOST, &upstream.host_port)?;
+        }
+
+        // Retries might choose a server with a different path, always map the URI from before the
+        // first attempt
+        let uri = context.uri.get_or_insert_with(|| session.uri().clone());
+        let mapped = upstream.map_uri(uri);
+        if &mapped != session.uri() {
+            session.set_uri(mapped);
+        }
+
+        context.active = Some(balancer.acquire(index));
+        Ok(Some(balancer.peer(index)))
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut impl SessionWrapper,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        let (Some(balancer), Some(context)) = (&self.balancer, ctx) else {
+            return e;
+        };
+
+        context.fail(balancer);
+        if let Some(retry) = &self.retry {
+            e.set_retry(retry.retry_connect(context.attempts));
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        session: &mut impl SessionWrapper,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+        _client_reused: bool,
+    ) -> Box<Error> {
+        let (Some(balancer), Some(context)) = (&self.balancer, ctx) else {
+            return e;
+        };
+
+        if e.esource() == &ErrorSource::Upstream {
+            context.fail(balancer);
+        }
+
+        // Pingora already decided to retry requests failing on reused upstream connections
+        if !e.retry() {
+            if let Some(retry) = &self.retry {
+                let can_replay = session.response_written().is_none()
+                    && !session.as_ref().retry_buffer_truncated();
+                let method = &session.req_header().method;
+                e.set_retry(retry.retry_reset(context.attempts, method, &e, can_replay));
+            }
+        }
+        e
+    }
+
+    async fn logging(
+        &self,
+        session: &mut impl SessionWrapper,
+        e: Option<&Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        let (Some(balancer), Some(active)) = (
+            &self.balancer,
+            ctx.as_ref().and_then(|context| context.active.as_ref()),
+        ) else {
+            return;
+        };
+
+        // Connection errors and server errors count as failures, client errors don’t
+        let failed = e.is_some_and(|e| e.esource() == &ErrorSource::Upstream)
+            || session
+                .response_written()
+                .is_some_and(|response| response.status.is_server_error());
+        balancer.record(active.index(), !failed);
+    }
+
+    fn background_services(&self, server_conf: &ServerConf) -> Vec<Box<dyn Service>> {
+        match (&self.balancer, &self.health_check) {
+            (Some(balancer), Some(health_check)) => vec![HealthChecker::service(
+                balancer.clone(),
+                health_check.clone(),
+                server_conf,
+            )],
+            _ => Vec::new(),
+        }
+    }
+}
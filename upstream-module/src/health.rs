@@ -0,0 +1,378 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Active health checks of upstream servers
+
+use async_trait::async_trait;
+use http::header;
+use log::{debug, info, warn};
+use pandora_module_utils::health::{set_healthy, set_unhealthy};
+use pandora_module_utils::pingora::{RequestHeader, ServerConf, Service};
+use pingora::connectors::http::Connector;
+use pingora::connectors::ConnectorOptions;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::{background_service, BackgroundService};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+
+use crate::balancer::{Balancer, Upstream};
+use crate::configuration::{HealthCheckConf, HealthCheckType};
+
+/// Consecutive health check results of a server
+#[derive(Debug, Default)]
+struct Counters {
+    successes: u32,
+    failures: u32,
+}
+
+/// Background task checking the health of all servers of a balancer periodically
+pub(crate) struct HealthChecker {
+    balancer: Balancer,
+    conf: HealthCheckConf,
+    connector: Option<Connector>,
+    component: String,
+    counters: Mutex<Vec<Counters>>,
+}
+
+impl std::fmt::Debug for HealthChecker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HealthChecker")
+            .field("balancer", &self.balancer)
+            .field("conf", &self.conf)
+            .field("component", &self.component)
+            .field("counters", &self.counters)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HealthChecker {
+    fn new(balancer: Balancer, conf: HealthCheckConf, server_conf: &ServerConf) -> Self {
+        let connector = if conf.check_type == HealthCheckType::Http {
+            Some(Connector::new(Some(ConnectorOptions::from_server_conf(
+                server_conf,
+            ))))
+        } else {
+            None
+        };
+
+        let component = format!(
+            "upstream {}",
+            balancer
+                .upstreams()
+                .iter()
+                .map(|upstream| upstream.addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let counters = balancer
+            .upstreams()
+            .iter()
+            .map(|_| Counters::default())
+            .collect();
+
+        Self {
+            balancer,
+            conf,
+            connector,
+            component,
+            counters: Mutex::new(counters),
+        }
+    }
+
+    /// Creates a background service running health checks for the balancer’s servers.
+    pub(crate) fn service(
+        balancer: Balancer,
+        conf: HealthCheckConf,
+        server_conf: &ServerConf,
+    ) -> Box<dyn Service> {
+        Box::new(background_service(
+            "upstream health checks",
+            Self::new(balancer, conf, server_conf),
+        ))
+    }
+
+    /// Checks whether a server is healthy
+    async fn check(&self, index: usize) -> bool {
+        let upstream = self.balancer.upstream(index);
+        let result = match &self.connector {
+            Some(connector) => self.check_http(connector, index).await,
+            None => self.check_tcp(upstream).await,
+        };
+        if let Err(err) = &result {
+            debug!(
+                "health check of upstream server {} failed: {err}",
+                upstream.addr
+            );
+        }
+        result.is_ok()
+    }
+
+    async fn check_tcp(&self, upstream: &Upstream) -> Result<(), String> {
+        match timeout(
+            Duration::from_secs(self.conf.timeout),
+            TcpStream::connect(upstream.addr),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err("timed out".to_owned()),
+        }
+    }
+
+    async fn check_http(&self, connector: &Connector, index: usize) -> Result<(), String> {
+        let upstream = self.balancer.upstream(index);
+        let request = async {
+            let peer = self.balancer.peer(index);
+            let (mut session, _) = connector
+                .get_http_session(&*peer)
+                .await
+                .map_err(|err| err.to_string())?;
+
+            let mut header = RequestHeader::build("GET", self.conf.path.as_bytes(), None)
+                .map_err(|err| err.to_string())?;
+            header
+                .insert_header(header::HOST, &upstream.host_port)
+                .map_err(|err| err.to_string())?;
+            session
+                .write_request_header(Box::new(header))
+                .await
+                .map_err(|err| err.to_string())?;
+            session
+                .finish_request_body()
+                .await
+                .map_err(|err| err.to_string())?;
+            session
+                .read_response_header()
+                .await
+                .map_err(|err| err.to_string())?;
+
+            let status = session
+                .response_header()
+                .map(|header| header.status.as_u16())
+                .unwrap_or_default();
+            session.shutdown().await;
+
+            if status == self.conf.status {
+                Ok(())
+            } else {
+                Err(format!("unexpected response status {status}"))
+            }
+        };
+
+        timeout(Duration::from_secs(self.conf.timeout), request)
+            .await
+            .unwrap_or_else(|_| Err("timed out".to_owned()))
+    }
+
+    /// Runs health checks for all servers and updates their health state
+    async fn check_all(&self) {
+        let upstreams = self.balancer.upstreams();
+        let results =
+            futures::future::join_all((0..upstreams.len()).map(|index| self.check(index))).await;
+
+        let mut counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        for (index, success) in results.into_iter().enumerate() {
+            let counters = &mut counters[index];
+            let addr = upstreams[index].addr;
+            if success {
+                counters.successes = counters.successes.saturating_add(1);
+                counters.failures = 0;
+                if counters.successes >= self.conf.rise && !self.balancer.is_healthy(index) {
+                    info!("upstream server {addr} is healthy again");
+                    self.balancer.set_healthy(index, true);
+                }
+            } else {
+                counters.failures = counters.failures.saturating_add(1);
+                counters.successes = 0;
+                if counters.failures >= self.conf.fall && self.balancer.is_healthy(index) {
+                    warn!("upstream server {addr} failed health checks, marking it unhealthy");
+                    self.balancer.set_healthy(index, false);
+                }
+            }
+        }
+
+        if (0..upstreams.len()).any(|index| self.balancer.is_healthy(index)) {
+            set_healthy(&self.component);
+        } else {
+            set_unhealthy(&self.component, "all upstream servers failed health checks");
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthChecker {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = interval(Duration::from_secs(self.conf.interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => self.check_all().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pandora_module_utils::health::unhealthy_components;
+    use pandora_module_utils::FromYaml;
+    use std::sync::Arc;
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::configuration::UpstreamConf;
+
+    fn make_checker(conf: &str) -> HealthChecker {
+        let mut conf = UpstreamConf::from_yaml(conf).unwrap();
+        let health_check = conf.health_check.take().unwrap();
+        let balancer = Balancer::new(conf).unwrap().unwrap();
+        HealthChecker::new(balancer, health_check, &ServerConf::default())
+    }
+
+    fn is_unhealthy(checker: &HealthChecker) -> bool {
+        unhealthy_components()
+            .iter()
+            .any(|(name, _)| *name == checker.component)
+    }
+
+    async fn listen() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    /// Responds to HTTP requests with the given status, records the request headers
+    fn serve(listener: TcpListener, status: u16) -> Arc<Mutex<Vec<String>>> {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request).into_owned());
+                let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        requests
+    }
+
+    #[test(tokio::test)]
+    async fn tcp() {
+        let (listener1, port1) = listen().await;
+        let (listener2, port2) = listen().await;
+        drop(listener2);
+
+        let checker = make_checker(&format!(
+            r#"
+                upstream:
+                - http://127.0.0.1:{port1}
+                - http://127.0.0.1:{port2}
+                health_check:
+                    rise: 2
+                    fall: 2
+            "#
+        ));
+
+        // A server is only marked unhealthy after `fall` consecutive failures
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(1));
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(0));
+        assert!(!checker.balancer.is_healthy(1));
+        assert!(!is_unhealthy(&checker));
+
+        // It is only healthy again after `rise` consecutive successes
+        let listener2 = TcpListener::bind(("127.0.0.1", port2)).await.unwrap();
+        checker.check_all().await;
+        assert!(!checker.balancer.is_healthy(1));
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(1));
+
+        // A success in between resets the failure count
+        drop(listener2);
+        checker.check_all().await;
+        let listener2 = TcpListener::bind(("127.0.0.1", port2)).await.unwrap();
+        checker.check_all().await;
+        drop(listener2);
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(1));
+
+        // The component is unhealthy once all servers failed
+        drop(listener1);
+        checker.check_all().await;
+        assert!(!is_unhealthy(&checker));
+        checker.check_all().await;
+        assert!(!checker.balancer.is_healthy(0));
+        assert!(!checker.balancer.is_healthy(1));
+        assert!(is_unhealthy(&checker));
+
+        let _listener1 = TcpListener::bind(("127.0.0.1", port1)).await.unwrap();
+        checker.check_all().await;
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(0));
+        assert!(!is_unhealthy(&checker));
+    }
+
+    #[test(tokio::test)]
+    async fn http() {
+        let (listener1, port1) = listen().await;
+        let (listener2, port2) = listen().await;
+        let requests = serve(listener1, 204);
+        serve(listener2, 200);
+
+        let checker = make_checker(&format!(
+            r#"
+                upstream:
+                - http://localhost:{port1}
+                - http://127.0.0.1:{port2}
+                health_check:
+                    type: http
+                    path: /health?full
+                    status: 204
+                    rise: 1
+                    fall: 1
+            "#
+        ));
+
+        // Only the expected response status counts as success
+        checker.check_all().await;
+        assert!(checker.balancer.is_healthy(0));
+        assert!(!checker.balancer.is_healthy(1));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = requests[0].to_ascii_lowercase();
+        assert!(request.starts_with("get /health?full http/1.1\r\n"));
+        assert!(request.contains(&format!("\r\nhost: localhost:{port1}\r\n")));
+    }
+}
@@ -14,183 +14,30 @@
 
 #![doc = include_str!("../README.md")]
 
-use async_trait::async_trait;
-use clap::{value_parser, Parser};
-use http::header;
-use http::uri::{Scheme, Uri};
-use log::error;
-use pandora_module_utils::pingora::{Error, ErrorType, HttpPeer, SessionWrapper};
-use pandora_module_utils::{DeserializeMap, RequestFilter, RequestFilterResult};
-use serde::de::{Deserializer, Error as _};
-use serde::Deserialize as _;
-use std::net::{SocketAddr, ToSocketAddrs};
-
-/// Command line options of the compression module
-#[derive(Debug, Default, Parser)]
-pub struct UpstreamOpt {
-    /// http:// or https:// URL identifying the server that requests should be forwarded for.
-    /// Path and query parts of the URL have no effect.
-    #[clap(long, value_parser = value_parser!(String))]
-    pub upstream: Option<Uri>,
-}
-
-fn deserialize_uri<'de, D>(d: D) -> Result<Option<Uri>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let uri = String::deserialize(d)?;
-    let uri = uri
-        .parse()
-        .map_err(|err| D::Error::custom(format!("URL {uri} could not be parsed: {err}")))?;
-    Ok(Some(uri))
-}
-
-/// Configuration settings of the compression module
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
-pub struct UpstreamConf {
-    /// http:// or https:// URL identifying the server that requests should be forwarded for.
-    /// Path and query parts of the URL have no effect.
-    #[pandora(deserialize_with = "deserialize_uri")]
-    pub upstream: Option<Uri>,
-}
-
-impl UpstreamConf {
-    /// Merges the command line options into the current configuration. Any command line options
-    /// present overwrite existing settings.
-    pub fn merge_with_opt(&mut self, opt: UpstreamOpt) {
-        if opt.upstream.is_some() {
-            self.upstream = opt.upstream;
-        }
-    }
-}
-
-/// Context data of the handler
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UpstreamContext {
-    addr: SocketAddr,
-    tls: bool,
-    sni: String,
-}
-
-/// Handler for Pingora’s `request_filter` phase
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UpstreamHandler {
-    host_port: String,
-    context: Option<UpstreamContext>,
-}
-
-impl TryFrom<UpstreamConf> for UpstreamHandler {
-    type Error = Box<Error>;
-
-    fn try_from(conf: UpstreamConf) -> Result<Self, Self::Error> {
-        if let Some(upstream) = conf.upstream {
-            let scheme = upstream.scheme().ok_or_else(|| {
-                error!("provided upstream URL has no scheme: {upstream}");
-                Error::new(ErrorType::InternalError)
-            })?;
-
-            let tls = if scheme == &Scheme::HTTP {
-                false
-            } else if scheme == &Scheme::HTTPS {
-                true
-            } else {
-                error!("provided upstream URL is neither HTTP nor HTTPS: {upstream}");
-                return Err(Error::new(ErrorType::InternalError));
-            };
-
-            let host = upstream.host().ok_or_else(|| {
-                error!("provided upstream URL has no host name: {upstream}");
-                Error::new(ErrorType::InternalError)
-            })?;
-
-            let port = upstream.port_u16().unwrap_or(if tls { 443 } else { 80 });
-
-            let addr = (host, port)
-                .to_socket_addrs()
-                .map_err(|err| {
-                    error!("failed resolving upstream host name {host}: {err}");
-                    Error::new(ErrorType::InternalError)
-                })?
-                .next()
-                .ok_or_else(|| {
-                    error!("DNS lookup of upstream host name {host} didn't produce any results");
-                    Error::new(ErrorType::InternalError)
-                })?;
-
-            let mut host_port = host.to_owned();
-            if let Some(port) = upstream.port() {
-                host_port.push(':');
-                host_port.push_str(port.as_str());
-            }
-
-            Ok(Self {
-                host_port,
-                context: Some(UpstreamContext {
-                    tls,
-                    addr,
-                    sni: host.to_owned(),
-                }),
-            })
-        } else {
-            Ok(Self {
-                host_port: Default::default(),
-                context: None,
-            })
-        }
-    }
-}
-
-#[async_trait]
-impl RequestFilter for UpstreamHandler {
-    type Conf = UpstreamConf;
-    type CTX = Option<UpstreamContext>;
-    fn new_ctx() -> Self::CTX {
-        None
-    }
-
-    async fn request_filter(
-        &self,
-        session: &mut impl SessionWrapper,
-        ctx: &mut Self::CTX,
-    ) -> Result<RequestFilterResult, Box<Error>> {
-        if let Some(context) = &self.context {
-            session
-                .req_header_mut()
-                .insert_header(header::HOST, &self.host_port)?;
-
-            *ctx = Some(context.clone());
-
-            Ok(RequestFilterResult::Handled)
-        } else {
-            Ok(RequestFilterResult::Unhandled)
-        }
-    }
-
-    async fn upstream_peer(
-        &self,
-        _session: &mut impl SessionWrapper,
-        ctx: &mut Self::CTX,
-    ) -> Result<Option<Box<HttpPeer>>, Box<Error>> {
-        if let Some(context) = ctx {
-            Ok(Some(Box::new(HttpPeer::new(
-                context.addr,
-                context.tls,
-                context.sni.clone(),
-            ))))
-        } else {
-            Ok(None)
-        }
-    }
-}
+mod balancer;
+mod breaker;
+mod configuration;
+mod handler;
+mod health;
+mod retry;
+
+pub use configuration::{
+    CircuitBreakerConf, HashKey, HealthCheckConf, HealthCheckType, LoadBalancing, RetryConf,
+    UpstreamConf, UpstreamOpt, UpstreamServer,
+};
+pub use handler::{UpstreamContext, UpstreamHandler};
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use http::HeaderValue;
-    use pandora_module_utils::pingora::{Error, ProxyHttp, RequestHeader, TestSession};
+    use pandora_module_utils::pingora::{
+        Error, ErrorType, ProxyHttp, RequestHeader, ResponseHeader, TestSession,
+    };
     use pandora_module_utils::FromYaml;
-    use startup_module::DefaultApp;
+    use startup_module::{DefaultApp, ServerApp};
+    use std::time::Duration;
     use test_log::test;
 
     fn make_app(configured: bool) -> DefaultApp<UpstreamHandler> {
@@ -263,4 +110,573 @@ mod tests {
 
         Ok(())
     }
+
+    fn make_balanced_app(conf: &str) -> DefaultApp<UpstreamHandler> {
+        DefaultApp::new(UpstreamConf::from_yaml(conf).unwrap().try_into().unwrap())
+    }
+
+    async fn make_session_with_header(name: &'static str, value: &str) -> TestSession {
+        let mut header = RequestHeader::build("GET", b"/", None).unwrap();
+        header.insert_header(name, value).unwrap();
+        TestSession::from(header).await
+    }
+
+    /// Runs a request through the app, returns the chosen upstream address along with the context
+    async fn choose(
+        app: &DefaultApp<UpstreamHandler>,
+        session: &mut TestSession,
+    ) -> (String, <DefaultApp<UpstreamHandler> as ProxyHttp>::CTX) {
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(session, &mut ctx).await.unwrap());
+        let peer = app.upstream_peer(session, &mut ctx).await.unwrap();
+        (peer._address.to_string(), ctx)
+    }
+
+    #[test]
+    fn deserialize() {
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - url: https://127.0.0.1:8082
+                  weight: 3
+                load_balancing: least_connections
+                hash_key: http_x_user_id
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.upstream.as_slice(),
+            &[
+                UpstreamServer {
+                    url: "http://127.0.0.1:8081".parse().unwrap(),
+                    weight: 1,
+                },
+                UpstreamServer {
+                    url: "https://127.0.0.1:8082".parse().unwrap(),
+                    weight: 3,
+                }
+            ]
+        );
+        assert_eq!(conf.load_balancing, LoadBalancing::LeastConnections);
+        assert_eq!(
+            conf.hash_key,
+            HashKey::Header(http::header::HeaderName::from_static("x-user-id"))
+        );
+
+        assert!(UpstreamConf::from_yaml("upstream: {url: http://127.0.0.1, weight: 0}").is_err());
+        assert!(UpstreamConf::from_yaml("upstream: {weight: 1}").is_err());
+        assert!(UpstreamConf::from_yaml("load_balancing: fastest").is_err());
+        assert!(UpstreamConf::from_yaml("hash_key: client_port").is_err());
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: http://127.0.0.1:8081
+                health_check:
+                    type: http
+                    path: /status
+                    interval: 5
+                    fall: 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.health_check,
+            Some(HealthCheckConf {
+                check_type: HealthCheckType::Http,
+                path: "/status".to_owned(),
+                interval: 5,
+                fall: 2,
+                ..Default::default()
+            })
+        );
+
+        assert!(UpstreamConf::from_yaml("health_check: {type: udp}").is_err());
+        assert!(UpstreamConf::from_yaml("health_check: {interval: 0}").is_err());
+        assert!(UpstreamConf::from_yaml("health_check: {status: 1000}").is_err());
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: http://127.0.0.1:8081
+                circuit_breaker:
+                    failures: 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.circuit_breaker,
+            Some(CircuitBreakerConf {
+                failures: 3,
+                window: 10,
+                cooldown: 30,
+            })
+        );
+        assert!(UpstreamConf::from_yaml("circuit_breaker: {cooldown: 0}").is_err());
+
+        let conf = UpstreamConf::from_yaml(
+            r#"
+                upstream: http://127.0.0.1:8081
+                retry:
+                    attempts: 5
+                    reset: true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            conf.retry,
+            Some(RetryConf {
+                attempts: 5,
+                connect_errors: true,
+                reset: true,
+                budget: 20,
+            })
+        );
+        assert!(UpstreamConf::from_yaml("retry: {budget: 101}").is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn round_robin() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - url: http://127.0.0.1:8082
+                  weight: 5
+                - http://127.0.0.1:8083
+            "#,
+        );
+
+        let mut chosen = Vec::new();
+        for _ in 0..4 {
+            let mut session = make_session().await;
+            let (addr, _) = choose(&app, &mut session).await;
+            assert_eq!(
+                session.req_header().headers.get("Host"),
+                Some(&HeaderValue::from_str(&addr).unwrap())
+            );
+            chosen.push(addr);
+        }
+        assert_eq!(
+            chosen,
+            [
+                "127.0.0.1:8081",
+                "127.0.0.1:8082",
+                "127.0.0.1:8083",
+                "127.0.0.1:8081"
+            ]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn weighted() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - url: http://127.0.0.1:8082
+                  weight: 2
+                load_balancing: weighted
+            "#,
+        );
+
+        let mut chosen = Vec::new();
+        for _ in 0..6 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            chosen.push(addr);
+        }
+        assert_eq!(
+            chosen,
+            [
+                "127.0.0.1:8082",
+                "127.0.0.1:8081",
+                "127.0.0.1:8082",
+                "127.0.0.1:8082",
+                "127.0.0.1:8081",
+                "127.0.0.1:8082"
+            ]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn least_connections() {
+        for strategy in ["least_connections", "random_two_choices"] {
+            let app = make_balanced_app(&format!(
+                r#"
+                    upstream:
+                    - http://127.0.0.1:8081
+                    - http://127.0.0.1:8082
+                    load_balancing: {strategy}
+                "#
+            ));
+
+            // Active requests keep a server busy
+            let (first, first_ctx) = choose(&app, &mut make_session().await).await;
+            let (second, _second_ctx) = choose(&app, &mut make_session().await).await;
+            assert_ne!(first, second);
+            drop(first_ctx);
+            for _ in 0..3 {
+                let (addr, _) = choose(&app, &mut make_session().await).await;
+                assert_eq!(addr, first);
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn consistent_hash() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                - http://127.0.0.1:8083
+                load_balancing: consistent_hash
+                hash_key: cookie_user
+            "#,
+        );
+
+        let mut distinct = std::collections::HashSet::new();
+        for user in 0..20 {
+            let cookie = format!("theme=dark; user={user}");
+            let (addr, _) =
+                choose(&app, &mut make_session_with_header("Cookie", &cookie).await).await;
+            for _ in 0..3 {
+                let (repeated, _) =
+                    choose(&app, &mut make_session_with_header("Cookie", &cookie).await).await;
+                assert_eq!(repeated, addr);
+            }
+            distinct.insert(addr);
+        }
+        assert!(distinct.len() > 1);
+    }
+
+    #[test(tokio::test)]
+    async fn health_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:18470")
+            .await
+            .unwrap();
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:18470
+                - http://127.0.0.1:18471
+                health_check:
+                    interval: 1
+                    rise: 1
+                    fall: 1
+            "#,
+        );
+
+        // Health checks only run if configured
+        assert!(make_balanced_app("upstream: http://127.0.0.1:18470")
+            .background_services(&Default::default())
+            .is_empty());
+        let mut services = app.background_services(&Default::default());
+        assert_eq!(services.len(), 1);
+        let mut service = services.pop().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let service = tokio::spawn(async move { service.start_service(None, shutdown_rx).await });
+        let component = "upstream 127.0.0.1:18470, 127.0.0.1:18471";
+        let unhealthy = || {
+            pandora_module_utils::health::unhealthy_components()
+                .iter()
+                .any(|(name, _)| name == component)
+        };
+
+        // The server that isn't listening is excluded
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        for _ in 0..4 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            assert_eq!(addr, "127.0.0.1:18470");
+        }
+        assert!(!unhealthy());
+
+        // If all servers are down, all are used
+        drop(listener);
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        let mut chosen = std::collections::HashSet::new();
+        for _ in 0..4 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            chosen.insert(addr);
+        }
+        assert_eq!(chosen.len(), 2);
+        assert!(unhealthy());
+
+        shutdown_tx.send(true).unwrap();
+        service.await.unwrap();
+    }
+
+    #[test(tokio::test)]
+    async fn circuit_breaker() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                circuit_breaker:
+                    failures: 2
+                    cooldown: 1
+            "#,
+        );
+
+        let fail = |error: Box<Error>| {
+            let app = &app;
+            async move {
+                loop {
+                    let mut session = make_session().await;
+                    let (addr, mut ctx) = choose(app, &mut session).await;
+                    if addr == "127.0.0.1:8081" {
+                        app.logging(&mut session, Some(&error), &mut ctx).await;
+                        break;
+                    }
+                }
+            }
+        };
+
+        // Client errors don’t count
+        fail(Error::new_down(ErrorType::ConnectionClosed)).await;
+        fail(Error::new_down(ErrorType::ConnectionClosed)).await;
+        fail(Error::new_up(ErrorType::ConnectRefused)).await;
+        for _ in 0..2 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            assert_eq!(addr, "127.0.0.1:8082");
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            assert_eq!(addr, "127.0.0.1:8081");
+        }
+
+        // Upstream errors open the circuit breaker
+        fail(Error::new_up(ErrorType::ConnectRefused)).await;
+        for _ in 0..4 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            assert_eq!(addr, "127.0.0.1:8082");
+        }
+
+        // After the cooldown period a single trial request goes to the server
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let mut session = make_session().await;
+        let (mut addr, mut ctx) = choose(&app, &mut session).await;
+        if addr != "127.0.0.1:8081" {
+            session = make_session().await;
+            (addr, ctx) = choose(&app, &mut session).await;
+        }
+        assert_eq!(addr, "127.0.0.1:8081");
+        for _ in 0..4 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            assert_eq!(addr, "127.0.0.1:8082");
+        }
+
+        // Its success closes the circuit breaker
+        app.logging(&mut session, None, &mut ctx).await;
+        let mut chosen = std::collections::HashSet::new();
+        for _ in 0..2 {
+            let (addr, _) = choose(&app, &mut make_session().await).await;
+            chosen.insert(addr);
+        }
+        assert_eq!(chosen.len(), 2);
+    }
+
+    #[test(tokio::test)]
+    async fn retry() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - url: http://127.0.0.1:8081
+                  weight: 10
+                - http://127.0.0.1:8082
+                load_balancing: weighted
+                retry:
+                    attempts: 2
+            "#,
+        );
+
+        // Retry chooses a different server, up to the maximal number of attempts
+        let mut session = make_session().await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        assert_eq!(peer._address.to_string(), "127.0.0.1:8081");
+        let e = app.fail_to_connect(
+            &mut session,
+            &peer,
+            &mut ctx,
+            Error::new_up(ErrorType::ConnectRefused),
+        );
+        assert!(e.retry());
+
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        assert_eq!(peer._address.to_string(), "127.0.0.1:8082");
+        let e = app.fail_to_connect(
+            &mut session,
+            &peer,
+            &mut ctx,
+            Error::new_up(ErrorType::ConnectRefused),
+        );
+        assert!(!e.retry());
+
+        // Reset connections aren’t retried by default
+        let (_, mut ctx) = choose(&app, &mut session).await;
+        let e = app.error_while_proxy(
+            &peer,
+            &mut session,
+            Error::new_up(ErrorType::ConnectionClosed),
+            &mut ctx,
+            false,
+        );
+        assert!(!e.retry());
+
+        // With resets enabled, only requests that can be safely replayed are retried
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                retry:
+                    reset: true
+            "#,
+        );
+        let retried = |mut session: TestSession| {
+            let app = &app;
+            let peer = &peer;
+            async move {
+                let (_, mut ctx) = choose(app, &mut session).await;
+                app.error_while_proxy(
+                    peer,
+                    &mut session,
+                    Error::new_up(ErrorType::ConnectionClosed),
+                    &mut ctx,
+                    false,
+                )
+                .retry()
+            }
+        };
+        assert!(retried(make_session().await).await);
+
+        let header = RequestHeader::build("POST", b"/", None).unwrap();
+        assert!(!retried(TestSession::from(header).await).await);
+
+        let mut responded = make_session().await;
+        responded
+            .as_mut()
+            .write_response_header(Box::new(ResponseHeader::build(200, None).unwrap()))
+            .await
+            .unwrap();
+        assert!(!retried(responded).await);
+
+        // Request bodies are only buffered up to a limit, larger ones cannot be replayed
+        for (size, expected) in [(1000, true), (100_000, false)] {
+            let header = RequestHeader::build("PUT", b"/", None).unwrap();
+            let mut uploading = TestSession::with_body(header, vec![b'x'; size]).await;
+            uploading.as_mut().enable_retry_buffering();
+            while uploading.read_request_body().await.unwrap().is_some() {}
+            assert_eq!(retried(uploading).await, expected);
+        }
+
+        // Retry budget limits the number of retries
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - http://127.0.0.1:8081
+                - http://127.0.0.1:8082
+                retry:
+                    reset: true
+                    budget: 0
+            "#,
+        );
+        let mut retries = 0;
+        for _ in 0..20 {
+            let (_, mut ctx) = choose(&app, &mut session).await;
+            let e = app.error_while_proxy(
+                &peer,
+                &mut session,
+                Error::new_up(ErrorType::ConnectionClosed),
+                &mut ctx,
+                false,
+            );
+            if e.retry() {
+                retries += 1;
+            }
+        }
+        assert_eq!(retries, 10);
+    }
+
+    #[test(tokio::test)]
+    async fn timeouts() {
+        let app = make_balanced_app(
+            r#"
+                upstream: http://127.0.0.1:8081
+                connection_timeout: 1
+                total_connection_timeout: 2
+                read_timeout: 30
+                idle_timeout: 60
+            "#,
+        );
+
+        let mut session = make_session().await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            peer.options.total_connection_timeout,
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(peer.options.read_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(peer.options.write_timeout, None);
+        assert_eq!(peer.options.idle_timeout, Some(Duration::from_secs(60)));
+    }
+
+    #[test(tokio::test)]
+    async fn path_mapping() {
+        let app = make_balanced_app(
+            r#"
+                upstream:
+                - url: http://127.0.0.1:8081/v2/
+                  weight: 10
+                - http://127.0.0.1:8082
+                load_balancing: weighted
+                retry: {}
+            "#,
+        );
+
+        let header = RequestHeader::build("GET", b"/api/items?page=2", None).unwrap();
+        let mut session = TestSession::from(header).await;
+        let mut ctx = app.new_ctx();
+        assert!(!app.request_filter(&mut session, &mut ctx).await.unwrap());
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        assert_eq!(peer._address.to_string(), "127.0.0.1:8081");
+        assert_eq!(session.req_header().uri, "/v2/api/items?page=2");
+
+        // A retry maps the original path
+        let e = app.fail_to_connect(
+            &mut session,
+            &peer,
+            &mut ctx,
+            Error::new_up(ErrorType::ConnectRefused),
+        );
+        assert!(e.retry());
+        let peer = app.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        assert_eq!(peer._address.to_string(), "127.0.0.1:8082");
+        assert_eq!(session.req_header().uri, "/api/items?page=2");
+    }
+
+    #[test(tokio::test)]
+    async fn preserve_host() {
+        for (preserve_host, expected) in [(false, "127.0.0.1:8081"), (true, "example.com")] {
+            let app = make_balanced_app(&format!(
+                r#"
+                    upstream: http://127.0.0.1:8081
+                    preserve_host: {preserve_host}
+                "#
+            ));
+
+            let mut session = make_session_with_header("Host", "example.com").await;
+            choose(&app, &mut session).await;
+            assert_eq!(
+                session.req_header().headers.get("Host"),
+                Some(&HeaderValue::from_static(expected))
+            );
+        }
+    }
 }
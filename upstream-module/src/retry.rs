@@ -0,0 +1,217 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retries of failed upstream requests
+
+use http::Method;
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pingora::ErrorSource;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::configuration::RetryConf;
+
+/// Retry budget units corresponding to a single retry
+const RETRY_COST: u64 = 100;
+
+/// Maximal number of retries that can be accumulated in the budget
+const MAX_RETRIES: u64 = 10;
+
+/// Decides whether failed requests should be retried
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    conf: RetryConf,
+    budget: Arc<AtomicU64>,
+}
+
+impl PartialEq for RetryPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        self.conf == other.conf
+    }
+}
+
+impl Eq for RetryPolicy {}
+
+impl RetryPolicy {
+    pub(crate) fn new(conf: RetryConf) -> Self {
+        Self {
+            conf,
+            budget: Arc::new(AtomicU64::new(MAX_RETRIES * RETRY_COST)),
+        }
+    }
+
+    /// Adds a new request to the retry budget
+    pub(crate) fn request_started(&self) {
+        let _ = self
+            .budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+                Some((budget + u64::from(self.conf.budget)).min(MAX_RETRIES * RETRY_COST))
+            });
+    }
+
+    /// Takes a retry from the budget if possible
+    fn take_budget(&self) -> bool {
+        self.budget
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+                budget.checked_sub(RETRY_COST)
+            })
+            .is_ok()
+    }
+
+    /// Decides whether a request that failed to connect should be retried
+    pub(crate) fn retry_connect(&self, attempts: u32) -> bool {
+        self.conf.connect_errors && attempts < self.conf.attempts && self.take_budget()
+    }
+
+    /// Decides whether a request that failed after connecting should be retried
+    ///
+    /// Only requests with idempotent methods are retried, and only if the request can be replayed:
+    /// no response has been sent to the client yet and the request body is still buffered.
+    pub(crate) fn retry_reset(
+        &self,
+        attempts: u32,
+        method: &Method,
+        e: &Error,
+        can_replay: bool,
+    ) -> bool {
+        let reset = e.esource() == &ErrorSource::Upstream
+            && matches!(
+                e.etype(),
+                ErrorType::ConnectionClosed | ErrorType::ReadError | ErrorType::WriteError
+            );
+        let idempotent = matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+        );
+
+        self.conf.reset
+            && reset
+            && idempotent
+            && can_replay
+            && attempts < self.conf.attempts
+            && self.take_budget()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn make_policy(reset: bool, budget: u32) -> RetryPolicy {
+        RetryPolicy::new(RetryConf {
+            reset,
+            budget,
+            ..Default::default()
+        })
+    }
+
+    fn reset_error() -> Box<Error> {
+        Error::new_up(ErrorType::ConnectionClosed)
+    }
+
+    #[test]
+    fn connect() {
+        let policy = make_policy(false, 20);
+        assert!(policy.retry_connect(1));
+        assert!(policy.retry_connect(2));
+        assert!(!policy.retry_connect(3));
+
+        let policy = RetryPolicy::new(RetryConf {
+            connect_errors: false,
+            ..Default::default()
+        });
+        assert!(!policy.retry_connect(1));
+    }
+
+    #[test]
+    fn reset() {
+        let policy = make_policy(true, 20);
+        assert!(policy.retry_reset(1, &Method::GET, &reset_error(), true));
+        assert!(policy.retry_reset(2, &Method::PUT, &reset_error(), true));
+        assert!(!policy.retry_reset(3, &Method::GET, &reset_error(), true));
+
+        // Requests with non-idempotent methods aren’t retried
+        assert!(!policy.retry_reset(1, &Method::POST, &reset_error(), true));
+        assert!(!policy.retry_reset(1, &Method::PATCH, &reset_error(), true));
+
+        // Neither are requests that cannot be replayed, e.g. with the request body already consumed
+        assert!(!policy.retry_reset(1, &Method::GET, &reset_error(), false));
+
+        // Only upstream connection errors are retried
+        for e in [
+            Error::new_down(ErrorType::ConnectionClosed),
+            Error::new_up(ErrorType::ReadTimedout),
+            Error::new_up(ErrorType::InvalidHTTPHeader),
+        ] {
+            assert!(!policy.retry_reset(1, &Method::GET, &e, true));
+        }
+        for etype in [ErrorType::ReadError, ErrorType::WriteError] {
+            assert!(policy.retry_reset(1, &Method::GET, &Error::new_up(etype), true));
+        }
+
+        // Resets aren’t retried unless enabled
+        let policy = make_policy(false, 20);
+        assert!(!policy.retry_reset(1, &Method::GET, &reset_error(), true));
+    }
+
+    #[test]
+    fn budget() {
+        let policy = make_policy(true, 20);
+
+        // The initial budget is exhausted after the maximal number of retries
+        for _ in 0..MAX_RETRIES {
+            assert!(policy.retry_connect(1));
+        }
+        assert!(!policy.retry_connect(1));
+        assert!(!policy.retry_reset(1, &Method::GET, &reset_error(), true));
+
+        // Every request adds to the budget, five requests allow one retry at 20%
+        for _ in 0..4 {
+            policy.request_started();
+            assert!(!policy.retry_connect(1));
+        }
+        policy.request_started();
+        assert!(policy.retry_connect(1));
+        assert!(!policy.retry_connect(1));
+
+        // Failed decisions don’t use up the budget
+        for _ in 0..5 {
+            policy.request_started();
+        }
+        assert!(!policy.retry_reset(1, &Method::POST, &reset_error(), true));
+        assert!(!policy.retry_connect(3));
+        assert!(policy.retry_reset(1, &Method::GET, &reset_error(), true));
+
+        // The budget is capped and shared between copies of the policy
+        for _ in 0..1000 {
+            policy.request_started();
+        }
+        let copy = policy.clone();
+        for _ in 0..MAX_RETRIES {
+            assert!(copy.retry_connect(1));
+        }
+        assert!(!policy.retry_connect(1));
+
+        // Without a budget, no retries are possible once the initial budget is gone
+        let policy = make_policy(true, 0);
+        for _ in 0..MAX_RETRIES {
+            policy.request_started();
+            assert!(policy.retry_connect(1));
+        }
+        policy.request_started();
+        assert!(!policy.retry_connect(1));
+    }
+}
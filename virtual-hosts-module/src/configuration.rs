@@ -69,6 +69,11 @@ pub struct VirtualHostConf<C: Default> {
     /// If true, this virtual host should be used as fallback when no other virtual host
     /// configuration applies
     pub default: bool,
+    /// Names of the listeners this virtual host applies to
+    ///
+    /// If empty, the virtual host applies to all listeners. Otherwise requests arriving on other
+    /// listeners will not match it.
+    pub listeners: OneOrMany<String>,
     /// Maps virtual host's paths to their special configurations
     pub subpaths: HashMap<PathMatchRule, SubPathConf<C>>,
     /// Generic handler settings
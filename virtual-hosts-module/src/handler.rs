@@ -15,10 +15,12 @@
 use async_trait::async_trait;
 use http::uri::Uri;
 use log::warn;
-use pandora_module_utils::pingora::{Error, HttpPeer, ResponseHeader, SessionWrapper};
+use pandora_module_utils::pingora::{
+    Error, HttpPeer, ResponseHeader, ServerConf, Service, SessionWrapper,
+};
 use pandora_module_utils::router::{Path, Router};
 use pandora_module_utils::{RequestFilter, RequestFilterResult};
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
 
@@ -42,7 +44,7 @@ fn set_uri_path(uri: &Uri, path: &[u8]) -> Uri {
 /// Context for the virtual hosts handler
 #[derive(Debug)]
 pub struct VirtualHostsCtx<Ctx> {
-    index: Option<usize>,
+    index: Option<(usize, usize)>,
     handler: Ctx,
 }
 
@@ -63,7 +65,12 @@ impl<Ctx> DerefMut for VirtualHostsCtx<Ctx> {
 /// Handler for Pingora’s `request_filter` phase
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VirtualHostsHandler<H: Debug> {
-    handlers: Router<(Option<Path>, H)>,
+    /// Routers for requests on unnamed listeners (first entry) and on each named listener
+    routers: Vec<Router<(Option<Path>, H)>>,
+    /// Listener names mapped to their respective router
+    listeners: HashMap<String, usize>,
+    /// All configured handlers, each one listed once regardless of how many routes it is used in
+    handlers: Vec<H>,
 }
 
 impl<H: Debug> VirtualHostsHandler<H> {
@@ -77,12 +84,17 @@ impl<H: Debug> VirtualHostsHandler<H> {
         H::Conf: Default,
         H::CTX: Send,
     {
-        self.handlers.retrieve(ctx.index?).map(|(_, h)| h)
+        let (router, index) = ctx.index?;
+        self.retrieve(router, index)
+    }
+
+    fn retrieve(&self, router: usize, index: usize) -> Option<&H> {
+        self.routers.get(router)?.retrieve(index).map(|(_, h)| h)
     }
 }
 
 #[derive(Debug, Clone)]
-struct IndexEntry(usize);
+struct IndexEntry(usize, usize);
 
 #[async_trait]
 impl<H> RequestFilter for VirtualHostsHandler<H>
@@ -107,18 +119,23 @@ where
         session: &mut impl SessionWrapper,
         ctx: &mut Self::CTX,
     ) -> Result<RequestFilterResult, Box<Error>> {
+        let router = session
+            .listener_name()
+            .and_then(|name| self.listeners.get(name))
+            .copied()
+            .unwrap_or(0);
         let path = session.uri().path();
         let host = session.host().unwrap_or_default();
 
-        if let Some(result) = self.handlers.lookup(host.as_ref(), &path) {
+        if let Some(result) = self.routers[router].lookup(host.as_ref(), &path) {
             let (strip_path, handler) = result.as_value();
             let index = result.index();
             let new_path = strip_path.as_ref().and_then(|p| p.remove_prefix_from(path));
 
-            ctx.index = Some(index);
+            ctx.index = Some((router, index));
 
             // Save ctx.index in session as well, response_filter could be called without context
-            session.extensions_mut().insert(IndexEntry(index));
+            session.extensions_mut().insert(IndexEntry(router, index));
 
             if let Some(new_path) = new_path {
                 session.set_uri(set_uri_path(session.uri(), &new_path));
@@ -141,6 +158,35 @@ where
         }
     }
 
+    fn fail_to_connect(
+        &self,
+        session: &mut impl SessionWrapper,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.fail_to_connect(session, peer, ctx, e)
+        } else {
+            e
+        }
+    }
+
+    fn error_while_proxy(
+        &self,
+        session: &mut impl SessionWrapper,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+        client_reused: bool,
+    ) -> Box<Error> {
+        if let Some(handler) = self.as_inner(ctx) {
+            handler.error_while_proxy(session, peer, ctx, e, client_reused)
+        } else {
+            e
+        }
+    }
+
     fn response_filter(
         &self,
         session: &mut impl SessionWrapper,
@@ -150,9 +196,8 @@ where
         let handler = ctx
             .as_ref()
             .and_then(|ctx| ctx.index)
-            .or_else(|| session.extensions().get::<IndexEntry>().map(|i| i.0))
-            .and_then(|index| self.handlers.retrieve(index))
-            .map(|(_, h)| h);
+            .or_else(|| session.extensions().get::<IndexEntry>().map(|i| (i.0, i.1)))
+            .and_then(|(router, index)| self.retrieve(router, index));
         if let Some(handler) = handler {
             handler.response_filter(session, response, ctx.map(|ctx| ctx.deref_mut()));
         }
@@ -168,6 +213,13 @@ where
             handler.logging(session, e, ctx).await;
         }
     }
+
+    fn background_services(&self, server_conf: &ServerConf) -> Vec<Box<dyn Service>> {
+        self.handlers
+            .iter()
+            .flat_map(|handler| handler.background_services(server_conf))
+            .collect()
+    }
 }
 
 impl<C, H> TryFrom<VirtualHostsConf<C>> for VirtualHostsHandler<H>
@@ -178,24 +230,41 @@ where
     type Error = Box<Error>;
 
     fn try_from(conf: VirtualHostsConf<C>) -> Result<Self, Box<Error>> {
-        let mut handlers = Router::builder();
-        let mut default: Option<Vec<String>> = None;
-        for (mut hosts, host_conf) in conf.vhosts.into_iter() {
-            let handler = host_conf.config.try_into()?;
-
-            let mut names = BTreeSet::new();
-            if host_conf.default {
-                if let Some(previous) = &default {
-                    warn!(
-                        "both [{}] and [{}] are marked as default virtual host, ignoring the latter",
-                        previous.join(", "),
-                        hosts.join(", ")
-                    );
-                } else {
-                    default = Some(hosts.clone().into());
-                    names.insert(String::new());
-                }
-            }
+        let listeners = conf
+            .vhosts
+            .values()
+            .flat_map(|host_conf| host_conf.listeners.iter().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| (name, index + 1))
+            .collect::<HashMap<_, _>>();
+
+        let mut builders = (0..=listeners.len())
+            .map(|_| Router::builder())
+            .collect::<Vec<_>>();
+        let mut defaults: Vec<Option<(Vec<String>, bool)>> = vec![None; builders.len()];
+        let mut restricted_hosts = vec![HashSet::new(); builders.len()];
+        let mut handlers = Vec::new();
+
+        // Add virtual hosts restricted to particular listeners first, so that their default
+        // virtual host takes precedence over the one applying to all listeners.
+        let mut vhosts = conf.vhosts.into_iter().collect::<Vec<_>>();
+        vhosts.sort_by_key(|(_, host_conf)| host_conf.listeners.is_empty());
+
+        for (mut hosts, host_conf) in vhosts {
+            let handler: H = host_conf.config.try_into()?;
+
+            let restricted = !host_conf.listeners.is_empty();
+            let targets = if restricted {
+                host_conf
+                    .listeners
+                    .iter()
+                    .map(|name| listeners[name])
+                    .collect::<BTreeSet<_>>()
+            } else {
+                (0..builders.len()).collect()
+            };
 
             hosts.retain(|host| {
                 if host.is_empty() {
@@ -205,16 +274,6 @@ where
                     true
                 }
             });
-            names.extend(hosts);
-
-            for host in &names {
-                handlers.push(
-                    host,
-                    "",
-                    (None, handler.clone()),
-                    Some((None, handler.clone())),
-                );
-            }
 
             let mut subpaths = host_conf.subpaths.into_iter().collect::<Vec<_>>();
 
@@ -223,30 +282,90 @@ where
             // because these are all added already.
             subpaths.sort_by_key(|(rule, _)| rule.exact);
 
-            for (rule, conf) in subpaths {
-                let handler = conf.config.try_into()?;
-                let strip_path = if conf.strip_prefix {
-                    Some(Path::new(&rule.path))
+            let subpaths = subpaths
+                .into_iter()
+                .map(|(rule, conf)| {
+                    let handler: H = conf.config.try_into()?;
+                    let strip_path = if conf.strip_prefix {
+                        Some(Path::new(&rule.path))
+                    } else {
+                        None
+                    };
+                    Ok((rule, strip_path, handler))
+                })
+                .collect::<Result<Vec<_>, Box<Error>>>()?;
+
+            handlers.push(handler.clone());
+            handlers.extend(subpaths.iter().map(|(_, _, handler)| handler.clone()));
+
+            let mut warned = false;
+            for target in targets {
+                let mut names = BTreeSet::new();
+                if host_conf.default {
+                    match &defaults[target] {
+                        Some((previous, previous_restricted)) => {
+                            // Defaults restricted to a listener override the general one silently
+                            if *previous_restricted == restricted && !warned {
+                                warn!(
+                                    "both [{}] and [{}] are marked as default virtual host, ignoring the latter",
+                                    previous.join(", "),
+                                    hosts.join(", ")
+                                );
+                                warned = true;
+                            }
+                        }
+                        None => {
+                            defaults[target] = Some((hosts.clone().into(), restricted));
+                            names.insert(String::new());
+                        }
+                    }
+                }
+                if restricted {
+                    restricted_hosts[target].extend(hosts.iter().cloned());
+                    names.extend(hosts.iter().cloned());
                 } else {
-                    None
-                };
+                    // Host names restricted to this listener take precedence
+                    names.extend(
+                        hosts
+                            .iter()
+                            .filter(|host| !restricted_hosts[target].contains(*host))
+                            .cloned(),
+                    );
+                }
+
+                let builder = &mut builders[target];
                 for host in &names {
-                    handlers.push(
+                    builder.push(
                         host,
-                        &rule.path,
-                        (strip_path.clone(), handler.clone()),
-                        if rule.exact {
-                            None
-                        } else {
-                            Some((strip_path.clone(), handler.clone()))
-                        },
+                        "",
+                        (None, handler.clone()),
+                        Some((None, handler.clone())),
                     );
                 }
+
+                for (rule, strip_path, handler) in &subpaths {
+                    for host in &names {
+                        builder.push(
+                            host,
+                            &rule.path,
+                            (strip_path.clone(), handler.clone()),
+                            if rule.exact {
+                                None
+                            } else {
+                                Some((strip_path.clone(), handler.clone()))
+                            },
+                        );
+                    }
+                }
             }
         }
-        let handlers = handlers.build();
+        let routers = builders.into_iter().map(|b| b.build()).collect();
 
-        Ok(Self { handlers })
+        Ok(Self {
+            routers,
+            listeners,
+            handlers,
+        })
     }
 }
 
@@ -560,4 +679,53 @@ mod tests {
         assert_eq!(session.original_uri(), "/subdir/file.txt/xyz");
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn listener_restriction() -> Result<(), Box<Error>> {
+        let handler: VirtualHostsHandler<Handler> = VirtualHostsConf::<Conf>::from_yaml(
+            r#"
+                vhosts:
+                    [localhost, example.com]:
+                        default: true
+                        result: ResponseSent
+                    [localhost, internal]:
+                        default: true
+                        listeners: admin
+                        result: Handled
+            "#,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+        for (listener, host, expected) in [
+            (None, "localhost", RequestFilterResult::ResponseSent),
+            (None, "internal", RequestFilterResult::ResponseSent),
+            (
+                Some("public"),
+                "internal",
+                RequestFilterResult::ResponseSent,
+            ),
+            (Some("admin"), "localhost", RequestFilterResult::Handled),
+            (Some("admin"), "internal", RequestFilterResult::Handled),
+            (Some("admin"), "unknown", RequestFilterResult::Handled),
+            (
+                Some("admin"),
+                "example.com",
+                RequestFilterResult::ResponseSent,
+            ),
+        ] {
+            let mut ctx = VirtualHostsHandler::<Handler>::new_ctx();
+            let mut session = make_session("/", Some(host)).await;
+            if let Some(listener) = listener {
+                session.set_listener_name(listener.to_owned());
+            }
+            assert_eq!(
+                handler.request_filter(&mut session, &mut ctx).await?,
+                expected,
+                "listener {listener:?}, host {host}"
+            );
+        }
+        Ok(())
+    }
 }